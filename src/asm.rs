@@ -0,0 +1,485 @@
+use std::collections::HashMap;
+use super::err::EmuErr;
+use super::opcodes::{AM,Op,Variant};
+
+/// Assembled program
+#[allow(dead_code)]
+#[derive(Debug, Clone)]
+pub struct Assembly {
+    /// Address of the first byte
+    pub origin: u16,
+    pub bytes: Vec<u8>,
+    pub labels: HashMap<String, u16>,
+}
+
+#[allow(dead_code)]
+impl Assembly {
+    pub fn label(&self, name: &str) -> Option<u16> {
+	self.labels.get(name).copied()
+    }
+
+    /// Wraps the program in an NROM iNES image with an empty CHR bank. A
+    /// program at or above $C000 gets a single 16KiB bank, mirrored at
+    /// $8000. Vectors the program doesn't set itself point at the `nmi`,
+    /// `reset` and `irq` labels, falling back to the origin.
+    pub fn to_nrom(&self) -> Result<Vec<u8>, EmuErr> {
+	let end = self.origin as usize + self.bytes.len();
+	if self.origin < 0x8000 || end > 0x10000 {
+	    return Err(EmuErr::Assemble(0, format!("${:04X}-${:04X} is outside prg rom", self.origin, end - 1)));
+	}
+	let banks = if self.origin >= 0xc000 { 1 } else { 2 };
+	let base = 0x10000 - banks * 0x4000;
+	let mut prg = vec![0; banks * 0x4000];
+	prg[self.origin as usize - base..end - base].copy_from_slice(&self.bytes);
+
+	let vectors = [(0xfffa, "nmi"), (0xfffc, "reset"), (0xfffe, "irq")];
+	for (vector, label) in vectors {
+	    if end <= vector {
+		let target = self.label(label).unwrap_or(self.origin);
+		prg[vector - base..vector - base + 2].copy_from_slice(&target.to_le_bytes());
+	    }
+	}
+
+	let mut rom = vec![0x4e, 0x45, 0x53, 0x1a, banks as u8, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+	rom.extend_from_slice(&prg);
+	rom.extend_from_slice(&[0; 0x2000]);
+	Ok(rom)
+    }
+}
+
+/// Assembles 6502 source for the 2A03, see `assemble_for`.
+#[allow(dead_code)]
+pub fn assemble(source: &str) -> Result<Assembly, EmuErr> {
+    assemble_for(source, Variant::Ricoh2A03)
+}
+
+/// Assembles 6502 source with the usual syntax, one statement per line:
+///
+/// ```text
+/// ; comment
+/// COUNT = $10          ; constant
+///         .org $C000   ; also .byte and .word
+/// reset:  LDX #COUNT
+/// loop:   STA $0200,X
+///         DEX
+///         BNE loop
+///         JMP (vector)
+/// ```
+///
+/// Numbers are `$hex`, `%binary` or decimal. Operands can add or subtract
+/// offsets and take the low (`<`) or high (`>`) byte of a value. Zero page
+/// addressing is picked when the address is known to fit, which forward
+/// references aren't. Mnemonics are looked up in `variant`'s opcode table,
+/// so the unofficial opcodes assemble under their table names (ISB is
+/// accepted for ISC).
+#[allow(dead_code)]
+pub fn assemble_for(source: &str, variant: Variant) -> Result<Assembly, EmuErr> {
+    let mut asm = Assembler {
+	variant,
+	labels: HashMap::new(),
+    };
+
+    // First pass: sizes every statement and places the labels
+    let mut statements = Vec::new();
+    let mut origin = None;
+    let mut pc: u32 = 0;
+    for (i, line) in source.lines().enumerate() {
+	let line_no = i + 1;
+	let err = |msg: String| EmuErr::Assemble(line_no, msg);
+	let mut text = line.split(';').next().unwrap_or("").trim();
+
+	if let Some((name, value)) = text.split_once('=') {
+	    let name = name.trim();
+	    if is_identifier(name) {
+		let value = asm.eval(&parse_expr(value).map_err(err)?).ok_or_else(|| err(format!("{} must be defined before use", value.trim())))?;
+		asm.define(name, value).map_err(err)?;
+		continue;
+	    }
+	}
+	if let Some((label, rest)) = text.split_once(':') {
+	    let label = label.trim();
+	    if !is_identifier(label) {
+		return Err(err(format!("invalid label {}", label)));
+	    }
+	    asm.define(label, pc as u16).map_err(err)?;
+	    text = rest.trim();
+	}
+	if text.is_empty() {
+	    continue;
+	}
+
+	let (word, operand) = match text.split_once(char::is_whitespace) {
+	    Some((word, operand)) => (word, operand.trim()),
+	    None => (text, ""),
+	};
+	let statement = if word.eq_ignore_ascii_case(".org") {
+	    let addr = asm.eval(&parse_expr(operand).map_err(err)?).ok_or_else(|| err(".org needs a known address".to_string()))?;
+	    if origin.is_some() && (addr as u32) < pc {
+		return Err(err(format!(".org ${:04X} is behind ${:04X}", addr, pc)));
+	    }
+	    origin.get_or_insert(addr);
+	    pc = addr as u32;
+	    continue;
+	} else if word.eq_ignore_ascii_case(".byte") || word.eq_ignore_ascii_case(".word") {
+	    let exprs = operand.split(',').map(parse_expr).collect::<Result<Vec<_>, _>>().map_err(err)?;
+	    Statement::Data {
+		word: word.eq_ignore_ascii_case(".word"),
+		exprs,
+	    }
+	} else {
+	    let op = asm.mnemonic(word).ok_or_else(|| err(format!("unknown mnemonic {}", word)))?;
+	    let (mode, expr) = asm.operand(op, operand).map_err(err)?;
+	    Statement::Instruction { op, mode, expr }
+	};
+
+	let addr = pc as u16;
+	origin.get_or_insert(addr);
+	pc += statement.len();
+	if pc > 0x10000 {
+	    return Err(err("program runs past $FFFF".to_string()));
+	}
+	statements.push((line_no, addr, statement));
+    }
+
+    // Second pass: every label is known, emit the bytes
+    let origin = origin.unwrap_or(0);
+    let mut bytes = Vec::new();
+    for (line_no, addr, statement) in statements {
+	let err = |msg: String| EmuErr::Assemble(line_no, msg);
+	bytes.resize((addr - origin) as usize, 0);
+	match statement {
+	    Statement::Data { word, exprs } => {
+		for expr in &exprs {
+		    let value = asm.resolve(expr).map_err(err)?;
+		    if word {
+			bytes.extend_from_slice(&value.to_le_bytes());
+		    } else {
+			bytes.push(asm.byte(value).map_err(err)?);
+		    }
+		}
+	    },
+	    Statement::Instruction { op, mode, expr } => {
+		bytes.push(asm.opcode(op, mode).ok_or_else(|| err(format!("{:?} has no {:?} mode", op, mode)))?);
+		let value = match &expr {
+		    Some(expr) => asm.resolve(expr).map_err(err)?,
+		    None => 0,
+		};
+		match mode {
+		    AM::IMP => (),
+		    AM::REL => {
+			let offset = value as i32 - (addr as i32 + 2);
+			if !(-128..=127).contains(&offset) {
+			    return Err(err(format!("branch to ${:04X} is out of range", value)));
+			}
+			bytes.push(offset as u8);
+		    },
+		    _ if mode.operand_len() == 1 => bytes.push(asm.byte(value).map_err(err)?),
+		    _ => bytes.extend_from_slice(&value.to_le_bytes()),
+		}
+	    },
+	}
+    }
+
+    Ok(Assembly {
+	origin,
+	bytes,
+	labels: asm.labels,
+    })
+}
+
+enum Statement {
+    Data { word: bool, exprs: Vec<Expr> },
+    Instruction { op: Op, mode: AM, expr: Option<Expr> },
+}
+
+impl Statement {
+    fn len(&self) -> u32 {
+	match self {
+	    Statement::Data { word, exprs } => exprs.len() as u32 * if *word { 2 } else { 1 },
+	    Statement::Instruction { mode, .. } => 1 + mode.operand_len() as u32,
+	}
+    }
+}
+
+/// Sum of terms, optionally narrowed to its low or high byte
+struct Expr {
+    terms: Vec<(bool, Term)>,
+    byte: Option<Byte>,
+}
+
+enum Term {
+    Number(u16),
+    Label(String),
+}
+
+enum Byte {
+    Low,
+    High,
+}
+
+struct Assembler {
+    variant: Variant,
+    labels: HashMap<String, u16>,
+}
+
+impl Assembler {
+    fn define(&mut self, name: &str, value: u16) -> Result<(), String> {
+	if self.labels.insert(name.to_string(), value).is_some() {
+	    return Err(format!("{} is already defined", name));
+	}
+	Ok(())
+    }
+
+    /// Value of `expr`, or `None` while a label in it is still unknown
+    fn eval(&self, expr: &Expr) -> Option<u16> {
+	let mut value: u16 = 0;
+	for (negative, term) in &expr.terms {
+	    let term = match term {
+		Term::Number(n) => *n,
+		Term::Label(name) => *self.labels.get(name)?,
+	    };
+	    value = if *negative { value.wrapping_sub(term) } else { value.wrapping_add(term) };
+	}
+	Some(match expr.byte {
+	    Some(Byte::Low) => value & 0xff,
+	    Some(Byte::High) => value >> 8,
+	    None => value,
+	})
+    }
+
+    fn resolve(&self, expr: &Expr) -> Result<u16, String> {
+	self.eval(expr).ok_or_else(|| {
+	    let unknown = expr.terms.iter().find_map(|(_, term)| match term {
+		Term::Label(name) if !self.labels.contains_key(name) => Some(name.as_str()),
+		_ => None,
+	    });
+	    format!("undefined label {}", unknown.unwrap_or("?"))
+	})
+    }
+
+    fn byte(&self, value: u16) -> Result<u8, String> {
+	u8::try_from(value).map_err(|_| format!("${:04X} doesn't fit in a byte", value))
+    }
+
+    fn mnemonic(&self, word: &str) -> Option<Op> {
+	let word = word.to_ascii_uppercase();
+	let word = if word == "ISB" { "ISC" } else { word.as_str() };
+	(0..=255u8)
+	    .map(|opcode| self.variant.decode(opcode).opcode)
+	    .find(|op| format!("{:?}", op) == word)
+    }
+
+    /// The opcode encoding `op` in `mode`, preferring the official one
+    fn opcode(&self, op: Op, mode: AM) -> Option<u8> {
+	let mut matching = (0..=255u8).filter(|&opcode| {
+	    let i = self.variant.decode(opcode);
+	    i.opcode == op && i.addr_mode == mode
+	});
+	let first = matching.next()?;
+	Some(std::iter::once(first).chain(matching)
+	     .find(|&opcode| !self.variant.is_unofficial(opcode))
+	     .unwrap_or(first))
+    }
+
+    fn has_mode(&self, op: Op, mode: AM) -> bool {
+	self.opcode(op, mode).is_some()
+    }
+
+    /// Picks the addressing mode from the operand's syntax, preferring the
+    /// zero page modes when the address is known to fit.
+    fn operand(&self, op: Op, operand: &str) -> Result<(AM, Option<Expr>), String> {
+	if operand.is_empty() || operand.eq_ignore_ascii_case("a") {
+	    return Ok((AM::IMP, None));
+	}
+	if let Some(value) = operand.strip_prefix('#') {
+	    return Ok((AM::IMM, Some(parse_expr(value)?)));
+	}
+	if self.has_mode(op, AM::REL) {
+	    return Ok((AM::REL, Some(parse_expr(operand)?)));
+	}
+
+	let upper = operand.to_ascii_uppercase();
+	let (inner, zp, abs) = if operand.starts_with('(') {
+	    if let Some(inner) = strip_suffix(operand, &upper, ",X)") {
+		(&inner[1..], AM::INX, AM::IAX)
+	    } else if let Some(inner) = strip_suffix(operand, &upper, "),Y") {
+		(&inner[1..], AM::INY, AM::INY)
+	    } else if let Some(inner) = strip_suffix(operand, &upper, ")") {
+		(&inner[1..], AM::ZPI, AM::IND)
+	    } else {
+		return Err(format!("can't parse operand {}", operand));
+	    }
+	} else if let Some(inner) = strip_suffix(operand, &upper, ",X") {
+	    (inner, AM::ZPX, AM::ABX)
+	} else if let Some(inner) = strip_suffix(operand, &upper, ",Y") {
+	    (inner, AM::ZPY, AM::ABY)
+	} else {
+	    (operand, AM::ZPG, AM::ABS)
+	};
+
+	let expr = parse_expr(inner)?;
+	let fits_zp = matches!(self.eval(&expr), Some(value) if value <= 0xff);
+	let mode = if self.has_mode(op, zp) && (fits_zp || !self.has_mode(op, abs)) {
+	    zp
+	} else {
+	    abs
+	};
+	Ok((mode, Some(expr)))
+    }
+}
+
+/// Strips `suffix` from `operand` by matching against its uppercased copy
+fn strip_suffix<'a>(operand: &'a str, upper: &str, suffix: &str) -> Option<&'a str> {
+    upper.strip_suffix(suffix).map(|rest| operand[..rest.len()].trim())
+}
+
+fn is_identifier(s: &str) -> bool {
+    let mut chars = s.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_') &&
+	chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn parse_expr(s: &str) -> Result<Expr, String> {
+    let mut s = s.trim();
+    let byte = if let Some(rest) = s.strip_prefix('<') {
+	s = rest;
+	Some(Byte::Low)
+    } else if let Some(rest) = s.strip_prefix('>') {
+	s = rest;
+	Some(Byte::High)
+    } else {
+	None
+    };
+
+    let mut terms = Vec::new();
+    let mut negative = false;
+    let mut rest = s.trim();
+    loop {
+	let end = rest.find(['+', '-']).unwrap_or(rest.len());
+	terms.push((negative, parse_term(rest[..end].trim())?));
+	if end == rest.len() {
+	    break;
+	}
+	negative = rest.as_bytes()[end] == b'-';
+	rest = &rest[end + 1..];
+    }
+    Ok(Expr { terms, byte })
+}
+
+fn parse_term(s: &str) -> Result<Term, String> {
+    let number = if let Some(hex) = s.strip_prefix('$') {
+	u16::from_str_radix(hex, 16)
+    } else if let Some(bin) = s.strip_prefix('%') {
+	u16::from_str_radix(bin, 2)
+    } else if s.starts_with(|c: char| c.is_ascii_digit()) {
+	s.parse()
+    } else if is_identifier(s) {
+	return Ok(Term::Label(s.to_string()));
+    } else {
+	return Err(format!("can't parse {:?}", s));
+    };
+    number.map(Term::Number).map_err(|_| format!("bad number {}", s))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bus::Bus;
+    use crate::cartridge::Cartridge;
+    use crate::cpu::Cpu;
+    use super::*;
+
+    #[test]
+    fn test_addressing_modes() {
+	let program = assemble("
+	    ZP = $10
+	    .org $8000
+	    LDA #$01        ; immediate
+	    STA ZP          ; zero page
+	    STA $0200,X
+	    LDX ZP,Y
+	    LDA (ZP,X)
+	    LDA (ZP),Y
+	    ASL A
+	    ISB $44
+	    JMP (vector)
+	vector: .word $1234
+	    .byte <vector, >vector, %101
+	").unwrap();
+	assert_eq!(program.origin, 0x8000);
+	assert_eq!(program.label("vector"), Some(0x8013));
+	assert_eq!(program.bytes, [
+	    0xa9, 0x01,
+	    0x85, 0x10,
+	    0x9d, 0x00, 0x02,
+	    0xb6, 0x10,
+	    0xa1, 0x10,
+	    0xb1, 0x10,
+	    0x0a,
+	    0xe7, 0x44,
+	    0x6c, 0x13, 0x80,
+	    0x34, 0x12,
+	    0x13, 0x80, 0x05,
+	]);
+    }
+
+    #[test]
+    fn test_labels_and_branches() {
+	let program = assemble("
+	    .org $c000
+	    LDX #3
+	loop: DEX
+	    BNE loop
+	    BEQ done
+	    NOP
+	done: JMP loop
+	").unwrap();
+	assert_eq!(program.bytes, [
+	    0xa2, 0x03,
+	    0xca,
+	    0xd0, 0xfd,
+	    0xf0, 0x01,
+	    0xea,
+	    0x4c, 0x02, 0xc0,
+	]);
+    }
+
+    #[test]
+    fn test_errors() {
+	assert!(matches!(assemble("FOO #1"), Err(EmuErr::Assemble(1, _))));
+	assert!(matches!(assemble("NOP\nJMP nowhere"), Err(EmuErr::Assemble(2, _))));
+	assert!(matches!(assemble("LDA #$100"), Err(EmuErr::Assemble(1, _))));
+	assert!(matches!(assemble("STX $1234,X"), Err(EmuErr::Assemble(1, _))));
+	assert_eq!(assemble("NOP\nJMP nowhere").unwrap_err().to_string(), "line 2: undefined label nowhere");
+    }
+
+    #[test]
+    fn test_65c02() {
+	let program = assemble_for("STZ $10\nBRA 0\nLDA ($10)", Variant::Cmos65C02).unwrap();
+	assert_eq!(program.bytes, [0x64, 0x10, 0x80, 0xfc, 0xb2, 0x10]);
+    }
+
+    #[test]
+    fn test_nrom_image() {
+	let program = assemble("
+	    .org $c000
+	reset: LDA #$42
+	    STA $00
+	    JMP reset
+	").unwrap();
+	let cartridge = Cartridge::from_bytes(&program.to_nrom().unwrap()).unwrap();
+	let mut bus = Bus::new();
+	bus.insert_cartridge(cartridge);
+	let mut cpu = Cpu::new();
+	cpu.set_cycle_stepped(true);
+	cpu.power_on();
+	cpu.reset(&mut bus);
+	assert_eq!(cpu.pc(), 0xc000);
+	for _ in 0..3 {
+	    cpu.step(&mut bus).unwrap();
+	}
+	assert_eq!(bus.read(0x00), 0x42);
+	assert_eq!(cpu.pc(), 0xc000);
+	// mirrored at $8000
+	assert_eq!(bus.read(0x8000), 0xa9);
+    }
+}
@@ -1,63 +1,218 @@
-use std::cell::RefCell;
 use std::path::Path;
-use std::rc::Rc;
 use super::cartridge::Cartridge;
+use super::clock::{MasterClock, Region};
 use super::controller::Controller;
 use super::err::EmuErr;
-use super::mapper::{Mapper, build_mapper};
+use super::frame::Palette;
+use super::interrupt::{InterruptController, IrqSource};
+use super::mapper::{Mapper, Unplugged, build_mapper};
+use super::memory::{Dma, Memory, RamInit};
 use super::ppu::Ppu;
 
+/// Something odd the program did that doesn't stop emulation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Diagnostic {
+    /// A write to cartridge space the cartridge doesn't decode
+    UnmappedWrite { addr: u16, data: u8 },
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+	match self {
+	    Diagnostic::UnmappedWrite { addr, data } => write!(f, "unmapped write ${:02X} to ${:04X}", data, addr),
+	}
+    }
+}
+
 pub struct Bus {
     ram: Vec<u8>,
     mapper: Option<Box<dyn Mapper>>,
     ppu: Ppu,
+    clock: MasterClock,
+    // Error from a catch up during a register access, returned by the next step
+    ppu_err: Option<EmuErr>,
     controller: Controller,
+    interrupts: InterruptController,
+    ram_init: RamInit,
+    // Last value on the data bus, returned by reads nothing responds to
+    open_bus: u8,
+    oam_dma: bool,
+    dmc_dma: bool,
+    diagnostics: Vec<Diagnostic>,
 }
 
 impl Bus {
 
-    pub fn new(nmi_signal: Rc<RefCell<bool>>) -> Self {
+    pub fn new() -> Self {
 	Self {
 	    ram: [0;u16::MAX as usize].to_vec(),
 	    mapper: None,
-	    ppu: Ppu::new(nmi_signal),
+	    ppu: Ppu::new(),
+	    clock: MasterClock::new(Region::default()),
+	    ppu_err: None,
 	    controller: Controller::new(),
+	    interrupts: InterruptController::new(),
+	    ram_init: RamInit::default(),
+	    open_bus: 0,
+	    oam_dma: false,
+	    dmc_dma: false,
+	    diagnostics: Vec::new(),
 	}
     }
 
-    pub fn draw(&self, buf: &mut [u8]) {
-	if let Some(m) = &self.mapper {
-//	    self.ppu.draw(buf, m.as_ref());
+    /// How ram is filled by `power_on`
+    pub fn set_ram_init(&mut self, ram_init: RamInit) {
+	self.ram_init = ram_init;
+    }
+
+    /// Switches between NTSC and PAL timing
+    #[allow(dead_code)]
+    pub fn set_region(&mut self, region: Region) {
+	self.catch_up_ppu();
+	self.clock.reset(region);
+	self.ppu.set_region(region);
+    }
+
+    /// Power up state for everything on the bus. The cartridge stays in.
+    pub fn power_on(&mut self) {
+	self.ram_init.fill(&mut self.ram);
+	self.ppu.power_on();
+	self.clock.reset(self.clock.region());
+	self.ppu_err = None;
+	self.controller = Controller::new();
+	if let Some(m) = &mut self.mapper {
+	    m.power_on();
 	}
+	self.interrupts = InterruptController::new();
+	self.oam_dma = false;
+	self.dmc_dma = false;
+    }
+
+    /// The reset button. Only the ppu listens to the reset line, ram and
+    /// the mapper keep their state.
+    pub fn reset(&mut self) {
+	self.ppu.reset();
+	self.oam_dma = false;
+	self.dmc_dma = false;
+    }
+
+    /// Pulls the IRQ line low on behalf of `source`.
+    #[allow(dead_code)]
+    pub fn assert_irq(&mut self, source: IrqSource) {
+	self.interrupts.assert_irq(source);
+    }
+
+    /// Releases `source`'s hold on the IRQ line. The line stays asserted
+    /// while any other source is still holding it.
+    #[allow(dead_code)]
+    pub fn release_irq(&mut self, source: IrqSource) {
+	self.interrupts.release_irq(source);
+    }
+
+    /// The cpu's interrupt inputs
+    #[allow(dead_code)]
+    pub fn interrupts(&self) -> &InterruptController {
+	&self.interrupts
+    }
+
+    /// For devices outside the bus, like expansion port hardware
+    #[allow(dead_code)]
+    pub fn interrupts_mut(&mut self) -> &mut InterruptController {
+	&mut self.interrupts
     }
 
-    /// Ticks ppu once
+    /// Copies the ppu's NMI output onto the cpu's NMI line
+    fn sync_nmi(&mut self) {
+	self.interrupts.set_nmi_line(self.ppu.nmi_line());
+    }
+
+    /// Copies page `page` into ppu OAM through $2004. The cpu is stalled
+    /// for the transfer once the current instruction completes.
+    fn oam_dma(&mut self, page: u8) {
+	self.catch_up_ppu();
+	let base = (page as u16) << 8;
+	for i in 0..=0xff {
+	    let data = self.read(base | i);
+	    self.ppu.write(0x2004, data, self.mapper.as_deref_mut().unwrap_or(&mut Unplugged));
+	}
+	self.oam_dma = true;
+    }
+
+    /// Fetches a sample byte for the DMC, stealing cycles from the cpu.
+    #[allow(dead_code)]
+    pub fn dmc_fetch(&mut self, addr: u16) -> u8 {
+	self.dmc_dma = true;
+	self.read(addr)
+    }
+
+    /// Hands back what was noticed since the last call. Only the first
+    /// `MAX_DIAGNOSTICS` are kept in between.
+    pub fn take_diagnostics(&mut self) -> Vec<Diagnostic> {
+	std::mem::take(&mut self.diagnostics)
+    }
+
+    fn diagnose(&mut self, diagnostic: Diagnostic) {
+	if self.diagnostics.len() < Self::MAX_DIAGNOSTICS {
+	    self.diagnostics.push(diagnostic);
+	}
+    }
+
+    /// The ppu as of the last `catch_up`
+    pub fn ppu(&self) -> &Ppu {
+	&self.ppu
+    }
+
+    /// Writes the ppu's picture to `buf` as RGB24, see `Frame::to_rgb24`
+    pub fn draw(&self, palette: &Palette, buf: &mut [u8]) {
+	self.ppu.frame().to_rgb24(palette, buf);
+    }
+
+    /// Whether the ppu completed a frame since the last call
+    pub fn take_frame_complete(&mut self) -> bool {
+	self.ppu.take_frame_complete()
+    }
+
+    /// Advances the master clock by one cpu cycle. The ppu only runs when
+    /// an event it scheduled comes due, see `catch_up`.
     pub fn step(&mut self) -> Result<(), EmuErr> {
-	if let Some(m) = &self.mapper {
-	    let _before_nmi = *self.ppu.nmi_signal.borrow();
-	    // The ppu is ticked at a 3-1 ratio with cpu cycles
-	    self.ppu.step(m.as_ref())?;
-	    self.ppu.step(m.as_ref())?;
-	    self.ppu.step(m.as_ref())?;
-	    let _after_nmi = *self.ppu.nmi_signal.borrow();
-
-//	    if !before_nmi && after_nmi {
-//		(self.update_game)(&self.ppu, &mut self.controller);
-//	    }
+	if self.clock.tick_cpu() {
+	    self.catch_up()?;
+	}
+	match self.ppu_err.take() {
+	    Some(e) => Err(e),
+	    None => Ok(()),
 	}
+    }
 
-	
+    /// Runs the ppu up to the cpu's time and schedules its next event.
+    /// Anything looking at the ppu from outside has to call this first.
+    pub fn catch_up(&mut self) -> Result<(), EmuErr> {
+	let dots = self.clock.take_ppu_dots();
+	self.ppu.run(dots, self.mapper.as_deref().unwrap_or(&Unplugged))?;
+	self.sync_nmi();
+	self.clock.schedule_ppu(self.ppu.dots_until_event());
 	Ok(())
     }
 
+    /// `catch_up` before the cpu touches the ppu, errors wait for `step`
+    fn catch_up_ppu(&mut self) {
+	if let Err(e) = self.catch_up() {
+	    self.ppu_err.get_or_insert(e);
+	}
+    }
+
     /// Loads an iNES rom file, constructing the appropriate mapper based on
     /// parsed header information.
     pub fn load_rom<P: AsRef<Path>>(&mut self, rom_path: P) -> Result<(), EmuErr> {
 	let cartridge = Cartridge::load_rom(rom_path)?;
-	self.ppu.set_mirror(cartridge.mirroring());
+	self.insert_cartridge(cartridge);
+	Ok(())
+    }
+
+    /// Plugs in an already loaded cartridge
+    pub fn insert_cartridge(&mut self, cartridge: Cartridge) {
 	let mapper = build_mapper(cartridge);
 	self.mapper = Some(mapper);
-	Ok(())
     }
 
     const MEMORY_START: u16 = 0x0;
@@ -67,15 +222,14 @@ impl Bus {
     const PPU_END: u16 = 0x3fff;
     const OAM_DMA: u16 = 0x4014;
 
+    const APU_STATUS: u16 = 0x4015;
     const CONTROLLER1: u16 = 0x4016;
-    const APU_START: u16 = 0x4000;
-    const APU_END: u16 = 0x4017;
+    const CONTROLLER2: u16 = 0x4017;
 
+    const CARTRIDGE_START: u16 = 0x4020;
+    const CARTRIDGE_END: u16 = 0xffff;
 
-    const EXPANSION_START: u16 = 0x4020;
-    const EXPANSION_END: u16 = 0x5fff;
-    const PRG_ROM_START: u16 = 0x8000;
-    const PRG_ROM_END: u16 = 0xffff;
+    const MAX_DIAGNOSTICS: usize = 256;
     
     /// Matches the address range and reads from the appropriate memory source.
     ///
@@ -86,24 +240,58 @@ impl Bus {
     /// [0x4000,0x4017] - apu & I/O registers
     /// [0x4018,0x401f] - apu & I/O functionality which is normally disabled
     /// [0x4020,0xffff] - catridge space: prg rom, prg ram, and mapper regsiters
+    ///
+    /// Reads of write only registers and of anything unmapped return the
+    /// open bus, the last value driven on the data bus.
     pub fn read(&mut self, addr: u16) -> u8 {
-	if let Some(m) = &self.mapper {
-	    match addr {
-		// addr & 0x07ff (2kib) to implement mirroring
-		// effectively addr % 2KiB
-		Self::MEMORY_START..=Self::MEMORY_END => self.ram[(addr & 0x7ff) as usize],
-		// PPU memory-mapped registers are [0x2000,0x2007] and mirrored every 8 bytes
-		// [0x2008,0x3fff]
-		Self::PPU_START..=Self::PPU_END => self.ppu.read(addr),
-		// TODO OAM DMA and APU range intersect. How to handle this better?
-		Self::OAM_DMA => todo!("oam direct memory access."),
-		Self::CONTROLLER1 => self.controller.read(),
-		Self::APU_START..=Self::APU_END => todo!("apu mem"),
-		Self::EXPANSION_START..=Self::EXPANSION_END => todo!("cartridge expansion rom"),
-		Self::PRG_ROM_START..=Self::PRG_ROM_END => m.read_prg_rom(addr),
-		_ => panic!("bus read address out of range {:x}", addr),
-	    }
-	} else { panic!("no mapper for read"); }
+	let data = match addr {
+	    // PPU memory-mapped registers are [0x2000,0x2007] and mirrored every 8 bytes
+	    // [0x2008,0x3fff]
+	    Self::PPU_START..=Self::PPU_END => {
+		self.catch_up_ppu();
+		let data = self.ppu.read(addr, self.mapper.as_deref().unwrap_or(&Unplugged));
+		self.sync_nmi();
+		data
+	    },
+	    // Status is read inside the cpu and doesn't drive the data bus
+	    Self::APU_STATUS => return self.open_bus & 0x20,
+	    // Only the low bits are driven by the controller ports
+	    Self::CONTROLLER1 => (self.open_bus & 0xe0) | (self.controller.read() & 0x1f),
+	    Self::CARTRIDGE_START..=Self::CARTRIDGE_END => self.mapper.as_mut()
+		.and_then(|m| m.read_prg(addr))
+		.unwrap_or(self.open_bus),
+	    _ => self.peek_bus(addr),
+	};
+	self.open_bus = data;
+	data
+    }
+
+    /// Returns what `read` would without side effects: the ppu, the mapper
+    /// and the open bus are left alone. `None` for the controller port,
+    /// which can't be inspected without shifting it. Ppu registers are as of
+    /// the last `catch_up`.
+    pub fn peek(&self, addr: u16) -> Option<u8> {
+	match addr {
+	    Self::PPU_START..=Self::PPU_END => Some(self.ppu.peek(addr)),
+	    Self::APU_STATUS => Some(self.open_bus & 0x20),
+	    Self::CONTROLLER1 => None,
+	    Self::CARTRIDGE_START..=Self::CARTRIDGE_END => Some(self.mapper.as_ref()
+		.and_then(|m| m.peek_prg(addr))
+		.unwrap_or(self.open_bus)),
+	    _ => Some(self.peek_bus(addr)),
+	}
+    }
+
+    /// The parts of the cpu address space reading doesn't disturb
+    fn peek_bus(&self, addr: u16) -> u8 {
+	match addr {
+	    // addr & 0x07ff (2kib) to implement mirroring
+	    // effectively addr % 2KiB
+	    Self::MEMORY_START..=Self::MEMORY_END => self.ram[(addr & 0x7ff) as usize],
+	    Self::CONTROLLER2 => self.open_bus & 0xe0,
+	    // write only apu registers including OAM_DMA and disabled I/O
+	    _ => self.open_bus,
+	}
     }
 
     pub fn read_u16(&mut self, addr: u16) -> u16 {
@@ -121,15 +309,72 @@ impl Bus {
     /// [0x4000,0x4017] - apu & I/O registers, notably the OAM direct memory access register is 0x4014
     /// [0x4018,0x401f] - apu & I/O functionality which is normally disabled
     /// [0x4020,0xffff] - catridge space: prg rom, prg ram, and mapper regsiters
+    ///
+    /// Cartridge writes the mapper doesn't decode are reported through
+    /// `take_diagnostics`.
     pub fn write(&mut self, addr: u16, data: u8) {
+	self.open_bus = data;
 	match addr {
 	    // addr & 0x07ff (2kib) to implement mirroring
 	    // effectively addr % 2KiB
 	    Self::MEMORY_START..=Self::MEMORY_END => self.ram[(addr & 0x7ff) as usize] = data,
-	    Self::PPU_START..=Self::PPU_END => self.ppu.write(addr, data),
+	    Self::PPU_START..=Self::PPU_END => {
+		self.catch_up_ppu();
+		self.ppu.write(addr, data, self.mapper.as_deref_mut().unwrap_or(&mut Unplugged));
+		self.sync_nmi();
+	    },
+	    Self::OAM_DMA => self.oam_dma(data),
 	    Self::CONTROLLER1 => self.controller.write(data),
-	    Self::PRG_ROM_START..=Self::PRG_ROM_END => panic!("prg rom write attempt"),
+	    Self::CARTRIDGE_START..=Self::CARTRIDGE_END => {
+		let decoded = match &mut self.mapper {
+		    Some(m) => m.write_prg(addr, data),
+		    None => false,
+		};
+		if !decoded {
+		    self.diagnose(Diagnostic::UnmappedWrite { addr, data });
+		}
+	    },
 	    _ => (),
 	}
     }
 }
+
+impl Memory for Bus {
+    fn read(&mut self, addr: u16) -> u8 {
+	Bus::read(self, addr)
+    }
+
+    fn write(&mut self, addr: u16, data: u8) {
+	Bus::write(self, addr, data)
+    }
+
+    fn peek(&self, addr: u16) -> Option<u8> {
+	Bus::peek(self, addr)
+    }
+
+    fn tick(&mut self) -> Result<(), EmuErr> {
+	self.step()
+    }
+
+    fn nmi_pending(&self) -> bool {
+	self.interrupts.nmi_pending()
+    }
+
+    fn acknowledge_nmi(&mut self) {
+	self.interrupts.acknowledge_nmi();
+    }
+
+    fn irq(&self) -> bool {
+	self.interrupts.irq()
+    }
+
+    fn take_dma(&mut self) -> Option<Dma> {
+	if std::mem::take(&mut self.dmc_dma) {
+	    Some(Dma::Dmc)
+	} else if std::mem::take(&mut self.oam_dma) {
+	    Some(Dma::Oam)
+	} else {
+	    None
+	}
+    }
+}
@@ -36,7 +36,17 @@ impl Cartridge {
     /// [9]   - ?
     /// [A,F] - reserved. must be zero.
     pub fn load_rom<P: AsRef<Path>>(rom_path: P) -> Result<Self, EmuErr> {
-	let mut file = OpenOptions::new().read(true).open(rom_path).map_err(EmuErr::ReadRom)?;
+	let file = OpenOptions::new().read(true).open(rom_path).map_err(EmuErr::ReadRom)?;
+	Self::read_rom(file)
+    }
+
+    /// Reads an iNES ROM already in memory, see `load_rom`
+    #[allow(dead_code)]
+    pub fn from_bytes(rom: &[u8]) -> Result<Self, EmuErr> {
+	Self::read_rom(rom)
+    }
+
+    fn read_rom<R: Read>(mut file: R) -> Result<Self, EmuErr> {
 	let mut header = [0;16];
 
 	file.read_exact(&mut header).map_err(EmuErr::ReadRom)?;
@@ -108,8 +118,20 @@ impl Cartridge {
     }
 }
 
+/// How the four logical nametables map onto the console's 2KiB of VRAM.
+/// https://www.nesdev.org/wiki/Mirroring#Nametable_Mirroring
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Mirroring {
+    /// $2000 = $2400 and $2800 = $2C00, for vertical scrolling
     Horizontal,
+    /// $2000 = $2800 and $2400 = $2C00, for horizontal scrolling
     Vertical,
+    /// Every nametable is the first KiB of VRAM
+    #[allow(dead_code)]
+    SingleScreenA,
+    /// Every nametable is the second KiB of VRAM
+    #[allow(dead_code)]
+    SingleScreenB,
+    /// $2800 and $2C00 are in VRAM on the cartridge
     FourScreen,
 }
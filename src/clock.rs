@@ -0,0 +1,118 @@
+/// The console's timing. The cpu and ppu run off dividers of one master
+/// clock, 21.477272 MHz on NTSC and 26.601712 MHz on PAL.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Region {
+    #[default]
+    Ntsc,
+    #[allow(dead_code)]
+    Pal,
+}
+
+impl Region {
+    /// Master clock ticks per cpu cycle
+    pub fn cpu_divider(self) -> u64 {
+	match self {
+	    Region::Ntsc => 12,
+	    Region::Pal => 16,
+	}
+    }
+
+    /// Master clock ticks per ppu dot
+    pub fn ppu_divider(self) -> u64 {
+	match self {
+	    Region::Ntsc => 4,
+	    Region::Pal => 5,
+	}
+    }
+
+    /// Scanlines per frame, including vblank and the pre-render scanline
+    pub fn scanlines(self) -> usize {
+	match self {
+	    Region::Ntsc => 262,
+	    Region::Pal => 312,
+	}
+    }
+}
+
+/// Keeps the cpu and the ppu on one timeline counted in master clock ticks.
+///
+/// The cpu drives time forward. The ppu runs lazily, only catching up when
+/// the cpu touches it or when an event it scheduled, such as the start of
+/// vblank, comes due.
+pub struct MasterClock {
+    region: Region,
+    // Master ticks the cpu has run
+    cpu: u64,
+    // Master ticks the ppu has caught up to
+    ppu: u64,
+    next_event: u64,
+}
+
+impl MasterClock {
+    pub fn new(region: Region) -> Self {
+	Self {
+	    region,
+	    cpu: 0,
+	    ppu: 0,
+	    next_event: 0,
+	}
+    }
+
+    pub fn region(&self) -> Region {
+	self.region
+    }
+
+    /// Back to zero for `region`, the ppu catches up on the next cpu cycle
+    pub fn reset(&mut self, region: Region) {
+	*self = Self::new(region);
+    }
+
+    /// Counts one cpu cycle. Returns true when an event is due and the ppu
+    /// has to catch up.
+    pub fn tick_cpu(&mut self) -> bool {
+	self.cpu += self.region.cpu_divider();
+	self.cpu >= self.next_event
+    }
+
+    /// Whole ppu dots the ppu is behind the cpu. They are counted as run.
+    pub fn take_ppu_dots(&mut self) -> u64 {
+	let divider = self.region.ppu_divider();
+	let dots = (self.cpu - self.ppu) / divider;
+	self.ppu += dots * divider;
+	dots
+    }
+
+    /// Has the ppu catch up again once it would be `dots` dots ahead of
+    /// where it is now.
+    pub fn schedule_ppu(&mut self, dots: u64) {
+	self.next_event = self.ppu + dots * self.region.ppu_divider();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ppu_dots_per_cpu_cycle() {
+	for (region, dots) in [(Region::Ntsc, 3 * 5), (Region::Pal, 16)] {
+	    let mut clock = MasterClock::new(region);
+	    for _ in 0..5 {
+		clock.tick_cpu();
+	    }
+	    assert_eq!(clock.take_ppu_dots(), dots);
+	    assert_eq!(clock.take_ppu_dots(), 0);
+	}
+    }
+
+    #[test]
+    fn test_events() {
+	let mut clock = MasterClock::new(Region::Ntsc);
+	clock.schedule_ppu(7);
+	// 7 dots take 2 1/3 cpu cycles
+	assert!(!clock.tick_cpu());
+	assert!(!clock.tick_cpu());
+	assert!(clock.tick_cpu());
+	assert_eq!(clock.take_ppu_dots(), 9);
+    }
+}
@@ -1,8 +1,13 @@
-use std::cell::RefCell;
-use std::rc::Rc;
+use std::collections::HashSet;
+use std::fmt;
 use super::err::EmuErr;
-use super::bus::Bus;
-use super::opcodes::{OPCODES,I,AM,Op};
+use super::memory::{Dma, Memory};
+use super::opcodes::{I,AM,Op,Access,Variant};
+use dispatch::Dispatch;
+
+mod dispatch;
+#[cfg(test)]
+mod single_step;
 
 pub struct Cpu {
     // Registers
@@ -21,10 +26,28 @@ pub struct Cpu {
     flag_v: bool,
     flag_n: bool,
 
+    variant: Variant,
     cycles: usize,
+    total_cycles: usize,
+    access: Access,
+    cycle_stepped: bool,
+    bus_err: Option<EmuErr>,
+    // Bus noise ORed into A by the unstable XAA and LAX #imm opcodes.
+    magic: u8,
+    breakpoints: HashSet<u16>,
+    // Breakpoint already reported, execution resumes from it on the next step.
+    resume_pc: Option<u16>,
+    // Set when the cpu locks up, only a reset recovers.
+    halted: Option<Halt>,
     interrupt: Option<Interrupt>,
-    instruction: Option<I>, // for debugging
-    nmi_signal: Rc<RefCell<bool>>,
+    last_opcode: Option<u8>, // for debugging
+    // Interrupt inputs as seen by the last poll, for `state`
+    nmi_seen: bool,
+    irq_seen: bool,
+    // I flag as seen by the interrupt poll at the end of the last instruction.
+    // CLI, SEI and PLP change I after the poll, delaying their effect by one
+    // instruction.
+    irq_inhibit: bool,
 }
 
 /// Macro rule to implement post-increment for a mutable expression.
@@ -39,19 +62,232 @@ macro_rules! post_inc {
     };
 }
 
+/// Why the cpu stopped executing instructions.
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HaltReason {
+    /// A KIL (JAM) opcode locked up the cpu. Only a reset recovers.
+    Jam,
+    /// The next instruction is at a breakpoint. Stepping again executes it.
+    Breakpoint,
+    /// The opcode couldn't be executed.
+    Error,
+}
+
+/// Register snapshot
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Registers {
+    pub pc: u16,
+    pub sp: u8,
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub p: u8,
+}
+
+/// Reported by `Cpu::step` when execution stops. `opcode` is the byte at
+/// `pc`, the address of the instruction which stopped the cpu.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Halt {
+    pub reason: HaltReason,
+    pub opcode: u8,
+    pub pc: u16,
+    pub registers: Registers,
+}
+
+impl fmt::Display for Halt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+	let r = &self.registers;
+	write!(f, "{:?} at ${:04X} (opcode ${:02X}) A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X}",
+	       self.reason, self.pc, self.opcode, r.a, r.x, r.y, r.p, r.sp)
+    }
+}
+
+/// Processor status flags
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Flags {
+    pub carry: bool,
+    pub zero: bool,
+    pub interrupt_disable: bool,
+    pub decimal: bool,
+    pub overflow: bool,
+    pub negative: bool,
+}
+
+impl Flags {
+    /// Unpacks the P register. Bits 4 and 5 aren't flags and are ignored.
+    pub fn from_p(p: u8) -> Self {
+	Self {
+	    carry: p & 0x01 != 0,
+	    zero: p & 0x02 != 0,
+	    interrupt_disable: p & 0x04 != 0,
+	    decimal: p & 0x08 != 0,
+	    overflow: p & 0x40 != 0,
+	    negative: p & 0x80 != 0,
+	}
+    }
+
+    /// Packs the flags into P, with bit 5 set and B clear.
+    pub fn p(self) -> u8 {
+	0x20 |
+	self.carry as u8 |
+	(self.zero as u8) << 1 |
+	(self.interrupt_disable as u8) << 2 |
+	(self.decimal as u8) << 3 |
+	(self.overflow as u8) << 6 |
+	(self.negative as u8) << 7
+    }
+}
+
+/// Interrupts waiting to be serviced
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PendingInterrupts {
+    /// Requested with `Cpu::interrupt`, serviced before the next instruction.
+    pub requested: Option<Interrupt>,
+    /// An NMI edge was waiting at the last poll. The bus latches it,
+    /// `Cpu::set_state` leaves it alone.
+    pub nmi: bool,
+    /// The IRQ line was asserted at the last poll. It's driven by the
+    /// devices, `Cpu::set_state` leaves it alone.
+    pub irq: bool,
+    /// IRQs are masked at the next poll. CLI, SEI and PLP update this one
+    /// instruction late.
+    pub irq_inhibit: bool,
+}
+
+/// Cpu state for debuggers, save states and tools. Read it with
+/// `Cpu::state` and write it back with `Cpu::set_state`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuState {
+    pub pc: u16,
+    pub sp: u8,
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub flags: Flags,
+    /// Cycles executed since reset
+    pub cycles: usize,
+    /// Idle cycles left before the next instruction starts. Always 0 when
+    /// cycle stepped.
+    pub stall: u16,
+    pub interrupts: PendingInterrupts,
+    /// Opcode of the last instruction executed
+    pub last_opcode: Option<u8>,
+}
+
 #[allow(dead_code)]
-#[derive(Debug, Clone, Copy)]
+impl CpuState {
+    /// Version written by `to_bytes`. Bumped whenever the layout changes.
+    pub const VERSION: u8 = 2;
+    pub const SERIALIZED_LEN: usize = 22;
+
+    pub fn p(&self) -> u8 {
+	self.flags.p()
+    }
+
+    /// Serializes the state. The layout is fixed for a given `VERSION`,
+    /// multi-byte values are little endian:
+    ///
+    /// | offset | size | field                                             |
+    /// |--------|------|---------------------------------------------------|
+    /// | 0      | 1    | version                                           |
+    /// | 1      | 2    | pc                                                |
+    /// | 3      | 5    | sp, a, x, y, p                                    |
+    /// | 8      | 8    | cycles                                            |
+    /// | 16     | 2    | stall                                             |
+    /// | 18     | 1    | requested interrupt: 0 none, 1 NMI, 2 IRQ, 3 BRK  |
+    /// | 19     | 1    | bit 0 NMI line, bit 1 IRQ line, bit 2 IRQ inhibit |
+    /// | 20     | 2    | 1 and the last opcode, or 0 and 0                 |
+    pub fn to_bytes(self) -> [u8; Self::SERIALIZED_LEN] {
+	let mut bytes = [0; Self::SERIALIZED_LEN];
+	bytes[0] = Self::VERSION;
+	bytes[1..3].copy_from_slice(&self.pc.to_le_bytes());
+	bytes[3..8].copy_from_slice(&[self.sp, self.a, self.x, self.y, self.p()]);
+	bytes[8..16].copy_from_slice(&(self.cycles as u64).to_le_bytes());
+	bytes[16..18].copy_from_slice(&self.stall.to_le_bytes());
+	bytes[18] = match self.interrupts.requested {
+	    None => 0,
+	    Some(Interrupt::Nmi) => 1,
+	    Some(Interrupt::Irq) => 2,
+	    Some(Interrupt::Brk) => 3,
+	};
+	bytes[19] = self.interrupts.nmi as u8 |
+	    (self.interrupts.irq as u8) << 1 |
+	    (self.interrupts.irq_inhibit as u8) << 2;
+	if let Some(opcode) = self.last_opcode {
+	    bytes[20] = 1;
+	    bytes[21] = opcode;
+	}
+	bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, EmuErr> {
+	if bytes.len() != Self::SERIALIZED_LEN || bytes[0] != Self::VERSION ||
+	    bytes[19] & !0b111 != 0 {
+	    return Err(EmuErr::InvalidCpuState);
+	}
+	let requested = match bytes[18] {
+	    0 => None,
+	    1 => Some(Interrupt::Nmi),
+	    2 => Some(Interrupt::Irq),
+	    3 => Some(Interrupt::Brk),
+	    _ => return Err(EmuErr::InvalidCpuState),
+	};
+	let last_opcode = match bytes[20] {
+	    0 => None,
+	    1 => Some(bytes[21]),
+	    _ => return Err(EmuErr::InvalidCpuState),
+	};
+	let mut cycles = [0; 8];
+	cycles.copy_from_slice(&bytes[8..16]);
+	Ok(Self {
+	    pc: u16::from_le_bytes([bytes[1], bytes[2]]),
+	    sp: bytes[3],
+	    a: bytes[4],
+	    x: bytes[5],
+	    y: bytes[6],
+	    flags: Flags::from_p(bytes[7]),
+	    cycles: u64::from_le_bytes(cycles) as usize,
+	    stall: u16::from_le_bytes([bytes[16], bytes[17]]),
+	    interrupts: PendingInterrupts {
+		requested,
+		nmi: bytes[19] & 1 != 0,
+		irq: bytes[19] & 2 != 0,
+		irq_inhibit: bytes[19] & 4 != 0,
+	    },
+	    last_opcode,
+	})
+    }
+}
+
+impl fmt::Display for CpuState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+	write!(f, "PC:{:04X} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} CYC:{}",
+	       self.pc, self.a, self.x, self.y, self.p(), self.sp, self.cycles)?;
+	if let Some(opcode) = self.last_opcode {
+	    write!(f, " last:{:02X}", opcode)?;
+	}
+	Ok(())
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Interrupt {
     Nmi,
+    Irq,
     Brk,
 }
 
 impl Cpu {
 
+    const NMI_VECTOR: u16 = 0xFFFA;
     const RESET_VECTOR: u16 = 0xFFFC;
+    const IRQ_VECTOR: u16 = 0xFFFE;
     const INITIAL_SP: u8 = 0xFD;
+    const DEFAULT_MAGIC: u8 = 0xEE;
 
-    pub fn new(nmi_signal: Rc<RefCell<bool>>) -> Self {
+    pub fn new() -> Self {
 	Self {
 	    reg_pc: Self::RESET_VECTOR,
 	    reg_a: 0,
@@ -67,76 +303,147 @@ impl Cpu {
 	    flag_n: false,
 	    
 	    reg_sp: Self::INITIAL_SP,
+	    variant: Variant::default(),
 	    cycles: 0,
+	    total_cycles: 0,
+	    access: Access::Read,
+	    cycle_stepped: false,
+	    bus_err: None,
+	    magic: Self::DEFAULT_MAGIC,
+	    breakpoints: HashSet::new(),
+	    resume_pc: None,
+	    halted: None,
 	    interrupt: None,
-	    instruction: None,
-	    nmi_signal,
+	    last_opcode: None,
+	    nmi_seen: false,
+	    irq_seen: false,
+	    irq_inhibit: false,
 	}
     }
 
-    /// Sets power up state
+    /// Sets power up state. Follow with `reset`, which the hardware runs at
+    /// power up too and which leaves SP at 0xFD.
     pub fn power_on(&mut self) {
 	self.reg_a = 0;
 	self.reg_x = 0;
 	self.reg_y = 0;
 	self.reg_p = 0x20;
 	self.split_flags();
-	self.reg_sp = Self::INITIAL_SP;
+	self.reg_sp = Self::INITIAL_SP.wrapping_add(3);
 	self.reg_pc = Self::RESET_VECTOR;
+	self.interrupt = None;
     }
 
     /// 6502 CPU reset
     ///
-    /// - sets pc to 0xFFFC
+    /// - sets pc to the address at 0xFFFC
     /// - sets interrupt disable flag (I)
-    /// - sets initial stack pointer to 0xFD
-    pub fn reset(&mut self, bus: &mut Bus) {
-	self.reg_pc = bus.read_u16(Self::RESET_VECTOR);
+    /// - moves the stack pointer down by 3, A, X and Y are kept
+    /// - stalls for the 7 cycles the reset sequence takes
+    pub fn reset<M: Memory>(&mut self, bus: &mut M) {
+	self.cycles = 0;
+	self.total_cycles = 0;
+	self.interrupt = None;
+	// Reset runs the interrupt sequence with the stack writes turned into reads.
+	self.read(bus, self.reg_pc);
+	self.read(bus, self.reg_pc);
+	for _ in 0..3 {
+	    self.peek_stack(bus);
+	    self.reg_sp = self.reg_sp.wrapping_sub(1);
+	}
+	self.reg_pc = self.read_vector(Self::RESET_VECTOR, bus);
 	// For testrom.nes automated mode (e.g. no graphics implemented yet)
 	// This was quite annoying when I kept seeing 0xc004 read from the reset vector
 	// but other places saying the test should start at 0xc000 :(.
 	//self.reg_pc = 0xc000;
 
-	self.reg_sp = Self::INITIAL_SP;
-	self.reg_a = 0;
-	self.reg_x = 0;
-	self.reg_y = 0;
 	self.flag_i = true;
+	self.irq_inhibit = true;
 	self.join_flags();
-	self.cycles = 0;
+	self.halted = None;
+	self.resume_pc = None;
+	self.finish_cycle_stepped();
     }
 
-    fn join_flags(&mut self) {
-	self.reg_p = 0x20;
-	if self.flag_c { self.reg_p |= 0x01; }
-	if self.flag_z { self.reg_p |= 0x02; }
-	if self.flag_i { self.reg_p |= 0x04; }
-	if self.flag_d { self.reg_p |= 0x08; }
-	if self.flag_v { self.reg_p |= 0x40; }
-	if self.flag_n { self.reg_p |= 0x80; }
+    /// Stops execution before the instruction at `addr` runs.
+    #[allow(dead_code)]
+    pub fn add_breakpoint(&mut self, addr: u16) {
+	self.breakpoints.insert(addr);
     }
 
-    fn split_flags(&mut self) {
-	self.flag_c = self.reg_p & 1 == 1;
-	self.flag_z = (self.reg_p >> 1) & 1 == 1;
-	self.flag_i = (self.reg_p >> 2) & 1 == 1;
-	self.flag_d = (self.reg_p >> 3) & 1 == 1;
-	self.flag_v = (self.reg_p >> 6) & 1 == 1;
-	self.flag_n = (self.reg_p >> 7) & 1 == 1;
+    #[allow(dead_code)]
+    pub fn remove_breakpoint(&mut self, addr: u16) {
+	self.breakpoints.remove(&addr);
+    }
+
+    pub fn registers(&self) -> Registers {
+	Registers {
+	    pc: self.reg_pc,
+	    sp: self.reg_sp,
+	    a: self.reg_a,
+	    x: self.reg_x,
+	    y: self.reg_y,
+	    p: self.flags().p(),
+	}
     }
 
+    fn halt(&mut self, reason: HaltReason, opcode: u8, pc: u16) -> Halt {
+	Halt {
+	    reason,
+	    opcode,
+	    pc,
+	    registers: self.registers(),
+	}
+    }
+
+    /// Switches between running whole instructions on their first cycle
+    /// followed by idle cycles, and cycle stepped execution. When cycle stepped,
+    /// `step` clocks the bus once per cpu cycle while executing an instruction,
+    /// so the bus must not be stepped separately.
     #[allow(dead_code)]
-    pub fn state(&mut self) -> String {
-	self.join_flags();
-	format!("PC:{:X} A:{:X} X:{:X} Y{:X} P:{:X} SP:{:X}, I:{:?}",
-		self.reg_pc,
-		self.reg_a,
-		self.reg_x,
-		self.reg_y,
-		self.reg_p,
-		self.reg_sp,
-		self.instruction,
-	)
+    pub fn set_cycle_stepped(&mut self, cycle_stepped: bool) {
+	self.cycle_stepped = cycle_stepped;
+    }
+
+    pub fn cycle_stepped(&self) -> bool {
+	self.cycle_stepped
+    }
+
+    /// Selects the 6502 family member to emulate. The NES uses the 2A03,
+    /// which is the default.
+    #[allow(dead_code)]
+    pub fn set_variant(&mut self, variant: Variant) {
+	self.variant = variant;
+    }
+
+    #[allow(dead_code)]
+    pub fn variant(&self) -> Variant {
+	self.variant
+    }
+
+    /// Sets the constant used by XAA and LAX #imm. On real hardware it
+    /// depends on the chip and temperature; 0xEE is the common choice,
+    /// 0xFF and 0x00 are also seen.
+    #[allow(dead_code)]
+    pub fn set_magic(&mut self, magic: u8) {
+	self.magic = magic;
+    }
+
+    /// In cycle stepped mode the bus has already been clocked through the
+    /// cycles just counted, so there's nothing left to idle through.
+    fn finish_cycle_stepped(&mut self) {
+	if self.cycle_stepped {
+	    self.total_cycles += self.cycles;
+	    self.cycles = 0;
+	}
+    }
+
+    fn join_flags(&mut self) {
+	self.reg_p = self.flags().p();
+    }
+
+    fn split_flags(&mut self) {
+	self.set_flags(Flags::from_p(self.reg_p));
     }
 
     #[allow(dead_code)]
@@ -150,1022 +457,283 @@ impl Cpu {
     /// - sets interrupt disabled flag (I)
     /// - picks interrupt vector
     /// - sets pc to that vector
-    fn execute_interrupt(&mut self, kind: Interrupt, memory: &mut Bus) {
-	if !matches!(kind, Interrupt::Nmi) && self.flag_i {
-	    return;
+    ///
+    /// NMI uses 0xFFFA, IRQ and BRK share 0xFFFE. BRK is told apart by the B
+    /// flag in the pushed status byte. An NMI asserted while BRK or IRQ is
+    /// pushing hijacks the vector fetch, so the NMI handler runs instead
+    /// (with B still set on the stack for BRK).
+    ///
+    /// The 65C02 also clears the D flag.
+    fn execute_interrupt<M: Memory>(&mut self, kind: Interrupt, memory: &mut M) {
+	// BRK skips the padding byte following the opcode, which was read
+	// as the dummy read of an implied instruction.
+	if matches!(kind, Interrupt::Brk) {
+	    self.reg_pc = self.reg_pc.wrapping_add(1);
 	}
 
 	self.push((self.reg_pc >> 8) as u8, memory);
 	self.push(self.reg_pc as u8, memory);
 	self.join_flags();
-	self.push(self.reg_p, memory);
+	let b_flag = if matches!(kind, Interrupt::Brk) { 0x10 } else { 0 };
+	self.push(self.reg_p | b_flag, memory);
 
 	self.flag_i = true;
+	self.irq_inhibit = true;
+	if self.variant.is_cmos() {
+	    self.flag_d = false;
+	}
 
 	let addr = match kind {
-	    Interrupt::Nmi => 0xFFFE,
-	    Interrupt::Brk => 0xFFFF,
+	    Interrupt::Nmi => Self::NMI_VECTOR,
+	    _ if memory.nmi_pending() => Self::NMI_VECTOR,
+	    Interrupt::Irq | Interrupt::Brk => Self::IRQ_VECTOR,
 	};
+	if addr == Self::NMI_VECTOR {
+	    memory.acknowledge_nmi();
+	}
 
-	let new_pc = memory.read_u16(addr);
+	let new_pc = self.read_vector(addr, memory);
 	self.reg_pc = new_pc;
     }
 
-    pub fn step(&mut self, bus: &mut Bus) -> Result<bool, EmuErr> {
+    fn read_vector<M: Memory>(&mut self, addr: u16, bus: &mut M) -> u16 {
+	let lo = self.read(bus, addr) as u16;
+	let hi = self.read(bus, addr + 1) as u16;
+	(hi << 8) | lo
+    }
+
+    /// Picks the interrupt to service before the next instruction, if any.
+    /// NMI takes priority over IRQ, and IRQ is masked by the I flag.
+    fn poll_interrupts<M: Memory>(&mut self, bus: &M) -> Option<Interrupt> {
+	self.nmi_seen = bus.nmi_pending();
+	self.irq_seen = bus.irq();
+	match self.interrupt {
+	    Some(Interrupt::Irq) if self.irq_inhibit => (),
+	    Some(kind) => {
+		self.interrupt = None;
+		return Some(kind);
+	    },
+	    None => (),
+	}
+
+	if self.nmi_seen {
+	    Some(Interrupt::Nmi)
+	} else if self.irq_seen && !self.irq_inhibit {
+	    Some(Interrupt::Irq)
+	} else {
+	    None
+	}
+    }
+
+    /// Steps the cpu by one cycle, or by a whole instruction when cycle
+    /// stepped. Returns the reason when the cpu stops.
+    pub fn step<M: Memory>(&mut self, bus: &mut M) -> Result<Option<Halt>, EmuErr> {
 	if self.cycles == 0 {
-	    // TODO: interrupt signals could be cleaner / not duplicated?
-	    if let Some(kind) = self.interrupt {
+	    if self.halted.is_some() {
+		return Ok(self.halted);
+	    }
+
+	    if let Some(kind) = self.poll_interrupts(bus) {
+		// The opcode fetch is discarded and the next read is a dummy,
+		// just like an implied instruction.
+		self.read(bus, self.reg_pc);
+		self.read(bus, self.reg_pc);
 		self.execute_interrupt(kind, bus);
+	    } else {
+		let pc = self.reg_pc;
+		if !self.breakpoints.is_empty() && self.breakpoints.contains(&pc) && self.resume_pc != Some(pc) {
+		    self.resume_pc = Some(pc);
+		    // halted, so nothing may see a bus access
+		    let opcode = bus.peek(pc).unwrap_or(0);
+		    return Ok(Some(self.halt(HaltReason::Breakpoint, opcode, pc)));
+		}
+		self.resume_pc = None;
+
+		let opcode: u8 = self.fetch(bus);
+		let lsd: usize = (opcode & 0x0F) as usize;
+		let msd: usize = ((opcode >> 4) & 0xF) as usize;
+		let instruction = &self.variant.opcodes()[msd][lsd];
+		self.last_opcode = Some(opcode);
+		self.access = match instruction.opcode {
+		    // The 65C02 only spends the index carry cycle on shifts
+		    // when a page is crossed, like a read.
+		    Op::ASL | Op::LSR | Op::ROL | Op::ROR if self.variant.is_cmos() => Access::Read,
+		    _ => instruction.access(),
+		};
+		let flag_i = self.flag_i;
+		// Implied and accumulator instructions still read the byte after
+		// the opcode, except for KIL and the single cycle 65C02 NOPs.
+		if matches!(instruction.addr_mode, AM::IMP) && instruction.cycles > 1 {
+		    self.read(bus, self.reg_pc);
+		}
+		let handler = Dispatch::<M>::handler(self.variant, opcode);
+		if let Some(reason) = handler(self, bus) {
+		    self.reg_pc = pc;
+		    self.cycles = 0;
+		    self.halted = Some(self.halt(reason, opcode, pc));
+		    return Ok(self.halted);
+		}
+		self.irq_inhibit = match instruction.opcode {
+		    Op::CLI | Op::SEI | Op::PLP => flag_i,
+		    _ => self.flag_i,
+		};
 	    }
 
-	    if *self.nmi_signal.borrow() {
-		self.execute_interrupt(Interrupt::Nmi, bus);
+	    // DMA started by the instruction holds the cpu off the bus
+	    while let Some(dma) = bus.take_dma() {
+		let mut left = dma.cycles(self.total_cycles + self.cycles, 0);
+		while left > 0 {
+		    self.tick(bus);
+		    left -= 1;
+		    // a DMC fetch during an OAM DMA shares its halt
+		    if dma == Dma::Oam && left > 0 {
+			if let Some(dmc) = bus.take_dma() {
+			    left += dmc.cycles(self.total_cycles + self.cycles, left);
+			}
+		    }
+		}
 	    }
 
-	    let opcode: u8 = bus.read(post_inc!(self.reg_pc));
-	    let lsd: usize = (opcode & 0x0F) as usize;
-	    let msd: usize = ((opcode >> 4) & 0xF) as usize;
-	    let instruction = &OPCODES[msd][lsd];
-	    self.instruction = Some(*instruction);
-	    self.cycles += instruction.cycles as usize;
-	    if self.execute(*instruction, bus)? {
-		return Ok(true);
+	    if self.cycle_stepped {
+		self.finish_cycle_stepped();
+		return match self.bus_err.take() {
+		    Some(e) => Err(e),
+		    None => Ok(None),
+		};
 	    }
 	}
 
 	self.cycles -= 1;
-	Ok(false)
+	self.total_cycles += 1;
+	Ok(None)
     }
 
-    fn execute(&mut self, instruction: I, bus: &mut Bus) -> Result<bool, EmuErr> {
-	match instruction {
-	    /* logical and arithmetic instructions */
-
-	    // ORA
-	    I{ opcode: Op::ORA, addr_mode: AM::IMM, ..} => {
-		let location = post_inc!(self.reg_pc);
-		self.ora(location, bus);
-	    },
-	    I{ opcode: Op::ORA, addr_mode: AM::ZPG, ..} => {
-		let location = self.zero_page(bus);
-		self.ora(location, bus);
-	    },
-	    I{ opcode: Op::ORA, addr_mode: AM::ZPX, ..} => {
-		let location = self.zero_page_x(bus);
-		self.ora(location, bus);
-	    },
-	    I{ opcode: Op::ORA, addr_mode: AM::INX, ..} => {
-		let location = self.indexed_indirect(bus);
-		self.ora(location, bus);
-	    },
-	    I{ opcode: Op::ORA, addr_mode: AM::INY, ..} => {
-		let location = self.indirect_indexed(bus);
-		self.ora(location, bus);
-	    },
-	    I{ opcode: Op::ORA, addr_mode: AM::ABS, ..} => {
-		let location = self.absolute(bus);
-		self.ora(location, bus);
-	    },
-	    I{ opcode: Op::ORA, addr_mode: AM::ABX, ..} => {
-		let location = self.absolute_x(bus);
-		self.ora(location, bus);
-	    },
-	    I{ opcode: Op::ORA, addr_mode: AM::ABY, ..} => {
-		let location = self.absolute_y(bus);
-		self.ora(location, bus);
-	    },
-
-	    // AND
-	    I{ opcode: Op::AND, addr_mode: AM::IMM, ..} => {
-		let location = post_inc!(self.reg_pc);
-		self.and(location, bus);
-	    },
-	    I{ opcode: Op::AND, addr_mode: AM::ZPG, ..} => {
-		let location = self.zero_page(bus);
-		self.and(location, bus);
-	    },
-	    I{ opcode: Op::AND, addr_mode: AM::ZPX, ..} => {
-		let location = self.zero_page_x(bus);
-		self.and(location, bus);
-	    },
-	    I{ opcode: Op::AND, addr_mode: AM::INX, ..} => {
-		let location = self.indexed_indirect(bus);
-		self.and(location, bus);
-	    },
-	    I{ opcode: Op::AND, addr_mode: AM::INY, ..} => {
-		let location = self.indirect_indexed(bus);
-		self.and(location, bus);
-	    },
-	    I{ opcode: Op::AND, addr_mode: AM::ABS, ..} => {
-		let location = self.absolute(bus);
-		self.and(location, bus);
-	    },
-	    I{ opcode: Op::AND, addr_mode: AM::ABX, ..} => {
-		let location = self.absolute_x(bus);
-		self.and(location, bus);
-	    },
-	    I{ opcode: Op::AND, addr_mode: AM::ABY, ..} => {
-		let location = self.absolute_y(bus);
-		self.and(location, bus);
-	    },
-
-	    // EOR
-	    I{ opcode: Op::EOR, addr_mode: AM::IMM, ..} => {
-		let location = post_inc!(self.reg_pc);
-		self.eor(location, bus);
-	    },
-	    I{ opcode: Op::EOR, addr_mode: AM::ZPG, ..} => {
-		let location = self.zero_page(bus);
-		self.eor(location, bus);
-	    },
-	    I{ opcode: Op::EOR, addr_mode: AM::ZPX, ..} => {
-		let location = self.zero_page_x(bus);
-		self.eor(location, bus);
-	    },
-	    I{ opcode: Op::EOR, addr_mode: AM::INX, ..} => {
-		let location = self.indexed_indirect(bus);
-		self.eor(location, bus);
-	    },
-	    I{ opcode: Op::EOR, addr_mode: AM::INY, ..} => {
-		let location = self.indirect_indexed(bus);
-		self.eor(location, bus);
-	    },
-	    I{ opcode: Op::EOR, addr_mode: AM::ABS, ..} => {
-		let location = self.absolute(bus);
-		self.eor(location, bus);
-	    },
-	    I{ opcode: Op::EOR, addr_mode: AM::ABX, ..} => {
-		let location = self.absolute_x(bus);
-		self.eor(location, bus);
-	    },
-	    I{ opcode: Op::EOR, addr_mode: AM::ABY, ..} => {
-		let location = self.absolute_y(bus);
-		self.eor(location, bus);
-	    },
-
-	    // ADC
-	    I{ opcode: Op::ADC, addr_mode: AM::IMM, ..} => {
-		let location = post_inc!(self.reg_pc);
-		self.adc(location, bus);
-	    },
-	    I{ opcode: Op::ADC, addr_mode: AM::ZPG, ..} => {
-		let location = self.zero_page(bus);
-		self.adc(location, bus);
-	    },
-	    I{ opcode: Op::ADC, addr_mode: AM::ZPX, ..} => {
-		let location = self.zero_page_x(bus);
-		self.adc(location, bus);
-	    },
-	    I{ opcode: Op::ADC, addr_mode: AM::INX, ..} => {
-		let location = self.indexed_indirect(bus);
-		self.adc(location, bus);
-	    },
-	    I{ opcode: Op::ADC, addr_mode: AM::INY, ..} => {
-		let location = self.indirect_indexed(bus);
-		self.adc(location, bus);
-	    },
-	    I{ opcode: Op::ADC, addr_mode: AM::ABS, ..} => {
-		let location = self.absolute(bus);
-		self.adc(location, bus);
-	    },
-	    I{ opcode: Op::ADC, addr_mode: AM::ABX, ..} => {
-		let location = self.absolute_x(bus);
-		self.adc(location, bus);
-	    },
-	    I{ opcode: Op::ADC, addr_mode: AM::ABY, ..} => {
-		let location = self.absolute_y(bus);
-		self.adc(location, bus);
-	    },
-
-	    // SBC
-	    I{ opcode: Op::SBC, addr_mode: AM::IMM, ..} => {
-		let location = post_inc!(self.reg_pc);
-		self.sbc(location, bus);
-	    },
-	    I{ opcode: Op::SBC, addr_mode: AM::ZPG, ..} => {
-		let location = self.zero_page(bus);
-		self.sbc(location, bus);
-	    },
-	    I{ opcode: Op::SBC, addr_mode: AM::ZPX, ..} => {
-		let location = self.zero_page_x(bus);
-		self.sbc(location, bus);
-	    },
-	    I{ opcode: Op::SBC, addr_mode: AM::INX, ..} => {
-		let location = self.indexed_indirect(bus);
-		self.sbc(location, bus);
-	    },
-	    I{ opcode: Op::SBC, addr_mode: AM::INY, ..} => {
-		let location = self.indirect_indexed(bus);
-		self.sbc(location, bus);
-	    },
-	    I{ opcode: Op::SBC, addr_mode: AM::ABS, ..} => {
-		let location = self.absolute(bus);
-		self.sbc(location, bus);
-	    },
-	    I{ opcode: Op::SBC, addr_mode: AM::ABX, ..} => {
-		let location = self.absolute_x(bus);
-		self.sbc(location, bus)
-	    },
-	    I{ opcode: Op::SBC, addr_mode: AM::ABY, ..} => {
-		let location = self.absolute_y(bus);
-		self.sbc(location, bus);
-	    },
-
-	    // CMP
-	    I{ opcode: Op::CMP, addr_mode: AM::IMM, ..} => {
-		let location = post_inc!(self.reg_pc);
-		self.cmp(self.reg_a, bus.read(location));
-	    },
-	    I{ opcode: Op::CMP, addr_mode: AM::ZPG, ..} => {
-		let location = self.zero_page(bus);
-		self.cmp(self.reg_a, bus.read(location));
-	    },
-	    I{ opcode: Op::CMP, addr_mode: AM::ZPX, ..} => {
-		let location = self.zero_page_x(bus);
-		self.cmp(self.reg_a, bus.read(location));
-	    },
-	    I{ opcode: Op::CMP, addr_mode: AM::INX, ..} => {
-		let location = self.indexed_indirect(bus);
-		self.cmp(self.reg_a, bus.read(location));
-	    },
-	    I{ opcode: Op::CMP, addr_mode: AM::INY, ..} => {
-		let location = self.indirect_indexed(bus);
-		self.cmp(self.reg_a, bus.read(location));
-	    },
-	    I{ opcode: Op::CMP, addr_mode: AM::ABS, ..} => {
-		let location = self.absolute(bus);
-		self.cmp(self.reg_a, bus.read(location));
-	    },
-	    I{ opcode: Op::CMP, addr_mode: AM::ABX, ..} => {
-		let location = self.absolute_x(bus);
-		self.cmp(self.reg_a, bus.read(location));
-	    },
-	    I{ opcode: Op::CMP, addr_mode: AM::ABY, ..} => {
-		let location = self.absolute_y(bus);
-		self.cmp(self.reg_a, bus.read(location));
-	    },
-
-	    // CPX
-	    I{ opcode: Op::CPX, addr_mode: AM::IMM, ..} => {
-		let location = post_inc!(self.reg_pc);
-		self.cmp(self.reg_x, bus.read(location));
-	    },
-	    I{ opcode: Op::CPX, addr_mode: AM::ZPG, ..} => {
-		let location = self.zero_page(bus);
-		self.cmp(self.reg_x, bus.read(location));
-	    },
-	    I{ opcode: Op::CPX, addr_mode: AM::ABS, ..} => {
-		let location = self.absolute(bus);
-		self.cmp(self.reg_x, bus.read(location));
-	    },
-
-	    // CPY
-	    I{ opcode: Op::CPY, addr_mode: AM::IMM, ..} => {
-		let location = post_inc!(self.reg_pc);
-		self.cmp(self.reg_y, bus.read(location));
-	    },
-	    I{ opcode: Op::CPY, addr_mode: AM::ZPG, ..} => {
-		let location = self.zero_page(bus);
-		self.cmp(self.reg_y, bus.read(location));
-	    },
-	    I{ opcode: Op::CPY, addr_mode: AM::ABS, ..} => {
-		let location = self.absolute(bus);
-		self.cmp(self.reg_y, bus.read(location));
-	    },
-
-	    // DEC
-	    I{ opcode: Op::DEC, addr_mode: AM::ZPG, ..} => {
-		let location = self.zero_page(bus);
-		self.dec(location, bus);
-	    },
-	    I{ opcode: Op::DEC, addr_mode: AM::ZPX, ..} => {
-		let location = self.zero_page_x(bus);
-		self.dec(location, bus);
-	    },
-	    I{ opcode: Op::DEC, addr_mode: AM::ABS, ..} => {
-		let location = self.absolute(bus);
-		self.dec(location, bus);
-	    },
-	    I{ opcode: Op::DEC, addr_mode: AM::ABX, ..} => {
-		let location = self.absolute_x(bus);
-		self.dec(location, bus);
-	    },
-
-	    // DEX
-	    I{ opcode: Op::DEX, addr_mode: AM::IMP, ..} => {
-		self.reg_x = self.reg_x.wrapping_sub(1);
-		self.set_zn(self.reg_x);
-	    },
-
-	    // DEY
-	    I{ opcode: Op::DEY, addr_mode: AM::IMP, ..} => {
-		self.reg_y = self.reg_y.wrapping_sub(1);
-		self.set_zn(self.reg_y);
-	    },
-
-	    // INC
-	    I{ opcode: Op::INC, addr_mode: AM::ZPG, ..} => {
-		let location = self.zero_page(bus);
-		self.inc(location, bus);
-	    },
-	    I{ opcode: Op::INC, addr_mode: AM::ZPX, ..} => {
-		let location = self.zero_page_x(bus);
-		self.inc(location, bus);
-	    },
-	    I{ opcode: Op::INC, addr_mode: AM::ABS, ..} => {
-		let location = self.absolute(bus);
-		self.inc(location, bus);
-	    },
-	    I{ opcode: Op::INC, addr_mode: AM::ABX, ..} => {
-		let location = self.absolute_x(bus);
-		self.inc(location, bus);
-	    },
-
-	    // INX
-	    I{ opcode: Op::INX, addr_mode: AM::IMP, ..} => {
-		self.reg_x = self.reg_x.wrapping_add(1);
-		self.set_zn(self.reg_x);
-	    },
-
-	    // INY
-	    I{ opcode: Op::INY, addr_mode: AM::IMP, ..} => {
-		self.reg_y = self.reg_y.wrapping_add(1);
-		self.set_zn(self.reg_y);
-	    },
-
-	    // ASL
-	    I{ opcode: Op::ASL, addr_mode: AM::IMP, ..} => {
-		self.asl_acc();
-	    },
-	    I{ opcode: Op::ASL, addr_mode: AM::ZPG, ..} => {
-		let location = self.zero_page(bus);
-		self.asl(location, bus);
-	    },
-	    I{ opcode: Op::ASL, addr_mode: AM::ZPX, ..} => {
-		let location = self.zero_page_x(bus);
-		self.asl(location, bus);
-	    },
-	    I{ opcode: Op::ASL, addr_mode: AM::ABS, ..} => {
-		let location = self.absolute(bus);
-		self.asl(location, bus);
-	    },
-	    I{ opcode: Op::ASL, addr_mode: AM::ABX, ..} => {
-		let location = self.absolute_x(bus);
-		self.asl(location, bus);
-	    },
-
-	    // ROL
-	    I{ opcode: Op::ROL, addr_mode: AM::IMP, ..} => self.rol_acc(),
-	    I{ opcode: Op::ROL, addr_mode: AM::ZPG, ..} => {
-		let location = self.zero_page(bus);
-		self.rol(location, bus);
-	    },
-	    I{ opcode: Op::ROL, addr_mode: AM::ZPX, ..} => {
-		let location = self.zero_page_x(bus);
-		self.rol(location, bus);
-	    },
-	    I{ opcode: Op::ROL, addr_mode: AM::ABS, ..} => {
-		let location = self.absolute(bus);
-		self.rol(location, bus);
-	    },
-	    I{ opcode: Op::ROL, addr_mode: AM::ABX, ..} => {
-		let location = self.absolute_x(bus);
-		self.rol(location, bus);
-	    },
-
-	    // LSR
-	    I{ opcode: Op::LSR, addr_mode: AM::IMP, ..} => self.lsr_acc(),
-	    I{ opcode: Op::LSR, addr_mode: AM::ZPG, ..} => {
-		let location = self.zero_page(bus);
-		self.lsr(location, bus);
-	    },
-	    I{ opcode: Op::LSR, addr_mode: AM::ZPX, ..} => {
-		let location = self.zero_page_x(bus);
-		self.lsr(location, bus);
-	    },
-	    I{ opcode: Op::LSR, addr_mode: AM::ABS, ..} => {
-		let location = self.absolute(bus);
-		self.lsr(location, bus);
-	    },
-	    I{ opcode: Op::LSR, addr_mode: AM::ABX, ..} => {
-		let location = self.absolute_x(bus);
-		self.lsr(location, bus);
-	    },
-
-	    // ROR
-	    I{ opcode: Op::ROR, addr_mode: AM::IMP, ..} => self.ror_acc(),
-	    I{ opcode: Op::ROR, addr_mode: AM::ZPG, ..} => {
-		let location = self.zero_page(bus);
-		self.ror(location, bus);
-	    },
-	    I{ opcode: Op::ROR, addr_mode: AM::ZPX, ..} => {
-		let location = self.zero_page_x(bus);
-		self.ror(location, bus);
-	    },
-	    I{ opcode: Op::ROR, addr_mode: AM::ABS, ..} => {
-		let location = self.absolute(bus);
-		self.ror(location, bus);
-	    },
-	    I{ opcode: Op::ROR, addr_mode: AM::ABX, ..} => {
-		let location = self.absolute_x(bus);
-		self.ror(location, bus);
-	    },
-
-	    /* move instructions */
-
-	    // LDA
-	    I{ opcode: Op::LDA, addr_mode: AM::IMM, ..} => {
-		let location = post_inc!(self.reg_pc);
-		self.lda(location, bus);
-	    },
-	    I{ opcode: Op::LDA, addr_mode: AM::ZPG, ..} => {
-		let location = self.zero_page(bus);
-		self.lda(location, bus);
-	    },
-	    I{ opcode: Op::LDA, addr_mode: AM::ZPX, ..} => {
-		let location = self.zero_page_x(bus);
-		self.lda(location, bus);
-	    },
-	    I{ opcode: Op::LDA, addr_mode: AM::INX, ..} => {
-		let location = self.indexed_indirect(bus);
-		self.lda(location, bus);
-	    },
-	    I{ opcode: Op::LDA, addr_mode: AM::INY, ..} => {
-		let location = self.indirect_indexed(bus);
-		self.lda(location, bus);
-	    },
-	    I{ opcode: Op::LDA, addr_mode: AM::ABS, ..} => {
-		let location = self.absolute(bus);
-		self.lda(location, bus);
-	    },
-	    I{ opcode: Op::LDA, addr_mode: AM::ABX, ..} => {
-		let location = self.absolute_x(bus);
-		self.lda(location, bus);
-	    },
-	    I{ opcode: Op::LDA, addr_mode: AM::ABY, ..} => {
-		let location = self.absolute_y(bus);
-		self.lda(location, bus);
-	    },
-
-	    // STA
-	    I{ opcode: Op::STA, addr_mode: AM::ZPG, ..} => {
-		let location = self.zero_page(bus);
-		self.sta(location, bus);
-	    },
-	    I{ opcode: Op::STA, addr_mode: AM::ZPX, ..} => {
-		let location = self.zero_page_x(bus);
-		self.sta(location, bus);
-	    },
-	    I{ opcode: Op::STA, addr_mode: AM::INX, ..} => {
-		let location = self.indexed_indirect(bus);
-		self.sta(location, bus);
-	    },
-	    I{ opcode: Op::STA, addr_mode: AM::INY, ..} => {
-		let location = self.indirect_indexed(bus);
-		self.sta(location, bus);
-	    },
-	    I{ opcode: Op::STA, addr_mode: AM::ABS, ..} => {
-		let location = self.absolute(bus);
-		self.sta(location, bus);
-	    },
-	    I{ opcode: Op::STA, addr_mode: AM::ABX, ..} => {
-		let location = self.absolute_x(bus);
-		self.sta(location, bus);
-	    },
-	    I{ opcode: Op::STA, addr_mode: AM::ABY, ..} => {
-		let location = self.absolute_y(bus);
-		self.sta(location, bus);
-	    },
-
-	    // LDX
-	    I{ opcode: Op::LDX, addr_mode: AM::IMM, ..} => {
-		let location = post_inc!(self.reg_pc);
-		self.ldx(location, bus);
-	    },
-	    I{ opcode: Op::LDX, addr_mode: AM::ZPG, ..} => {
-		let location = self.zero_page(bus);
-		self.ldx(location, bus);
-	    },
-	    I{ opcode: Op::LDX, addr_mode: AM::ZPY, ..} => {
-		let location = self.zero_page_y(bus);
-		self.ldx(location, bus);
-	    },
-	    I{ opcode: Op::LDX, addr_mode: AM::ABS, ..} => {
-		let location = self.absolute(bus);
-		self.ldx(location, bus);
-	    },
-	    I{ opcode: Op::LDX, addr_mode: AM::ABY, ..} => {
-		let location = self.absolute_y(bus);
-		self.ldx(location, bus);
-	    },
-
-	    // STX
-	    I{ opcode: Op::STX, addr_mode: AM::ZPG, ..} => {
-		let location = self.zero_page(bus);
-		bus.write(location, self.reg_x);
-	    },
-	    I{ opcode: Op::STX, addr_mode: AM::ZPY, ..} => {
-		let location = self.zero_page_y(bus);
-		bus.write(location, self.reg_x);
-	    },
-	    I{ opcode: Op::STX, addr_mode: AM::ABS, ..} => {
-		let location = self.absolute(bus);
-		bus.write(location, self.reg_x);
-	    },
-
-	    // LDY
-	    I{ opcode: Op::LDY, addr_mode: AM::IMM, ..} => {
-		let location = post_inc!(self.reg_pc);
-		self.ldy(location, bus);
-	    },
-	    I{ opcode: Op::LDY, addr_mode: AM::ZPG, ..} => {
-		let location = self.zero_page(bus);
-		self.ldy(location, bus);
-	    },
-	    I{ opcode: Op::LDY, addr_mode: AM::ZPX, ..} => {
-		let location = self.zero_page_x(bus);
-		self.ldy(location, bus);
-	    },
-	    I{ opcode: Op::LDY, addr_mode: AM::ABS, ..} => {
-		let location = self.absolute(bus);
-		self.ldy(location, bus);
-	    },
-	    I{ opcode: Op::LDY, addr_mode: AM::ABX, ..} => {
-		let location = self.absolute_x(bus);
-		self.ldy(location, bus);
-	    },
-
-	    // STY
-	    I{ opcode: Op::STY, addr_mode: AM::ZPG, ..} => {
-		let location = self.zero_page(bus);
-		bus.write(location, self.reg_y);
-	    },
-	    I{ opcode: Op::STY, addr_mode: AM::ZPX, ..} => {
-		let location = self.zero_page_x(bus);
-		bus.write(location, self.reg_y);
-	    },
-	    I{ opcode: Op::STY, addr_mode: AM::ABS, ..} => {
-		let location = self.absolute(bus);
-		bus.write(location, self.reg_y);
-	    },
-
-	    // TAX
-	    I{ opcode: Op::TAX, addr_mode: AM::IMP, ..} => {
-		self.reg_x = self.reg_a;
-		self.set_zn(self.reg_x);
-	    },
-
-	    // TXA
-	    I{ opcode: Op::TXA, addr_mode: AM::IMP, ..} => {
-		self.reg_a = self.reg_x;
-		self.set_zn(self.reg_a);
-	    },
-
-	    // TAY
-	    I{ opcode: Op::TAY, addr_mode: AM::IMP, ..} => {
-		self.reg_y = self.reg_a;
-		self.set_zn(self.reg_y);
-	    },
-
-	    // TYA
-	    I{ opcode: Op::TYA, addr_mode: AM::IMP, ..} => {
-		self.reg_a = self.reg_y;
-		self.set_zn(self.reg_a);
-	    },
-
-	    // TSX
-	    I{ opcode: Op::TSX, addr_mode: AM::IMP, ..} => {
-		self.reg_x = self.reg_sp;
-		self.set_zn(self.reg_x);
-	    },
-
-	    // TXS
-	    I{ opcode: Op::TXS, addr_mode: AM::IMP, ..} => self.reg_sp = self.reg_x,
-
-	    // PLA
-	    I{ opcode: Op::PLA, addr_mode: AM::IMP, ..} => {
-		self.reg_a = self.pull(bus);
-		self.set_zn(self.reg_a);
-	    },
-
-	    // PHA
-	    I{ opcode: Op::PHA, addr_mode: AM::IMP, ..} => self.push(self.reg_a, bus),
-
-	    // See: https://www.nesdev.org/wiki/Status_flags#The_B_flag
-	    // for PLP / PHP details.
-
-	    // PLP
-	    I{ opcode: Op::PLP, addr_mode: AM::IMP, ..} => {
-		self.reg_p = self.pull(bus);
-		self.split_flags();
-	    },
-
-	    // PHP
-	    I{ opcode: Op::PHP, addr_mode: AM::IMP, ..} => {
-		self.join_flags();
-		self.push(self.reg_p | 0x10, bus);
-	    },
-
-	    /* jump/flag instructions */
-
-	    // BPL
-	    I{ opcode: Op::BPL, addr_mode: AM::REL, ..} => self.execute_cond_branch(!self.flag_n, bus),
-
-	    // BMI
-	    I{ opcode: Op::BMI, addr_mode: AM::REL, ..} => self.execute_cond_branch(self.flag_n, bus),
-
-	    // BVC
-	    I{ opcode: Op::BVC, addr_mode: AM::REL, ..} => self.execute_cond_branch(!self.flag_v, bus),
-
-	    // BVS
-	    I{ opcode: Op::BVS, addr_mode: AM::REL, ..} => self.execute_cond_branch(self.flag_v, bus),
-	    I{ opcode: Op::BCC, addr_mode: AM::REL, ..} => self.execute_cond_branch(!self.flag_c, bus),
-
-	    // BCS
-	    I{ opcode: Op::BCS, addr_mode: AM::REL, ..} => self.execute_cond_branch(self.flag_c, bus),
-
-	    // BNE
-	    I{ opcode: Op::BNE, addr_mode: AM::REL, ..} => self.execute_cond_branch(!self.flag_z, bus),
-
-	    // BEQ
-	    I{ opcode: Op::BEQ, addr_mode: AM::REL, ..} => self.execute_cond_branch(self.flag_z, bus),
-	    
-	    // BRK
-	    I{ opcode: Op::BRK, addr_mode: AM::IMP, ..} => self.execute_interrupt(Interrupt::Brk, bus),
-
-	    // RTI
-	    I{ opcode: Op::RTI, addr_mode: AM::IMP, ..} => {
-		self.reg_p = self.pull(bus);
-		self.split_flags();
-		let pc_lo = self.pull(bus) as u16;
-		let pc_hi = self.pull(bus) as u16;
-		self.reg_pc = pc_hi << 8 | pc_lo;
-	    },
-
-	    // JSR
-	    I{ opcode: Op::JSR, addr_mode: AM::ABS, ..} => {
-		self.push(((self.reg_pc + 1) >> 8) as u8, bus);
-		self.push((self.reg_pc + 1) as u8, bus);
-		self.reg_pc = bus.read_u16(self.reg_pc);
-		// Don't need to increment pc.
-	    },
-
-	    // RTS
-	    I{ opcode: Op::RTS, addr_mode: AM::IMP, ..} => {
-		let pc_lo = self.pull(bus) as u16;
-		let pc_hi = self.pull(bus) as u16;
-		self.reg_pc = (pc_hi << 8) | pc_lo;
-		self.reg_pc += 1;
-	    },
-
-	    // JMP
-	    I{ opcode: Op::JMP, addr_mode: AM::ABS, ..} => {
-		let location = self.absolute(bus);
-		self.reg_pc = location;
-	    },
-	    I{ opcode: Op::JMP, addr_mode: AM::IND, ..} => {
-		/*
-		Quoted from: https://www.nesdev.org/obelisk-6502-guide/reference.html#INX
-		"""
-		NB:
-		An original 6502 has does not correctly fetch the target address if
-		the indirect vector falls on a page boundary (e.g. $xxFF where xx is
-		any value from $00 to $FF). In this case fetches the LSB from $xxFF
-		as expected but takes the MSB from $xx00. This is fixed in some later
-		chips like the 65SC02 so for compatibility always ensure the indirect
-		vector is not at the end of the page.
-		"""
-		 */
-		let location = self.absolute(bus);
-		let page = location & 0xff00;
-		self.reg_pc = bus.read(location) as u16 | ((bus.read(page | (location + 1) & 0xff) as u16) << 8);
-	    },
-
-	    // BIT
-	    I{ opcode: Op::BIT, addr_mode: AM::ZPG, ..} => {
-		let location = self.zero_page(bus);
-		self.bit(location, bus);
-	    },
-	    I{ opcode: Op::BIT, addr_mode: AM::ABS, ..} => {
-		let location = self.absolute(bus);
-		self.bit(location, bus);
-	    },
-
-	    // CLC
-	    I{ opcode: Op::CLC, addr_mode: AM::IMP, ..} => self.flag_c = false,
-
-	    // SEC
-	    I{ opcode: Op::SEC, addr_mode: AM::IMP, ..} => self.flag_c = true,
-
-	    /*
-	    NOTE: CLD & SED have no actual affect. The decimal flag has no effect on the NES
-	    and accordingly also does nothing in this emulator. The flags are still changed in P
-	    to pass the cpu test rom checks.
-	     */
-
-	    // CLD
-	    I{ opcode: Op::CLD, addr_mode: AM::IMP, ..} => self.flag_d = false, 
-	    // SED
-	    I{ opcode: Op::SED, addr_mode: AM::IMP, ..} => self.flag_d = true, 
-
-	    // CLI
-	    I{ opcode: Op::CLI, addr_mode: AM::IMP, ..} => self.flag_i = false,
-
-	    // SEI
-	    I{ opcode: Op::SEI, addr_mode: AM::IMP, ..} => self.flag_i = true,
-
-	    // CLV
-	    I{ opcode: Op::CLV, addr_mode: AM::IMP, ..} => self.flag_v = false,
-
-	    // NOP
-	    I{ opcode: Op::NOP, addr_mode: AM::IMP, ..} => {},
-	    I{ opcode: Op::NOP, addr_mode: AM::IMM, ..} => {let _ = post_inc!(self.reg_pc);},
-	    I{ opcode: Op::NOP, addr_mode: AM::ZPG, ..} => {
-		let _ = self.zero_page(bus);
-	    },
-	    I{ opcode: Op::NOP, addr_mode: AM::ZPX, ..} => {
-		let _ = self.zero_page_x(bus);
-	    },
-	    I{ opcode: Op::NOP, addr_mode: AM::ABS, ..} => {
-		let _ = self.absolute(bus);
-	    },
-	    I{ opcode: Op::NOP, addr_mode: AM::ABX, ..} => {
-		let _ = self.absolute_x(bus);
-	    },
-
-	    // LAX
-	    I{ opcode: Op::LAX, addr_mode: AM::IMM, ..} => {
-		let location = post_inc!(self.reg_pc);
-		self.lax(location, bus);
-	    },
-	    I{ opcode: Op::LAX, addr_mode: AM::INX, ..} => {
-		let location = self.indexed_indirect(bus);
-		self.lax(location, bus);
-	    },
-	    I{ opcode: Op::LAX, addr_mode: AM::ZPG, ..} => {
-		let location = self.zero_page(bus);
-		self.lax(location, bus);
-	    },
-	    I{ opcode: Op::LAX, addr_mode: AM::ABS, ..} => {
-		let location = self.absolute(bus);
-		self.lax(location, bus);
-	    },
-	    I{ opcode: Op::LAX, addr_mode: AM::INY, ..} => {
-		let location = self.indirect_indexed(bus);
-		self.lax(location, bus);
-	    },
-	    I{ opcode: Op::LAX, addr_mode: AM::ZPY, ..} => {
-		let location = self.zero_page_y(bus);
-		self.lax(location, bus);
-	    },
-	    I{ opcode: Op::LAX, addr_mode: AM::ABY, ..} => {
-		let location = self.absolute_y(bus);
-		self.lax(location, bus);
-	    },
-
-	    // SAX
-	    I{ opcode: Op::SAX, addr_mode: AM::INX, ..} => {
-		let location = self.indexed_indirect(bus);
-		self.sax(location, bus);
-	    },
-	    I{ opcode: Op::SAX, addr_mode: AM::ZPG, ..} => {
-		let location = self.zero_page(bus);
-		self.sax(location, bus);
-	    },
-	    I{ opcode: Op::SAX, addr_mode: AM::ZPY, ..} => {
-		let location = self.zero_page_y(bus);
-		self.sax(location, bus);
-	    },
-	    I{ opcode: Op::SAX, addr_mode: AM::ABS, ..} => {
-		let location = self.absolute(bus);
-		self.sax(location, bus);
-	    },
+    fn ora<M: Memory>(&mut self, location: u16, bus: &mut M) {
+	self.reg_a |= self.read(bus, location);
+	self.set_zn(self.reg_a);
+    }
 
-	    // DCP
-	    I { opcode: Op::DCP, addr_mode: AM::ZPG, ..} => {
-		let location = self.zero_page(bus);
-		self.dcp(location, bus);
-	    },
-	    I { opcode: Op::DCP, addr_mode: AM::ZPX, ..} => {
-		let location = self.zero_page_x(bus);
-		self.dcp(location, bus);
-	    },
-	    I { opcode: Op::DCP, addr_mode: AM::INX, ..} => {
-		let location = self.indexed_indirect(bus);
-		self.dcp(location, bus);
-	    },
-	    I { opcode: Op::DCP, addr_mode: AM::INY, ..} => {
-		let location = self.indirect_indexed(bus);
-		self.dcp(location, bus);
-	    },
-	    I { opcode: Op::DCP, addr_mode: AM::ABS, ..} => {
-		let location = self.absolute(bus);
-		self.dcp(location, bus);
-	    },
-	    I { opcode: Op::DCP, addr_mode: AM::ABX, ..} => {
-		let location = self.absolute_x(bus);
-		self.dcp(location, bus);
-	    },
-	    I { opcode: Op::DCP, addr_mode: AM::ABY, ..} => {
-		let location = self.absolute_y(bus);
-		self.dcp(location, bus);
-	    },
+    fn and<M: Memory>(&mut self, location: u16, bus: &mut M) {
+	self.reg_a &= self.read(bus, location);
+	self.set_zn(self.reg_a);
+    }
 
-	    // ISC
-	    I{ opcode: Op::ISC, addr_mode: AM::ZPG, ..} => {
-		let location = self.zero_page(bus);
-		self.isc(location, bus);
-	    },
-	    I{ opcode: Op::ISC, addr_mode: AM::ZPX, ..} => {
-		let location = self.zero_page_x(bus);
-		self.isc(location, bus);
-	    },
-	    I{ opcode: Op::ISC, addr_mode: AM::INX, ..} => {
-		let location = self.indexed_indirect(bus);
-		self.isc(location, bus);
-	    },
-	    I{ opcode: Op::ISC, addr_mode: AM::INY, ..} => {
-		let location = self.indirect_indexed(bus);
-		self.isc(location, bus);
-	    },
-	    I{ opcode: Op::ISC, addr_mode: AM::ABS, ..} => {
-		let location = self.absolute(bus);
-		self.isc(location, bus);
-	    },
-	    I{ opcode: Op::ISC, addr_mode: AM::ABX, ..} => {
-		let location = self.absolute_x(bus);
-		self.isc(location, bus);
-	    },
-	    I{ opcode: Op::ISC, addr_mode: AM::ABY, ..} => {
-		let location = self.absolute_y(bus);
-		self.isc(location, bus);
-	    },
+    fn eor<M: Memory>(&mut self, location: u16, bus: &mut M) {
+	self.reg_a ^= self.read(bus, location);
+	self.set_zn(self.reg_a);
+    }
 
-	    // SLO
-	    I{ opcode: Op::SLO, addr_mode: AM::ZPG, ..} => {
-		let location = self.zero_page(bus);
-		self.slo(location, bus);
-	    },
-	    I{ opcode: Op::SLO, addr_mode: AM::ZPX, ..} => {
-		let location = self.zero_page_x(bus);
-		self.slo(location, bus);
-	    },
-	    I{ opcode: Op::SLO, addr_mode: AM::INX, ..} => {
-		let location = self.indexed_indirect(bus);
-		self.slo(location, bus);
-	    },
-	    I{ opcode: Op::SLO, addr_mode: AM::INY, ..} => {
-		let location = self.indirect_indexed(bus);
-		self.slo(location, bus);
-	    },
-	    I{ opcode: Op::SLO, addr_mode: AM::ABS, ..} => {
-		let location = self.absolute(bus);
-		self.slo(location, bus);
-	    },
-	    I{ opcode: Op::SLO, addr_mode: AM::ABX, ..} => {
-		let location = self.absolute_x(bus);
-		self.slo(location, bus);
-	    },
-	    I{ opcode: Op::SLO, addr_mode: AM::ABY, ..} => {
-		let location = self.absolute_y(bus);
-		self.slo(location, bus);
-	    },
+    fn adc<M: Memory>(&mut self, location: u16, bus: &mut M) {
+	let operand = self.read(bus, location);
+	self.decimal_cycle(location, bus);
+	self.add(operand);
+    }
 
-	    // RLA
-	    I{ opcode: Op::RLA, addr_mode: AM::ZPG, ..} => {
-		let location = self.zero_page(bus);
-		self.rla(location, bus);
-	    },
-	    I{ opcode: Op::RLA, addr_mode: AM::ZPX, ..} => {
-		let location = self.zero_page_x(bus);
-		self.rla(location, bus);
-	    },
-	    I{ opcode: Op::RLA, addr_mode: AM::INX, ..} => {
-		let location = self.indexed_indirect(bus);
-		self.rla(location, bus);
-	    },
-	    I{ opcode: Op::RLA, addr_mode: AM::INY, ..} => {
-		let location = self.indirect_indexed(bus);
-		self.rla(location, bus);
-	    },
-	    I{ opcode: Op::RLA, addr_mode: AM::ABS, ..} => {
-		let location = self.absolute(bus);
-		self.rla(location, bus);
-	    },
-	    I{ opcode: Op::RLA, addr_mode: AM::ABX, ..} => {
-		let location = self.absolute_x(bus);
-		self.rla(location, bus);
-	    },
-	    I{ opcode: Op::RLA, addr_mode: AM::ABY, ..} => {
-		let location = self.absolute_y(bus);
-		self.rla(location, bus);
-	    },
+    fn sbc<M: Memory>(&mut self, location: u16, bus: &mut M) {
+	let operand = self.read(bus, location);
+	self.decimal_cycle(location, bus);
+	self.subtract(operand);
+    }
 
-	    // SRE
-	    I{ opcode: Op::SRE, addr_mode: AM::ZPG, ..} => {
-		let location = self.zero_page(bus);
-		self.sre(location, bus);
-	    },
-	    I{ opcode: Op::SRE, addr_mode: AM::ZPX, ..} => {
-		let location = self.zero_page_x(bus);
-		self.sre(location, bus);
-	    },
-	    I{ opcode: Op::SRE, addr_mode: AM::INX, ..} => {
-		let location = self.indexed_indirect(bus);
-		self.sre(location, bus);
-	    },
-	    I{ opcode: Op::SRE, addr_mode: AM::INY, ..} => {
-		let location = self.indirect_indexed(bus);
-		self.sre(location, bus);
-	    },
-	    I{ opcode: Op::SRE, addr_mode: AM::ABS, ..} => {
-		let location = self.absolute(bus);
-		self.sre(location, bus);
-	    },
-	    I{ opcode: Op::SRE, addr_mode: AM::ABX, ..} => {
-		let location = self.absolute_x(bus);
-		self.sre(location, bus);
-	    },
-	    I{ opcode: Op::SRE, addr_mode: AM::ABY, ..} => {
-		let location = self.absolute_y(bus);
-		self.sre(location, bus);
-	    },
+    fn decimal_mode(&self) -> bool {
+	self.flag_d && self.variant.has_decimal()
+    }
 
-	    // RRA
-	    I{ opcode: Op::RRA, addr_mode: AM::ZPG, ..} => {
-		let location = self.zero_page(bus);
-		self.rra(location, bus);
-	    },
-	    I{ opcode: Op::RRA, addr_mode: AM::ZPX, ..} => {
-		let location = self.zero_page_x(bus);
-		self.rra(location, bus);
-	    },
-	    I{ opcode: Op::RRA, addr_mode: AM::INX, ..} => {
-		let location = self.indexed_indirect(bus);
-		self.rra(location, bus);
-	    },
-	    I{ opcode: Op::RRA, addr_mode: AM::INY, ..} => {
-		let location = self.indirect_indexed(bus);
-		self.rra(location, bus);
-	    },
-	    I{ opcode: Op::RRA, addr_mode: AM::ABS, ..} => {
-		let location = self.absolute(bus);
-		self.rra(location, bus);
-	    },
-	    I{ opcode: Op::RRA, addr_mode: AM::ABX, ..} => {
-		let location = self.absolute_x(bus);
-		self.rra(location, bus);
-	    },
-	    I{ opcode: Op::RRA, addr_mode: AM::ABY, ..} => {
-		let location = self.absolute_y(bus);
-		self.rra(location, bus);
-	    },
+    /// The 65C02 spends an extra cycle fixing up N and Z after a decimal
+    /// ADC or SBC.
+    fn decimal_cycle<M: Memory>(&mut self, location: u16, bus: &mut M) {
+	if self.decimal_mode() && self.variant.is_cmos() {
+	    self.read(bus, location);
+	}
+    }
 
-	    I{ opcode: Op::KIL, .. } => return Ok(true),
+    /// A + M + C, in BCD when decimal mode is on.
+    ///
+    /// Decimal mode follows http://www.6502.org/tutorials/decimal_mode.html:
+    /// on NMOS parts Z comes from the binary sum and N and V from the sum
+    /// before the high digit is adjusted. The 65C02 sets N and Z from the
+    /// result.
+    fn add(&mut self, operand: u8) {
+	let a = self.reg_a as u16;
+	let m = operand as u16;
+	let c = self.flag_c as u16;
+	let binary = a + m + c;
+	if !self.decimal_mode() {
+	    self.set_cv(self.reg_a, operand, binary);
+	    self.reg_a = binary as u8;
+	    self.set_zn(self.reg_a);
+	    return;
+	}
 
-	    /* catch all */
-	    _ => {
-		println!("unrecognized instruction {:?}", instruction);
-		return Err(EmuErr::UnrecognizedOpCode(0x0));
-	    },
+	let mut lo = (a & 0x0f) + (m & 0x0f) + c;
+	if lo >= 0x0a {
+	    lo = ((lo + 0x06) & 0x0f) + 0x10;
+	}
+	let mut sum = (a & 0xf0) + (m & 0xf0) + lo;
+	let signed = (a & 0xf0) as u8 as i8 as i16 + (m & 0xf0) as u8 as i8 as i16 + lo as i16;
+	self.flag_v = !(-128..=127).contains(&signed);
+	self.set_n(sum as u8);
+	self.flag_z = binary as u8 == 0;
+	if sum >= 0xa0 {
+	    sum += 0x60;
+	}
+	self.flag_c = sum >= 0x100;
+	self.reg_a = sum as u8;
+	if self.variant.is_cmos() {
+	    self.set_zn(self.reg_a);
 	}
-	Ok(false)
     }
 
-    fn ora(&mut self, location: u16, bus: &mut Bus) {
-	self.reg_a |= bus.read(location);
+    /// A - M - !C, in BCD when decimal mode is on. The flags are those of
+    /// the binary subtraction, apart from N and Z on the 65C02 which come
+    /// from the result.
+    fn subtract(&mut self, operand: u8) {
+	let a = self.reg_a as i16;
+	let m = operand as i16;
+	let borrow = 1 - self.flag_c as i16;
+	let result = (self.reg_a as u16) + (!operand as u16) + self.flag_c as u16;
+	self.set_cv(self.reg_a, !operand, result);
+	self.reg_a = result as u8;
 	self.set_zn(self.reg_a);
-    }
+	if !self.decimal_mode() {
+	    return;
+	}
 
-    fn and(&mut self, location: u16, bus: &mut Bus) {
-	self.reg_a &= bus.read(location);
-	self.set_zn(self.reg_a);
+	let mut lo = (a & 0x0f) - (m & 0x0f) - borrow;
+	let mut res;
+	if self.variant.is_cmos() {
+	    res = a - m - borrow;
+	    if res < 0 {
+		res -= 0x60;
+	    }
+	    if lo < 0 {
+		res -= 0x06;
+	    }
+	} else {
+	    if lo < 0 {
+		lo = ((lo - 0x06) & 0x0f) - 0x10;
+	    }
+	    res = (a & 0xf0) - (m & 0xf0) + lo;
+	    if res < 0 {
+		res -= 0x60;
+	    }
+	}
+	self.reg_a = res as u8;
+	if self.variant.is_cmos() {
+	    self.set_zn(self.reg_a);
+	}
     }
 
-    fn eor(&mut self, location: u16, bus: &mut Bus) {
-	self.reg_a ^= bus.read(location);
-	self.set_zn(self.reg_a);
+    fn compare_a<M: Memory>(&mut self, location: u16, bus: &mut M) {
+	self.compare(self.reg_a, location, bus);
     }
 
-    fn adc(&mut self, location: u16, bus: &mut Bus) {
-	let operand = bus.read(location);
-	let result: u16 = operand as u16 + self.reg_a as u16 + self.flag_c as u16;
-	// Overflow
-	self.flag_c = result & 0x100 > 0;
-	let result = result as u8;
-	self.flag_v = (self.reg_a ^ result) & (operand ^ result) & 0x80 != 0;
-	self.reg_a = result;
-	self.set_zn(self.reg_a);
+    fn compare_x<M: Memory>(&mut self, location: u16, bus: &mut M) {
+	self.compare(self.reg_x, location, bus);
     }
 
-    fn sbc(&mut self, location: u16, bus: &mut Bus) {
-	let operand = !bus.read(location); // note: binary not
-	let result: u16 = self.reg_a as u16 + operand as u16 + self.flag_c as u16;
-	self.flag_c = result & 0x100 > 0;
-	let result = result as u8;
-	self.flag_v = (self.reg_a ^ result) & (operand ^ result) & 0x80 != 0;
-	self.reg_a = result;
-	self.set_zn(self.reg_a);
+    fn compare_y<M: Memory>(&mut self, location: u16, bus: &mut M) {
+	self.compare(self.reg_y, location, bus);
     }
 
     fn cmp(&mut self, fst: u8, snd: u8) {
@@ -1175,249 +743,482 @@ impl Cpu {
 	self.flag_c = fst >= snd;
     }
 
-    fn dec(&mut self, location: u16, bus: &mut Bus) {
-	let result = bus.read(location).wrapping_sub(1);
-	bus.write(location, result);
+    fn compare<M: Memory>(&mut self, reg: u8, location: u16, bus: &mut M) {
+	let m = self.read(bus, location);
+	self.cmp(reg, m);
+    }
+
+    fn dec<M: Memory>(&mut self, location: u16, bus: &mut M) {
+	let result = self.modify(location, bus, |_, m| m.wrapping_sub(1));
 	self.set_zn(result);
     }
 
-    fn inc(&mut self, location: u16, bus: &mut Bus) {
-	let result = bus.read(location).wrapping_add(1);
-	bus.write(location, result);
+    fn inc<M: Memory>(&mut self, location: u16, bus: &mut M) {
+	let result = self.modify(location, bus, |_, m| m.wrapping_add(1));
 	self.set_zn(result);
     }
 
     fn asl_acc(&mut self) {
-	self.flag_c = (self.reg_a >> 7) & Self::CARRY > 0;
-	self.reg_a <<= 1;
-	self.set_zn(self.reg_a);
+	self.reg_a = self.asl_val(self.reg_a);
+    }
+
+    fn asl<M: Memory>(&mut self, location: u16, bus: &mut M) -> u8 {
+	self.modify(location, bus, Self::asl_val)
     }
 
-    fn asl(&mut self, location: u16, bus: &mut Bus) {
-	let m = bus.read(location);
+    fn asl_val(&mut self, m: u8) -> u8 {
 	self.flag_c = (m >> 7) & Self::CARRY > 0;
 	let m = m << 1;
-	bus.write(location, m);
 	self.set_zn(m);
+	m
     }
 
     fn rol_acc(&mut self) {
-	let carry = self.flag_c as u8;
-	self.flag_c = (self.reg_a >> 7) & Self::CARRY > 0;
-	self.reg_a = (self.reg_a << 1) | carry;
-	self.set_zn(self.reg_a);
+	self.reg_a = self.rol_val(self.reg_a);
     }
 
-    fn rol(&mut self, location: u16, bus: &mut Bus) {
+    fn rol<M: Memory>(&mut self, location: u16, bus: &mut M) -> u8 {
+	self.modify(location, bus, Self::rol_val)
+    }
+
+    fn rol_val(&mut self, m: u8) -> u8 {
 	let carry = self.flag_c as u8;
-	let m = bus.read(location);
 	self.flag_c = (m >> 7) & Self::CARRY > 0;
 	let m = (m << 1) | carry;
-	bus.write(location, m);
 	self.set_zn(m);
+	m
     }
 
     fn lsr_acc(&mut self) {
-	self.flag_c = self.reg_a & Self::CARRY > 0;
-	self.reg_a >>= 1;
-	self.set_zn(self.reg_a);
+	self.reg_a = self.lsr_val(self.reg_a);
     }
 
-    fn lsr(&mut self, location: u16, bus: &mut Bus) {
-	let m = bus.read(location);
+    fn lsr<M: Memory>(&mut self, location: u16, bus: &mut M) -> u8 {
+	self.modify(location, bus, Self::lsr_val)
+    }
+
+    fn lsr_val(&mut self, m: u8) -> u8 {
 	self.flag_c = m & Self::CARRY > 0;
 	let m = m >> 1;
-	bus.write(location, m);
 	self.set_zn(m);
+	m
     }
 
     fn ror_acc(&mut self) {
-	let c = self.flag_c;
-	self.flag_c = (self.reg_a & 0x1) > 0;
-	self.reg_a = (self.reg_a >> 1) | ((c as u8) << 7);
-	self.flag_z = self.reg_a == 0;
-	self.flag_n = c;
+	self.reg_a = self.ror_val(self.reg_a);
     }
 
-    fn ror(&mut self, location: u16, bus: &mut Bus) {
-	let mut m = bus.read(location);
+    fn ror<M: Memory>(&mut self, location: u16, bus: &mut M) -> u8 {
+	self.modify(location, bus, Self::ror_val)
+    }
+
+    fn ror_val(&mut self, m: u8) -> u8 {
 	let c = self.flag_c;
 	self.flag_c = (m & 0x1) > 0;
-	m = (m >> 1) | ((c as u8) << 7);
+	let m = (m >> 1) | ((c as u8) << 7);
 	self.flag_z = m == 0;
 	self.flag_n = c;
-	bus.write(location, m);
+	m
     }
 
-    fn lda(&mut self, location: u16, bus: &mut Bus) {
-	self.reg_a = bus.read(location);
+    fn lda<M: Memory>(&mut self, location: u16, bus: &mut M) {
+	self.reg_a = self.read(bus, location);
 	self.set_zn(self.reg_a);
     }
 
-    fn lax(&mut self, location: u16, bus: &mut Bus) {
-	let data = bus.read(location);
+    fn lax<M: Memory>(&mut self, location: u16, bus: &mut M) {
+	let data = self.read(bus, location);
 	self.reg_a = data;
 	self.reg_x = data;
 	self.set_zn(data);
     }
 
-    fn sax(&mut self, location: u16, bus: &mut Bus) {
-	bus.write(location, self.reg_a & self.reg_x);
+    /// LAX #imm is unstable: A is ORed with the magic constant first
+    fn lax_imm<M: Memory>(&mut self, location: u16, bus: &mut M) {
+	let m = self.read(bus, location);
+	let res = (self.reg_a | self.magic) & m;
+	self.reg_a = res;
+	self.reg_x = res;
+	self.set_zn(res);
+    }
+
+    fn las<M: Memory>(&mut self, location: u16, bus: &mut M) {
+	let res = self.read(bus, location) & self.reg_sp;
+	self.reg_a = res;
+	self.reg_x = res;
+	self.reg_sp = res;
+	self.set_zn(res);
+    }
+
+    /// AND, then C is copied from N
+    fn anc<M: Memory>(&mut self, location: u16, bus: &mut M) {
+	self.and(location, bus);
+	self.flag_c = self.flag_n;
+    }
+
+    /// AND followed by LSR A
+    fn alr<M: Memory>(&mut self, location: u16, bus: &mut M) {
+	self.and(location, bus);
+	self.lsr_acc();
+    }
+
+    /// X = (A & X) - M, setting flags like CMP
+    fn axs<M: Memory>(&mut self, location: u16, bus: &mut M) {
+	let m = self.read(bus, location);
+	let ax = self.reg_a & self.reg_x;
+	self.flag_c = ax >= m;
+	self.reg_x = ax.wrapping_sub(m);
+	self.set_zn(self.reg_x);
+    }
+
+    fn xaa<M: Memory>(&mut self, location: u16, bus: &mut M) {
+	let m = self.read(bus, location);
+	self.reg_a = (self.reg_a | self.magic) & self.reg_x & m;
+	self.set_zn(self.reg_a);
+    }
+
+    fn sax<M: Memory>(&mut self, location: u16, bus: &mut M) {
+	self.write(bus, location, self.reg_a & self.reg_x);
     }
 
-    fn dcp(&mut self, location: u16, bus: &mut Bus) {
-	let res = bus.read(location).wrapping_sub(1);
+    fn dcp<M: Memory>(&mut self, location: u16, bus: &mut M) {
+	let res = self.modify(location, bus, |_, m| m.wrapping_sub(1));
 	let tmp = self.reg_a.wrapping_sub(res);
 	self.set_zn(tmp);
 	self.flag_c = self.reg_a >= res;
-	bus.write(location, res);
     }
 
-    fn isc(&mut self, location: u16, bus: &mut Bus) {
-	let operand = bus.read(location).wrapping_add(1);
-	bus.write(location, operand);
-
-	let result = (self.reg_a as u16)
-	    .wrapping_add(!operand as u16)
-	    .wrapping_add(self.flag_c as u16);
-
-	self.set_cv(self.reg_a, !operand, result);
-	self.set_zn(result as u8);
-	self.reg_a = result as u8;
+    fn isc<M: Memory>(&mut self, location: u16, bus: &mut M) {
+	let operand = self.modify(location, bus, |_, m| m.wrapping_add(1));
+	self.subtract(operand);
     }
 
-    fn slo(&mut self, location: u16, bus: &mut Bus) {
-	self.asl(location, bus);
-	let res = self.reg_a | bus.read(location);
+    fn slo<M: Memory>(&mut self, location: u16, bus: &mut M) {
+	let val = self.asl(location, bus);
+	let res = self.reg_a | val;
 	self.set_zn(res);
 	self.reg_a = res;
     }
 
-    fn sta(&mut self, location: u16, bus: &mut Bus) {
-	bus.write(location, self.reg_a);
+    fn sta<M: Memory>(&mut self, location: u16, bus: &mut M) {
+	self.write(bus, location, self.reg_a);
+    }
+
+    fn stx<M: Memory>(&mut self, location: u16, bus: &mut M) {
+	self.write(bus, location, self.reg_x);
     }
 
-    fn ldx(&mut self, location: u16, bus: &mut Bus) {
-	self.reg_x = bus.read(location);
+    fn sty<M: Memory>(&mut self, location: u16, bus: &mut M) {
+	self.write(bus, location, self.reg_y);
+    }
+
+    fn stz<M: Memory>(&mut self, location: u16, bus: &mut M) {
+	self.write(bus, location, 0);
+    }
+
+    /// NOPs with an operand still read it
+    fn nop<M: Memory>(&mut self, location: u16, bus: &mut M) {
+	self.read(bus, location);
+    }
+
+    fn ldx<M: Memory>(&mut self, location: u16, bus: &mut M) {
+	self.reg_x = self.read(bus, location);
 	self.set_zn(self.reg_x);
     }
 
-    fn ldy(&mut self, location: u16, bus: &mut Bus) {
-	self.reg_y = bus.read(location);
+    fn ldy<M: Memory>(&mut self, location: u16, bus: &mut M) {
+	self.reg_y = self.read(bus, location);
 	self.set_zn(self.reg_y);
     }
 
-    fn bit(&mut self, location: u16, bus: &mut Bus) {
-	let m = bus.read(location);
+    fn bit<M: Memory>(&mut self, location: u16, bus: &mut M) {
+	let m = self.read(bus, location);
 	self.flag_v = (m >> 6) & 1 > 0;
 	self.flag_z = (m & self.reg_a) == 0;
 	self.set_n(m);
     }
 
-    fn rla(&mut self, location: u16, bus: &mut Bus) {
-	self.rol(location, bus);
-	let val = bus.read(location);
+    /// 65C02 BIT #imm only affects Z
+    fn bit_imm<M: Memory>(&mut self, location: u16, bus: &mut M) {
+	self.flag_z = self.read(bus, location) & self.reg_a == 0;
+    }
+
+    fn jmp_indirect<M: Memory>(&mut self, bus: &mut M) {
+	/*
+	Quoted from: https://www.nesdev.org/obelisk-6502-guide/reference.html#INX
+	"""
+	NB:
+	An original 6502 has does not correctly fetch the target address if
+	the indirect vector falls on a page boundary (e.g. $xxFF where xx is
+	any value from $00 to $FF). In this case fetches the LSB from $xxFF
+	as expected but takes the MSB from $xx00. This is fixed in some later
+	chips like the 65SC02 so for compatibility always ensure the indirect
+	vector is not at the end of the page.
+	"""
+	 */
+	let location = self.absolute(bus);
+	let hi_location = if self.variant.is_cmos() {
+	    // fixed, at the cost of an extra cycle
+	    self.read(bus, self.reg_pc.wrapping_sub(1));
+	    location.wrapping_add(1)
+	} else {
+	    (location & 0xff00) | (location.wrapping_add(1) & 0xff)
+	};
+	let lo = self.read(bus, location) as u16;
+	let hi = self.read(bus, hi_location) as u16;
+	self.reg_pc = (hi << 8) | lo;
+    }
+
+    fn rla<M: Memory>(&mut self, location: u16, bus: &mut M) {
+	let val = self.rol(location, bus);
 	let res = self.reg_a & val;
 	self.set_zn(res);
 	self.reg_a = res;
     }
 
-    fn sre(&mut self, location: u16, bus: &mut Bus) {
-	self.lsr(location, bus);
-	let val = bus.read(location);
-	self.reg_a = self.reg_a ^ val;
+    fn sre<M: Memory>(&mut self, location: u16, bus: &mut M) {
+	let val = self.lsr(location, bus);
+	self.reg_a ^= val;
 	self.set_zn(self.reg_a);
     }
 
-    fn rra(&mut self, location: u16, bus: &mut Bus) {
-	self.ror(location, bus);
-	let val = bus.read(location);
-	let result = self.reg_a as u16 + val as u16 + self.flag_c as u16;
-	self.set_cv(self.reg_a, val, result);
-	self.reg_a = result as u8;
+    fn rra<M: Memory>(&mut self, location: u16, bus: &mut M) {
+	let val = self.ror(location, bus);
+	self.add(val);
+    }
+
+    /// AND with the operand followed by ROR A, except C and V come from bits
+    /// 6 and 5 of the result. In decimal mode the result is BCD adjusted
+    /// instead, with C set by the high digit fixup.
+    fn arr<M: Memory>(&mut self, location: u16, bus: &mut M) {
+	let and = self.reg_a & self.read(bus, location);
+	self.reg_a = (and >> 1) | ((self.flag_c as u8) << 7);
 	self.set_zn(self.reg_a);
+	if !self.decimal_mode() {
+	    self.flag_c = (self.reg_a >> 6) & 1 > 0;
+	    self.flag_v = ((self.reg_a >> 6) ^ (self.reg_a >> 5)) & 1 > 0;
+	    return;
+	}
+
+	self.flag_v = (and ^ self.reg_a) & 0x40 != 0;
+	if (and & 0x0f) + (and & 0x01) > 0x05 {
+	    self.reg_a = (self.reg_a & 0xf0) | (self.reg_a.wrapping_add(0x06) & 0x0f);
+	}
+	self.flag_c = (and as u16 & 0xf0) + (and as u16 & 0x10) > 0x50;
+	if self.flag_c {
+	    self.reg_a = self.reg_a.wrapping_add(0x60);
+	}
+    }
+
+    /// Z from A & M, then the bits set in A are cleared in M
+    fn trb<M: Memory>(&mut self, location: u16, bus: &mut M) {
+	self.modify(location, bus, |cpu, m| {
+	    cpu.flag_z = cpu.reg_a & m == 0;
+	    m & !cpu.reg_a
+	});
+    }
+
+    /// Z from A & M, then the bits set in A are set in M
+    fn tsb<M: Memory>(&mut self, location: u16, bus: &mut M) {
+	self.modify(location, bus, |cpu, m| {
+	    cpu.flag_z = cpu.reg_a & m == 0;
+	    m | cpu.reg_a
+	});
+    }
+
+    /// SHX, SHY, AHX and TAS store `value & (H + 1)`, where H is the high byte
+    /// of the base address. When indexing crosses a page the high byte of the
+    /// target address is corrupted: it's replaced by the stored value.
+    fn store_and_high<M: Memory>(&mut self, base: u16, index: u8, value: u8, bus: &mut M) {
+	let location = self.index(base, index, bus);
+	let data = value & ((base >> 8) as u8).wrapping_add(1);
+	let location = if Self::crosses_page(base, location) {
+	    ((data as u16) << 8) | (location & 0xff)
+	} else {
+	    location
+	};
+	self.write(bus, location, data);
+    }
+
+    /// Read-modify-write: the 6502 writes the unmodified value back while it
+    /// computes the result, then writes the result. Some mapper registers
+    /// observe both writes. The 65C02 reads the location again instead.
+    fn modify<M: Memory, F>(&mut self, location: u16, bus: &mut M, f: F) -> u8
+    where F: FnOnce(&mut Self, u8) -> u8 {
+	let m = self.read(bus, location);
+	if self.variant.is_cmos() {
+	    self.read(bus, location);
+	} else {
+	    self.write(bus, location, m);
+	}
+	let result = f(self, m);
+	self.write(bus, location, result);
+	result
     }
 
     /// pushes a value onto the stack
-    fn push(&mut self, val: u8, bus: &mut Bus) {
-	bus.write(0x100 | self.reg_sp as u16, val);
-	self.reg_sp -= 1;
+    fn push<M: Memory>(&mut self, val: u8, bus: &mut M) {
+	self.write(bus, 0x100 | self.reg_sp as u16, val);
+	self.reg_sp = self.reg_sp.wrapping_sub(1);
     }
 
     /// pulls a value off the top of the stack
-    fn pull(&mut self, bus: &mut Bus) -> u8 {
-	self.reg_sp += 1;
-	bus.read(0x100 | self.reg_sp as u16)
+    fn pull<M: Memory>(&mut self, bus: &mut M) -> u8 {
+	self.reg_sp = self.reg_sp.wrapping_add(1);
+	self.read(bus, 0x100 | self.reg_sp as u16)
+    }
+
+    /// Reads the top of the stack without popping it. Stack instructions
+    /// spend a cycle doing this while the stack pointer is incremented.
+    fn peek_stack<M: Memory>(&mut self, bus: &mut M) {
+	self.read(bus, 0x100 | self.reg_sp as u16);
     }
 
-    /// BPL, BMI, BVC, BCC, BCS, BNE, BEQ
-    fn execute_cond_branch(&mut self, condition: bool, bus: &mut Bus) {
+    /// BPL, BMI, BVC, BCC, BCS, BNE, BEQ and the 65C02 BRA
+    ///
+    /// A taken branch costs one extra cycle, and a second one if the
+    /// destination is on a different page than the next instruction.
+    fn execute_cond_branch<M: Memory>(&mut self, condition: bool, bus: &mut M) {
+	let offset = self.fetch(bus) as i8;
 	if condition {
-	    let offset = bus.read(post_inc!(self.reg_pc));
-	    let offset = offset as i8;
+	    let next = self.reg_pc;
+	    self.read(bus, next);
 	    // mixed integer ops :)
-	    self.reg_pc = self.reg_pc.wrapping_add_signed(offset as i16);
-	} else {
-	    self.reg_pc += 1;
+	    self.reg_pc = next.wrapping_add_signed(offset as i16);
+	    if Self::crosses_page(next, self.reg_pc) {
+		// the cpu reads before carrying into the high byte
+		self.read(bus, (next & 0xff00) | (self.reg_pc & 0xff));
+	    }
 	}
     }
 
     /* Addressing mode utilities */
 
+    /// the operand is the byte following the opcode
+    fn immediate<M: Memory>(&mut self, _bus: &mut M) -> u16 {
+	post_inc!(self.reg_pc)
+    }
+
     /// indexed indirect addressing mode resolution
-    fn indexed_indirect(&mut self, bus: &mut Bus) -> u16 {
-	let zero_addr = bus.read(post_inc!(self.reg_pc)) as u16 + self.reg_x as u16;
-	bus.read(zero_addr & 0xff) as u16 | ((bus.read((zero_addr + 1) & 0xff)) as u16) << 8
+    fn indexed_indirect<M: Memory>(&mut self, bus: &mut M) -> u16 {
+	let ptr = self.fetch(bus);
+	self.read(bus, ptr as u16);
+	let ptr = ptr.wrapping_add(self.reg_x);
+	let lo = self.read(bus, ptr as u16) as u16;
+	let hi = self.read(bus, ptr.wrapping_add(1) as u16) as u16;
+	(hi << 8) | lo
     }
 
     /// indirect indexed addressing mode resolution
-    fn indirect_indexed(&mut self, bus: &mut Bus) -> u16 {
-	let addr = bus.read(post_inc!(self.reg_pc)) as u16;
-	let lo = bus.read(addr & 0xff) as u16;
-	let hi = (bus.read((addr + 1) & 0xff) as u16) << 8;
-	(hi | lo) .wrapping_add(self.reg_y as u16)
+    fn indirect_indexed<M: Memory>(&mut self, bus: &mut M) -> u16 {
+	let base = self.indirect(bus);
+	self.index(base, self.reg_y, bus)
+    }
+
+    /// reads the 16 bit pointer on the zero page addressed by the operand
+    fn indirect<M: Memory>(&mut self, bus: &mut M) -> u16 {
+	let ptr = self.fetch(bus);
+	let lo = self.read(bus, ptr as u16) as u16;
+	let hi = self.read(bus, ptr.wrapping_add(1) as u16) as u16;
+	(hi << 8) | lo
     }
 
     /// absolute addressing mode resolution
-    fn absolute(&mut self, bus: &mut Bus) -> u16 {
-	let val = bus.read_u16(self.reg_pc);
-	self.reg_pc += 2;
-	val
+    fn absolute<M: Memory>(&mut self, bus: &mut M) -> u16 {
+	let lo = self.fetch(bus) as u16;
+	let hi = self.fetch(bus) as u16;
+	(hi << 8) | lo
     }
 
     /// indexed (by X) absolute addressing
-    fn absolute_x(&mut self, bus: &mut Bus) -> u16 {
-	let result = bus.read_u16(self.reg_pc) + self.reg_x as u16;
-	self.reg_pc += 2;
-	result
+    fn absolute_x<M: Memory>(&mut self, bus: &mut M) -> u16 {
+	let base = self.absolute(bus);
+	self.index(base, self.reg_x, bus)
     }
 
     /// indexed (by Y) absolute addressing
-    fn absolute_y(&mut self, bus: &mut Bus) -> u16 {
-	let result = (self.reg_y as u16).wrapping_add(bus.read_u16(self.reg_pc));
-	self.reg_pc = self.reg_pc.wrapping_add(2);
+    fn absolute_y<M: Memory>(&mut self, bus: &mut M) -> u16 {
+	let base = self.absolute(bus);
+	self.index(base, self.reg_y, bus)
+    }
+
+    /// Adds an index register to a 16 bit base address.
+    ///
+    /// The 6502 adds the index to the low byte first and reads from that
+    /// (possibly wrong) address while it carries into the high byte. Reads
+    /// skip that cycle when no carry is needed, stores and read-modify-write
+    /// instructions always spend it. The 65C02 re-reads the last operand
+    /// byte instead.
+    fn index<M: Memory>(&mut self, base: u16, index: u8, bus: &mut M) -> u16 {
+	let result = base.wrapping_add(index as u16);
+	if Self::crosses_page(base, result) || !matches!(self.access, Access::Read) {
+	    let dummy = if self.variant.is_cmos() {
+		self.reg_pc.wrapping_sub(1)
+	    } else {
+		(base & 0xff00) | (result & 0xff)
+	    };
+	    self.read(bus, dummy);
+	}
 	result
     }
 
     /// zero page addressing mode resolution
-    fn zero_page(&mut self, bus: &mut Bus) -> u16 {
-	bus.read(post_inc!(self.reg_pc)) as u16
+    fn zero_page<M: Memory>(&mut self, bus: &mut M) -> u16 {
+	self.fetch(bus) as u16
     }
 
     /// indexed (by X) zero page addressing mode resolution
-    fn zero_page_x(&mut self, bus: &mut Bus) -> u16 {
+    fn zero_page_x<M: Memory>(&mut self, bus: &mut M) -> u16 {
 	// Note: If we have LDA $80,X with X = $FF then memory location will be
 	// $7F and NOT $017F.
 	// Example: LDA $20,X
-	bus.read(post_inc!(self.reg_pc)).wrapping_add(self.reg_x) as u16
+	let base = self.zero_page(bus);
+	self.read(bus, base);
+	(base as u8).wrapping_add(self.reg_x) as u16
+    }
+
+    fn zero_page_y<M: Memory>(&mut self, bus: &mut M) -> u16 {
+	let base = self.zero_page(bus);
+	self.read(bus, base);
+	(base as u8).wrapping_add(self.reg_y) as u16
+    }
+
+    /* Bus access. Every 6502 cycle is a read or a write. */
+
+    /// reads the byte at pc and advances it
+    fn fetch<M: Memory>(&mut self, bus: &mut M) -> u8 {
+	let pc = post_inc!(self.reg_pc);
+	self.read(bus, pc)
+    }
+
+    fn read<M: Memory>(&mut self, bus: &mut M, addr: u16) -> u8 {
+	self.tick(bus);
+	bus.read(addr)
+    }
+
+    fn write<M: Memory>(&mut self, bus: &mut M, addr: u16, data: u8) {
+	self.tick(bus);
+	bus.write(addr, data);
     }
 
-    fn zero_page_y(&mut self, bus: &mut Bus) -> u16 {
-	bus.read(post_inc!(self.reg_pc)).wrapping_add(self.reg_y) as u16
+    /// Counts a cycle of the current instruction. When cycle stepped the rest
+    /// of the system is clocked before the access, so it observes the access
+    /// at the right time.
+    fn tick<M: Memory>(&mut self, bus: &mut M) {
+	self.cycles += 1;
+	if self.cycle_stepped {
+	    if let Err(e) = bus.tick() {
+		self.bus_err.get_or_insert(e);
+	    }
+	}
     }
 
     const CARRY: u8 = 1;
 
+    fn crosses_page(a: u16, b: u16) -> bool {
+	a & 0xff00 != b & 0xff00
+    }
+
     fn set_zn(&mut self, val: u8) {
 	self.flag_z = val == 0;
 	self.set_n(val);
@@ -1434,9 +1235,107 @@ impl Cpu {
     }
 }
 
+/// Register and state access for debuggers, save states and tools
+#[allow(dead_code)]
+impl Cpu {
+    pub fn pc(&self) -> u16 { self.reg_pc }
+    pub fn set_pc(&mut self, pc: u16) { self.reg_pc = pc; }
+
+    pub fn sp(&self) -> u8 { self.reg_sp }
+    pub fn set_sp(&mut self, sp: u8) { self.reg_sp = sp; }
+
+    pub fn a(&self) -> u8 { self.reg_a }
+    pub fn set_a(&mut self, a: u8) { self.reg_a = a; }
+
+    pub fn x(&self) -> u8 { self.reg_x }
+    pub fn set_x(&mut self, x: u8) { self.reg_x = x; }
+
+    pub fn y(&self) -> u8 { self.reg_y }
+    pub fn set_y(&mut self, y: u8) { self.reg_y = y; }
+
+    /// Status register, with bit 5 set and B clear
+    pub fn p(&self) -> u8 { self.flags().p() }
+    pub fn set_p(&mut self, p: u8) { self.set_flags(Flags::from_p(p)); }
+
+    pub fn flags(&self) -> Flags {
+	Flags {
+	    carry: self.flag_c,
+	    zero: self.flag_z,
+	    interrupt_disable: self.flag_i,
+	    decimal: self.flag_d,
+	    overflow: self.flag_v,
+	    negative: self.flag_n,
+	}
+    }
+
+    pub fn set_flags(&mut self, flags: Flags) {
+	self.flag_c = flags.carry;
+	self.flag_z = flags.zero;
+	self.flag_i = flags.interrupt_disable;
+	self.flag_d = flags.decimal;
+	self.flag_v = flags.overflow;
+	self.flag_n = flags.negative;
+	self.reg_p = flags.p();
+    }
+
+    /// Cycles executed since reset
+    pub fn total_cycles(&self) -> usize { self.total_cycles }
+
+    /// The next step starts a new instruction or interrupt
+    pub fn at_instruction_boundary(&self) -> bool {
+	self.cycles == 0 && self.halted.is_none()
+    }
+
+    /// The last instruction executed, decoded for the current variant
+    pub fn last_instruction(&self) -> Option<I> {
+	self.last_opcode.map(|opcode| self.variant.decode(opcode))
+    }
+
+    pub fn state(&self) -> CpuState {
+	CpuState {
+	    pc: self.reg_pc,
+	    sp: self.reg_sp,
+	    a: self.reg_a,
+	    x: self.reg_x,
+	    y: self.reg_y,
+	    flags: self.flags(),
+	    cycles: self.total_cycles,
+	    stall: self.cycles as u16,
+	    interrupts: PendingInterrupts {
+		requested: self.interrupt,
+		nmi: self.nmi_seen,
+		irq: self.irq_seen,
+		irq_inhibit: self.irq_inhibit,
+	    },
+	    last_opcode: self.last_opcode,
+	}
+    }
+
+    /// Restores a state from `state`. The interrupt lines belong to the
+    /// devices driving them and are left alone. A jammed cpu runs again.
+    pub fn set_state(&mut self, state: &CpuState) {
+	self.halted = None;
+	self.reg_pc = state.pc;
+	self.reg_sp = state.sp;
+	self.reg_a = state.a;
+	self.reg_x = state.x;
+	self.reg_y = state.y;
+	self.set_flags(state.flags);
+	self.total_cycles = state.cycles;
+	self.cycles = state.stall as usize;
+	self.interrupt = state.interrupts.requested;
+	self.irq_inhibit = state.interrupts.irq_inhibit;
+	self.last_opcode = state.last_opcode;
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::bus::Bus;
+    use crate::asm::assemble;
+    use crate::bus::{Bus, Diagnostic};
+    use crate::cartridge::Cartridge;
+    use crate::interrupt::IrqSource;
+    use crate::memory::Ram;
     use std::io::Read;
     use super::*;
 
@@ -1447,6 +1346,9 @@ mod tests {
 	reg_y: u8,
 	reg_p: u8,
 	reg_sp: u8,
+	scanline: usize,
+	dot: usize,
+	cycles: usize,
     }
 
     fn parse_log_line(line: &str) -> Log {
@@ -1456,6 +1358,10 @@ mod tests {
 	let reg_y = u8::from_str_radix(&line[60..62], 16).unwrap();
 	let reg_p = u8::from_str_radix(&line[65..67], 16).unwrap();
 	let reg_sp = u8::from_str_radix(&line[71..73], 16).unwrap();
+	// PPU:sss,ddd CYC:n
+	let scanline = line[78..81].trim().parse().unwrap();
+	let dot = line[82..85].trim().parse().unwrap();
+	let cycles = line[90..].trim().parse().unwrap();
 
 	Log {
 	    addr,
@@ -1464,6 +1370,16 @@ mod tests {
 	    reg_y,
 	    reg_p,
 	    reg_sp,
+	    scanline,
+	    dot,
+	    cycles,
+	}
+    }
+
+    /// Steps the cpu, failing the test with the reason if it stops.
+    fn step<M: Memory>(cpu: &mut Cpu, bus: &mut M) {
+	if let Some(halt) = cpu.step(bus).unwrap() {
+	    panic!("cpu stopped: {}", halt);
 	}
     }
 
@@ -1477,31 +1393,35 @@ mod tests {
     }
 
     /// Runs a test rom and compares the actual execution log
-    /// against the expected execution log.
-    ///
-    /// NB: Doesn't assert cycle counts. Still need to implement
-    /// condition cycle counting for some instructions.
-    #[test]
-    fn test_rom() {
+    /// against the expected execution log, including cycle counts
+    /// and the ppu position.
+    fn run_test_rom(cycle_stepped: bool) {
 	let test_rom = "testrom.nes";
 	let test_log = "nestest.log";
-	let nmi_signal: Rc<RefCell<bool>> = Rc::new(RefCell::new(false));
-	let mut bus = Bus::new(nmi_signal.clone());
-	let mut cpu = Cpu::new(nmi_signal);
+	let mut bus = Bus::new();
+	let mut cpu = Cpu::new();
 
 	cpu.power_on();
+	cpu.set_cycle_stepped(cycle_stepped);
 	bus.load_rom(test_rom).unwrap();
 	cpu.reset(&mut bus);
 
 	// Use the test rom's automated suite which starts at 0xc000
 	cpu.reg_pc = 0xc000;
 
+	// tick through the reset sequence
+	while cpu.cycles > 0 {
+	    step(&mut cpu, &mut bus);
+	    bus.step().unwrap();
+	}
+
 	let mut fd = std::fs::File::open(test_log).unwrap();
 	let mut logs = String::new();
 	fd.read_to_string(&mut logs).unwrap();
 
 	for l in logs.lines() {
 	    let state = parse_log_line(l);
+	    bus.catch_up().unwrap();
 	    println!("actual cpu: {}", cpu.state());
 	    let clean: Vec<_> = l.split_whitespace().collect();
 	    println!("log line: {}", clean.join(" "));
@@ -1517,14 +1437,804 @@ mod tests {
 	    check_status(state.reg_p, &cpu);
 	    assert_eq!(state.reg_sp, cpu.reg_sp,
 		       "reg_sp expected {:x}, actual {:x}", state.reg_sp, cpu.reg_sp);
+	    assert_eq!(state.cycles, cpu.total_cycles,
+		       "cycles expected {}, actual {}", state.cycles, cpu.total_cycles);
+	    assert_eq!(state.scanline, bus.ppu().scanline(),
+		       "ppu scanline expected {}, actual {}", state.scanline, bus.ppu().scanline());
+	    assert_eq!(state.dot, bus.ppu().cycle(),
+		       "ppu dot expected {}, actual {}", state.dot, bus.ppu().cycle());
+
+	    if cycle_stepped {
+		// the bus is clocked by the cpu
+		step(&mut cpu, &mut bus);
+		assert_eq!(cpu.cycles, 0);
+		continue;
+	    }
 
-	    assert!(!cpu.step(&mut bus).unwrap());
+	    step(&mut cpu, &mut bus);
+	    bus.step().unwrap();
 	    // tick down cycle stall
 	    while cpu.cycles > 0 {
-		assert!(!cpu.step(&mut bus).unwrap());
+		step(&mut cpu, &mut bus);
+		bus.step().unwrap();
 	    }
 	}
 	// The test does not actually end with a kill instruction,
 	// so no assert that the CPU exits.
     }
+
+    #[test]
+    fn test_rom() {
+	run_test_rom(false);
+    }
+
+    #[test]
+    fn test_rom_cycle_stepped() {
+	run_test_rom(true);
+    }
+
+    /// Builds a cpu and bus around an empty cartridge, ready to execute
+    /// whatever is placed in ram at 0x0000.
+    fn ram_harness() -> (Cpu, Bus) {
+	let mut bus = Bus::new();
+	let mut cpu = Cpu::new();
+	cpu.power_on();
+	let rom = assemble(".org $c000\nreset: JMP reset").unwrap().to_nrom().unwrap();
+	bus.insert_cartridge(Cartridge::from_bytes(&rom).unwrap());
+	cpu.reset(&mut bus);
+	cpu.cycles = 0;
+	cpu.reg_pc = 0x0000;
+	(cpu, bus)
+    }
+
+    /// Runs the cpu until the current instruction (or interrupt) completes.
+    fn run_instruction(cpu: &mut Cpu, bus: &mut Bus) {
+	step(cpu, bus);
+	while cpu.cycles > 0 {
+	    step(cpu, bus);
+	}
+    }
+
+    #[test]
+    fn test_brk_pushes_b_flag() {
+	let (mut cpu, mut bus) = ram_harness();
+	bus.write(0x0000, 0x00); // BRK
+	let vector = bus.read_u16(Cpu::IRQ_VECTOR);
+
+	run_instruction(&mut cpu, &mut bus);
+	assert_eq!(cpu.reg_pc, vector);
+	assert_eq!(cpu.total_cycles, 7);
+	assert!(cpu.flag_i);
+	// status, then return address skipping the padding byte
+	assert_eq!(bus.read(0x01fb) & 0x30, 0x30);
+	assert_eq!(bus.read(0x01fc), 0x02);
+	assert_eq!(bus.read(0x01fd), 0x00);
+    }
+
+    #[test]
+    fn test_nmi_hijacks_brk() {
+	let (mut cpu, mut bus) = ram_harness();
+	// BRK has been fetched when NMI is raised
+	cpu.reg_pc = 0x0001;
+	bus.interrupts_mut().set_nmi_line(true);
+	cpu.execute_interrupt(Interrupt::Brk, &mut bus);
+	assert_eq!(cpu.reg_pc, bus.read_u16(Cpu::NMI_VECTOR));
+	assert_eq!(bus.read(0x01fb) & 0x10, 0x10);
+	assert!(!bus.interrupts().nmi_pending());
+    }
+
+    #[test]
+    fn test_irq_delayed_after_cli() {
+	let (mut cpu, mut bus) = ram_harness();
+	bus.write(0x0000, 0x58); // CLI
+	bus.write(0x0001, 0xea); // NOP
+	bus.write(0x0002, 0xea); // NOP
+	bus.assert_irq(IrqSource::Mapper);
+
+	run_instruction(&mut cpu, &mut bus);
+	assert_eq!(cpu.reg_pc, 0x0001);
+	// the instruction after CLI runs before the IRQ is taken
+	run_instruction(&mut cpu, &mut bus);
+	assert_eq!(cpu.reg_pc, 0x0002);
+	run_instruction(&mut cpu, &mut bus);
+	assert_eq!(cpu.reg_pc, bus.read_u16(Cpu::IRQ_VECTOR));
+	assert_eq!(bus.read(0x01fb) & 0x10, 0x00);
+	assert_eq!(bus.read(0x01fc), 0x02);
+    }
+
+    #[test]
+    fn test_irq_masked_until_released() {
+	let (mut cpu, mut bus) = ram_harness();
+	bus.write(0x0000, 0x78); // SEI
+	bus.write(0x0001, 0xea); // NOP
+	bus.write(0x0002, 0x58); // CLI
+	bus.write(0x0003, 0xea); // NOP
+	bus.write(0x0004, 0xea); // NOP
+	bus.assert_irq(IrqSource::Mapper);
+	bus.assert_irq(IrqSource::Dmc);
+
+	// the line stays asserted but I holds it off
+	for pc in 1..=3 {
+	    run_instruction(&mut cpu, &mut bus);
+	    assert_eq!(cpu.reg_pc, pc);
+	}
+	// CLI's delay lets one more instruction through
+	run_instruction(&mut cpu, &mut bus);
+	assert_eq!(cpu.reg_pc, 0x0004);
+	run_instruction(&mut cpu, &mut bus);
+	assert_eq!(cpu.reg_pc, bus.read_u16(Cpu::IRQ_VECTOR));
+	assert_eq!(bus.read(0x01fc), 0x04);
+
+	// level triggered, held until every source lets go
+	bus.release_irq(IrqSource::Mapper);
+	assert!(bus.interrupts().irq());
+	bus.release_irq(IrqSource::Dmc);
+	assert!(!bus.interrupts().irq());
+    }
+
+    /// Every instruction performs one bus access per cycle, so the counted
+    /// cycles must match the table when no page is crossed.
+    #[test]
+    fn test_instruction_cycles() {
+	for variant in [Variant::Ricoh2A03, Variant::Nmos6502, Variant::Cmos65C02] {
+	    for opcode in 0..=0xffu8 {
+		let instruction = variant.opcodes()[(opcode >> 4) as usize][(opcode & 0xf) as usize];
+		if matches!(instruction.opcode, Op::KIL) || matches!(instruction.addr_mode, AM::REL) {
+		    continue;
+		}
+		let (mut cpu, mut bus) = ram_harness();
+		cpu.set_variant(variant);
+		bus.write(0x0000, opcode);
+		step(&mut cpu, &mut bus);
+		// step has already spent the first cycle
+		assert_eq!(cpu.cycles + 1, instruction.cycles as usize,
+			   "{:?} {:02X} {:?}", variant, opcode, instruction);
+	    }
+	}
+    }
+
+    #[test]
+    fn test_oam_dma() {
+	for cycle_stepped in [false, true] {
+	    let (mut cpu, mut bus) = ram_harness();
+	    cpu.set_cycle_stepped(cycle_stepped);
+	    for (i, b) in [0xa9, 0x02, 0x8d, 0x14, 0x40, 0x8d, 0x14, 0x40].iter().enumerate() {
+		bus.write(i as u16, *b); // LDA #$02; STA $4014; STA $4014
+	    }
+	    for i in 0..=0xff {
+		bus.write(0x0200 + i, i as u8);
+	    }
+	    run_instruction(&mut cpu, &mut bus);
+
+	    // one extra alignment cycle when the transfer starts on an odd cycle
+	    for _ in 0..2 {
+		let start = cpu.total_cycles;
+		run_instruction(&mut cpu, &mut bus);
+		let dma = 513 + (start + 4) % 2;
+		assert_eq!(cpu.total_cycles - start, 4 + dma);
+	    }
+	    assert!(bus.ppu().oam().iter().enumerate().all(|(i, b)| *b == i as u8));
+	}
+    }
+
+    /// A bus which starts a DMC fetch on its `dmc_at`th tick
+    struct DmcAt {
+	bus: Bus,
+	ticks: usize,
+	dmc_at: usize,
+    }
+
+    impl Memory for DmcAt {
+	fn read(&mut self, addr: u16) -> u8 {
+	    self.bus.read(addr)
+	}
+
+	fn write(&mut self, addr: u16, data: u8) {
+	    self.bus.write(addr, data);
+	}
+
+	fn tick(&mut self) -> Result<(), EmuErr> {
+	    self.ticks += 1;
+	    if self.ticks == self.dmc_at {
+		self.bus.dmc_fetch(0xc000);
+	    }
+	    self.bus.tick()
+	}
+
+	fn take_dma(&mut self) -> Option<Dma> {
+	    self.bus.take_dma()
+	}
+    }
+
+    /// Cycles the instruction at 0x0000 takes with a DMC fetch starting on
+    /// its `dmc_at`th cycle, after `LDA $00` (3 cycles) runs `skew` times
+    fn cycles_with_dmc(program: &[u8], skew: usize, dmc_at: usize) -> (usize, usize) {
+	let (mut cpu, bus) = ram_harness();
+	cpu.set_cycle_stepped(true);
+	let mut memory = DmcAt { bus, ticks: 0, dmc_at: 0 };
+	for _ in 0..skew {
+	    memory.write(0x0000, 0xa5); // LDA $00
+	    step(&mut cpu, &mut memory);
+	    cpu.reg_pc = 0x0000;
+	}
+	for (i, b) in program.iter().enumerate() {
+	    memory.write(i as u16, *b);
+	}
+	memory.ticks = 0;
+	memory.dmc_at = dmc_at;
+	let start = cpu.total_cycles;
+	step(&mut cpu, &mut memory);
+	(start, cpu.total_cycles - start)
+    }
+
+    #[test]
+    fn test_dmc_dma() {
+	// during an instruction the fetch waits for it to finish, then lines
+	// up with a read cycle
+	let mut nops = Vec::new();
+	for skew in 0..2 {
+	    let (start, cycles) = cycles_with_dmc(&[0xea], skew, 1); // NOP
+	    assert_eq!(cycles, 2 + 4 - (start + 2) % 2);
+	    nops.push(cycles);
+	}
+	nops.sort();
+	assert_eq!(nops, [5, 6]);
+
+	// during an OAM DMA it costs 2 cycles, 1 when only the last write of
+	// the OAM DMA is left
+	let sta = [0x8d, 0x14, 0x40]; // STA $4014
+	for skew in 0..2 {
+	    let (start, plain) = cycles_with_dmc(&sta, skew, 0);
+	    assert_eq!(plain, 4 + 513 + (start + 4) % 2);
+	    let (_, cycles) = cycles_with_dmc(&sta, skew, 100);
+	    assert_eq!(cycles, plain + 2);
+	    let (_, cycles) = cycles_with_dmc(&sta, skew, plain - 1);
+	    assert_eq!(cycles, plain + 1);
+	    // right at the end it's a fetch of its own
+	    let (_, cycles) = cycles_with_dmc(&sta, skew, plain);
+	    assert_eq!(cycles, plain + 4 - (start + plain) % 2);
+	}
+    }
+
+    #[test]
+    fn test_open_bus() {
+	let (mut cpu, mut bus) = ram_harness();
+	for (i, b) in [0xad, 0x00, 0x40, 0xad, 0x16, 0x40, 0xad, 0x00, 0x50].iter().enumerate() {
+	    bus.write(i as u16, *b); // LDA $4000; LDA $4016; LDA $5000
+	}
+
+	// the high byte of the operand is the last value on the bus
+	run_instruction(&mut cpu, &mut bus);
+	assert_eq!(cpu.reg_a, 0x40);
+	run_instruction(&mut cpu, &mut bus);
+	assert_eq!(cpu.reg_a & 0xe0, 0x40);
+	run_instruction(&mut cpu, &mut bus);
+	assert_eq!(cpu.reg_a, 0x50);
+    }
+
+    #[test]
+    fn test_cartridge_writes() {
+	let (mut cpu, mut bus) = ram_harness();
+	for (i, b) in [0xa9, 0x5a, 0x8d, 0x00, 0x60, 0x8d, 0x00, 0x80].iter().enumerate() {
+	    bus.write(i as u16, *b); // LDA #$5a; STA $6000; STA $8000
+	}
+	let rom = bus.read(0x8000);
+
+	for _ in 0..3 {
+	    run_instruction(&mut cpu, &mut bus);
+	}
+	assert_eq!(bus.read(0x6000), 0x5a);
+	assert_eq!(bus.read(0x8000), rom);
+	assert_eq!(bus.take_diagnostics(), vec![Diagnostic::UnmappedWrite { addr: 0x8000, data: 0x5a }]);
+	assert!(bus.take_diagnostics().is_empty());
+    }
+
+    #[test]
+    fn test_vblank_nmi_catches_up() {
+	let (_, mut bus) = ram_harness();
+	bus.write(0x2000, 0x80);
+
+	// vblank starts at dot 1 of scanline 241, 82182 dots in
+	let mut cycles = 0;
+	while !bus.interrupts().nmi_pending() {
+	    bus.step().unwrap();
+	    cycles += 1;
+	}
+	assert_eq!(cycles, 82182 / 3);
+	assert_eq!((bus.ppu().scanline(), bus.ppu().cycle()), (241, 1));
+	// the edge stays latched after the line drops
+	assert_eq!(bus.read(0x2002) & 0x80, 0x80);
+	assert!(!bus.ppu().nmi_line());
+	assert!(bus.interrupts().nmi_pending());
+    }
+
+    #[test]
+    fn test_nmi_once_per_vblank() {
+	let (mut cpu, mut bus) = ram_harness();
+	cpu.set_cycle_stepped(true);
+	for (i, b) in [0xa9, 0x80, 0x8d, 0x00, 0x20, 0x4c, 0x05, 0x00].iter().enumerate() {
+	    bus.write(i as u16, *b); // LDA #$80; STA $2000; JMP $0005
+	}
+	let sp = cpu.reg_sp;
+
+	// the nmi handler loops without reading $2002, vblank stays flagged
+	// until the pre-render scanline
+	while cpu.total_cycles < 29781 * 3 / 2 {
+	    step(&mut cpu, &mut bus);
+	}
+	assert_eq!(cpu.reg_sp, sp.wrapping_sub(3));
+    }
+
+    #[test]
+    fn test_peek_has_no_side_effects() {
+	let (_, mut bus) = ram_harness();
+	bus.write(0x0001, 0x34);
+	bus.write(0x2003, 0x10);
+	bus.write(0x2004, 0x99);
+	bus.write(0x2003, 0x10);
+
+	assert_eq!(bus.peek(0x2004), Some(0x99));
+	assert_eq!(bus.peek(0x0001), Some(0x34));
+	assert_eq!(bus.peek(0x5000), Some(0x10));
+	assert_eq!(bus.peek(0x2002), bus.peek(0x2002));
+	assert_eq!(bus.peek(0x4016), None);
+
+	// reads drive the bus, peeks don't
+	assert_eq!(bus.read(0x0001), 0x34);
+	assert_eq!(bus.peek(0x5000), Some(0x34));
+	assert_eq!(bus.read(0x2004), 0x99);
+	assert_eq!(bus.peek(0x5000), Some(0x99));
+    }
+
+    #[test]
+    fn test_four_screen_nametables() {
+	let mut bus = Bus::new();
+	let mut rom = assemble(".org $c000\nreset: JMP reset").unwrap().to_nrom().unwrap();
+	rom[6] |= 1 << 3;
+	bus.insert_cartridge(Cartridge::from_bytes(&rom).unwrap());
+	let seek = |bus: &mut Bus, addr: u16| {
+	    bus.write(0x2006, (addr >> 8) as u8);
+	    bus.write(0x2006, addr as u8);
+	};
+	for (i, addr) in [0x2000, 0x2400, 0x2800, 0x2c00].into_iter().enumerate() {
+	    seek(&mut bus, addr);
+	    bus.write(0x2007, i as u8 + 1);
+	}
+	for (i, addr) in [0x2000, 0x2400, 0x2800, 0x2c00].into_iter().enumerate() {
+	    seek(&mut bus, addr);
+	    bus.read(0x2007);
+	    assert_eq!(bus.read(0x2007), i as u8 + 1, "${:04X}", addr);
+	}
+    }
+
+    /// Runs the nestest automated suite and prints the throughput of the
+    /// fastest of a few runs. `clock` is called for every idle cycle in
+    /// legacy mode.
+    fn bench_nestest_on<M: Memory>(label: &str, cpu: &mut Cpu, memory: &mut M, mut clock: impl FnMut(&mut M)) {
+	const RUNS: usize = 5;
+	const PASSES: usize = 200;
+	const INSTRUCTIONS: usize = 8991; // lines in nestest.log
+	let mut best: Option<(f64, usize)> = None;
+	for _ in 0..RUNS {
+	    let start = std::time::Instant::now();
+	    let cycles = cpu.total_cycles;
+	    for _ in 0..PASSES {
+		cpu.reg_pc = 0xc000;
+		cpu.reg_sp = Cpu::INITIAL_SP;
+		for _ in 0..INSTRUCTIONS {
+		    step(cpu, memory);
+		    if !cpu.cycle_stepped() {
+			clock(memory);
+		    }
+		    while cpu.cycles > 0 {
+			step(cpu, memory);
+			clock(memory);
+		    }
+		}
+	    }
+	    let elapsed = start.elapsed().as_secs_f64();
+	    if best.is_none_or(|(fastest, _)| elapsed < fastest) {
+		best = Some((elapsed, cpu.total_cycles - cycles));
+	    }
+	}
+	let (elapsed, cycles) = best.unwrap();
+	let instructions = (PASSES * INSTRUCTIONS) as f64;
+	let mhz = cycles as f64 / elapsed / 1e6;
+	println!("{:<24} {:6.2} M instructions/s {:7.2} MHz ({:.1}x NTSC)",
+		 label, instructions / elapsed / 1e6, mhz, mhz / 1.789773);
+    }
+
+    /// Headless throughput, see BENCHMARKS.md. Run with
+    /// `cargo test --release bench_nestest -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_nestest() {
+	let rom = std::fs::read("testrom.nes").unwrap();
+	for cycle_stepped in [false, true] {
+	    let mut ram = Ram::new();
+	    ram.load(0xc000, &rom[16..16 + 0x4000]);
+	    let mut cpu = Cpu::new();
+	    cpu.set_cycle_stepped(cycle_stepped);
+	    let label = if cycle_stepped { "flat ram, cycle stepped" } else { "flat ram" };
+	    bench_nestest_on(label, &mut cpu, &mut ram, |_| ());
+
+	    let (mut cpu, mut bus) = ram_harness();
+	    cpu.set_cycle_stepped(cycle_stepped);
+	    let label = if cycle_stepped { "bus, cycle stepped" } else { "bus" };
+	    bench_nestest_on(label, &mut cpu, &mut bus, |bus| bus.step().unwrap());
+	}
+    }
+
+    /// Runs the instruction at 0x0000 and returns the cpu for inspection.
+    fn run_program(program: &[u8], setup: impl FnOnce(&mut Cpu)) -> (Cpu, Bus) {
+	let (mut cpu, mut bus) = ram_harness();
+	for (i, b) in program.iter().enumerate() {
+	    bus.write(i as u16, *b);
+	}
+	setup(&mut cpu);
+	run_instruction(&mut cpu, &mut bus);
+	(cpu, bus)
+    }
+
+    #[test]
+    fn test_anc_alr_arr_axs() {
+	// ANC #$80
+	let (cpu, _) = run_program(&[0x0b, 0x80], |cpu| cpu.reg_a = 0xff);
+	assert_eq!(cpu.reg_a, 0x80);
+	assert!(cpu.flag_c && cpu.flag_n);
+
+	// ALR #$03
+	let (cpu, _) = run_program(&[0x4b, 0x03], |cpu| cpu.reg_a = 0xff);
+	assert_eq!(cpu.reg_a, 0x01);
+	assert!(cpu.flag_c && !cpu.flag_z);
+
+	// ARR #$ff with carry in
+	let (cpu, _) = run_program(&[0x6b, 0xff], |cpu| { cpu.reg_a = 0x40; cpu.flag_c = true; });
+	assert_eq!(cpu.reg_a, 0xa0);
+	assert!(!cpu.flag_c && cpu.flag_v && cpu.flag_n);
+
+	// AXS #$02
+	let (cpu, _) = run_program(&[0xcb, 0x02], |cpu| { cpu.reg_a = 0x0f; cpu.reg_x = 0x3c; });
+	assert_eq!(cpu.reg_x, 0x0a);
+	assert!(cpu.flag_c);
+    }
+
+    #[test]
+    fn test_decimal_mode() {
+	let decimal = |variant| move |cpu: &mut Cpu| { cpu.set_variant(variant); cpu.flag_d = true; };
+
+	// ADC #$27 with A = $15
+	let (cpu, _) = run_program(&[0x69, 0x27], |cpu| { decimal(Variant::Nmos6502)(cpu); cpu.reg_a = 0x15; });
+	assert_eq!(cpu.reg_a, 0x42);
+	assert!(!cpu.flag_c);
+
+	// the 2A03 ignores D
+	let (cpu, _) = run_program(&[0x69, 0x27], |cpu| { decimal(Variant::Ricoh2A03)(cpu); cpu.reg_a = 0x15; });
+	assert_eq!(cpu.reg_a, 0x3c);
+
+	// ADC #$01 with A = $99: NMOS takes Z from the binary sum
+	let (cpu, _) = run_program(&[0x69, 0x01], |cpu| { decimal(Variant::Nmos6502)(cpu); cpu.reg_a = 0x99; });
+	assert_eq!(cpu.reg_a, 0x00);
+	assert!(cpu.flag_c && !cpu.flag_z && cpu.flag_n);
+	let (cpu, _) = run_program(&[0x69, 0x01], |cpu| { decimal(Variant::Cmos65C02)(cpu); cpu.reg_a = 0x99; });
+	assert_eq!(cpu.reg_a, 0x00);
+	assert!(cpu.flag_c && cpu.flag_z && !cpu.flag_n);
+	// the 65C02 spends a cycle fixing up the flags
+	assert_eq!(cpu.total_cycles, 3);
+
+	// SBC #$15 with A = $42 and no borrow
+	let (cpu, _) = run_program(&[0xe9, 0x15], |cpu| { decimal(Variant::Nmos6502)(cpu); cpu.reg_a = 0x42; cpu.flag_c = true; });
+	assert_eq!(cpu.reg_a, 0x27);
+	assert!(cpu.flag_c);
+
+	// SBC #$01 with A = $00 borrows
+	let (cpu, _) = run_program(&[0xe9, 0x01], |cpu| { decimal(Variant::Cmos65C02)(cpu); cpu.flag_c = true; });
+	assert_eq!(cpu.reg_a, 0x99);
+	assert!(!cpu.flag_c && cpu.flag_n);
+    }
+
+    #[test]
+    fn test_65c02_opcodes() {
+	let cmos = |cpu: &mut Cpu| cpu.set_variant(Variant::Cmos65C02);
+
+	// STZ $10
+	let (_, mut bus) = run_program(&[0x64, 0x10], |cpu| { cmos(cpu); });
+	assert_eq!(bus.read(0x0010), 0x00);
+
+	// TSB $02 / TRB $02 on the operand byte itself
+	let (cpu, mut bus) = run_program(&[0x04, 0x02, 0x0c], |cpu| { cmos(cpu); cpu.reg_a = 0x03; });
+	assert_eq!(bus.read(0x0002), 0x0f);
+	assert!(cpu.flag_z);
+	let (cpu, mut bus) = run_program(&[0x14, 0x02, 0x0c], |cpu| { cmos(cpu); cpu.reg_a = 0x04; });
+	assert_eq!(bus.read(0x0002), 0x08);
+	assert!(!cpu.flag_z);
+
+	// LDA ($02)
+	let (cpu, _) = run_program(&[0xb2, 0x02, 0x00, 0x00], |cpu| { cmos(cpu); });
+	assert_eq!(cpu.reg_a, 0xb2);
+
+	// BRA +4
+	let (cpu, _) = run_program(&[0x80, 0x04], |cpu| { cmos(cpu); });
+	assert_eq!(cpu.reg_pc, 0x0006);
+
+	// INC A
+	let (cpu, _) = run_program(&[0x1a], |cpu| { cmos(cpu); cpu.reg_a = 0xff; });
+	assert_eq!(cpu.reg_a, 0x00);
+	assert!(cpu.flag_z);
+
+	// PHX then PLY
+	let (mut cpu, mut bus) = ram_harness();
+	cmos(&mut cpu);
+	bus.write(0x0000, 0xda);
+	bus.write(0x0001, 0x7a);
+	cpu.reg_x = 0x80;
+	run_instruction(&mut cpu, &mut bus);
+	run_instruction(&mut cpu, &mut bus);
+	assert_eq!(cpu.reg_y, 0x80);
+	assert!(cpu.flag_n);
+
+	// JMP ($04FF,X) then JMP ($02FF): the page wrap bug is fixed
+	let (mut cpu, mut bus) = ram_harness();
+	cmos(&mut cpu);
+	for (addr, b) in [(0x0000, 0x7c), (0x0001, 0xff), (0x0002, 0x04),
+			  (0x0200, 0x6c), (0x0201, 0xff), (0x0202, 0x02),
+			  (0x02ff, 0x34), (0x0300, 0x12), (0x0500, 0x00), (0x0501, 0x02)] {
+	    bus.write(addr, b);
+	}
+	cpu.reg_x = 0x01;
+	run_instruction(&mut cpu, &mut bus);
+	assert_eq!(cpu.reg_pc, 0x0200);
+	run_instruction(&mut cpu, &mut bus);
+	assert_eq!(cpu.reg_pc, 0x1234);
+    }
+
+    #[test]
+    fn test_magic_constant() {
+	// XAA #$ff
+	let (cpu, _) = run_program(&[0x8b, 0xff], |cpu| { cpu.reg_a = 0x00; cpu.reg_x = 0x3f; });
+	assert_eq!(cpu.reg_a, 0x2e);
+
+	// LAX #$f0 with magic 0xff behaves like a plain load
+	let (cpu, _) = run_program(&[0xab, 0xf0], |cpu| cpu.set_magic(0xff));
+	assert_eq!(cpu.reg_a, 0xf0);
+	assert_eq!(cpu.reg_x, 0xf0);
+    }
+
+    #[test]
+    fn test_las() {
+	// LAS $0010,Y
+	let (cpu, _) = run_program(&[0xbb, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+				     0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+				     0x00, 0xf3], |cpu| cpu.reg_y = 0x01);
+	assert_eq!(cpu.reg_a, 0xf1);
+	assert_eq!(cpu.reg_x, 0xf1);
+	assert_eq!(cpu.reg_sp, 0xf1);
+    }
+
+    #[test]
+    fn test_store_and_high_byte() {
+	// SHX $0110,Y stores X & 0x02
+	let (_, mut bus) = run_program(&[0x9e, 0x10, 0x01], |cpu| { cpu.reg_x = 0xff; cpu.reg_y = 0x01; });
+	assert_eq!(bus.read(0x0111), 0x02);
+
+	// SHY $02f0,X crosses a page: the value replaces the high byte
+	let (_, mut bus) = run_program(&[0x9c, 0xf0, 0x02], |cpu| { cpu.reg_y = 0x05; cpu.reg_x = 0x20; });
+	assert_eq!(bus.read(0x0110), 0x01);
+	assert_eq!(bus.read(0x0310), 0x00);
+
+	// TAS $0100,Y sets SP to A & X
+	let (cpu, mut bus) = run_program(&[0x9b, 0x00, 0x01], |cpu| { cpu.reg_a = 0xf7; cpu.reg_x = 0x7f; });
+	assert_eq!(cpu.reg_sp, 0x77);
+	assert_eq!(bus.read(0x0100), 0x02);
+
+	// AHX ($20),Y
+	let (_, mut bus) = run_program(&[0x93, 0x20], |cpu| { cpu.reg_a = 0xff; cpu.reg_x = 0xff; cpu.reg_y = 0x00; });
+	assert_eq!(bus.read(0x0000), 0x01);
+    }
+
+    #[test]
+    fn test_jam_halts_until_reset() {
+	let (mut cpu, mut bus) = ram_harness();
+	bus.write(0x0000, 0xea); // NOP
+	bus.write(0x0001, 0x02); // KIL
+	cpu.reg_a = 0x42;
+	run_instruction(&mut cpu, &mut bus);
+
+	let halt = cpu.step(&mut bus).unwrap().unwrap();
+	assert_eq!(halt.reason, HaltReason::Jam);
+	assert_eq!(halt.opcode, 0x02);
+	assert_eq!(halt.pc, 0x0001);
+	assert_eq!(halt.registers.a, 0x42);
+	assert_eq!(cpu.step(&mut bus).unwrap(), Some(halt));
+
+	// restoring a state from before the jam runs again
+	let mut state = cpu.state();
+	state.pc = 0x0000;
+	cpu.set_state(&state);
+	step(&mut cpu, &mut bus);
+	assert_eq!(cpu.reg_pc, 0x0001);
+
+	cpu.reset(&mut bus);
+	cpu.cycles = 0;
+	cpu.reg_pc = 0x0000;
+	step(&mut cpu, &mut bus);
+    }
+
+    #[test]
+    fn test_breakpoint() {
+	let (mut cpu, mut bus) = ram_harness();
+	bus.write(0x0000, 0xe8); // INX
+	bus.write(0x0001, 0xe8); // INX
+	cpu.add_breakpoint(0x0001);
+	run_instruction(&mut cpu, &mut bus);
+	bus.write(0x0010, 0x77);
+
+	let halt = cpu.step(&mut bus).unwrap().unwrap();
+	assert_eq!(halt.reason, HaltReason::Breakpoint);
+	assert_eq!(halt.opcode, 0xe8);
+	assert_eq!(halt.pc, 0x0001);
+	assert_eq!(cpu.reg_x, 1);
+	// the opcode is peeked, the open bus still holds the last write
+	assert_eq!(bus.peek(0x5000), Some(0x77));
+
+	// stepping again resumes from the breakpoint
+	run_instruction(&mut cpu, &mut bus);
+	assert_eq!(cpu.reg_x, 2);
+    }
+
+    #[test]
+    fn test_cpu_state() {
+	let (cpu, mut bus) = run_program(&[0xa9, 0x80], |cpu| {
+	    cpu.set_x(0x12);
+	    cpu.set_flags(Flags { decimal: true, ..cpu.flags() });
+	    cpu.interrupt(Interrupt::Nmi);
+	});
+	// the requested NMI runs first
+	let state = cpu.state();
+	assert_eq!(state.pc, bus.read_u16(Cpu::NMI_VECTOR));
+	assert_eq!(state.x, 0x12);
+	assert!(state.flags.decimal && state.flags.interrupt_disable);
+	assert_eq!(state.interrupts.requested, None);
+	assert_eq!(state.cycles, 7);
+
+	let mut state = state;
+	state.pc = 0x0000;
+	state.interrupts.requested = Some(Interrupt::Irq);
+	let (mut cpu, mut bus) = ram_harness();
+	bus.write(0x0000, 0xa9); // LDA #$80
+	bus.write(0x0001, 0x80);
+	cpu.set_state(&state);
+	assert_eq!(cpu.state(), state);
+	assert_eq!(cpu.p(), 0x2c);
+
+	// I is set, so the requested IRQ waits
+	run_instruction(&mut cpu, &mut bus);
+	assert_eq!(cpu.a(), 0x80);
+	assert!(cpu.flags().negative);
+	assert!(matches!(cpu.last_instruction(), Some(I { opcode: Op::LDA, addr_mode: AM::IMM, .. })));
+	assert_eq!(cpu.state().last_opcode, Some(0xa9));
+
+	let state = cpu.state();
+	let bytes = state.to_bytes();
+	assert_eq!(CpuState::from_bytes(&bytes).unwrap(), state);
+	assert_eq!(&bytes[..8], &[CpuState::VERSION, 0x02, 0x00, 0xfa, 0x80, 0x12, 0x00, 0xac]);
+	assert!(CpuState::from_bytes(&bytes[1..]).is_err());
+	let mut bad = bytes;
+	bad[18] = 4;
+	assert!(CpuState::from_bytes(&bad).is_err());
+    }
+
+    #[test]
+    fn test_cpu_state_during_oam_dma() {
+	let (mut cpu, mut bus) = ram_harness();
+	for (i, b) in [0xa9, 0x02, 0x8d, 0x14, 0x40].iter().enumerate() {
+	    bus.write(i as u16, *b); // LDA #$02; STA $4014
+	}
+	run_instruction(&mut cpu, &mut bus);
+	let start = cpu.total_cycles;
+	step(&mut cpu, &mut bus);
+
+	// the rest of the store and the whole transfer are still ahead
+	let state = cpu.state();
+	assert_eq!(state.stall as usize, 3 + 513 + (start + 4) % 2);
+	let restored = CpuState::from_bytes(&state.to_bytes()).unwrap();
+	assert_eq!(restored, state);
+
+	let (mut other, _) = ram_harness();
+	other.set_state(&restored);
+	run_instruction(&mut cpu, &mut bus);
+	run_instruction(&mut other, &mut bus);
+	assert_eq!(other.total_cycles, cpu.total_cycles);
+    }
+
+    /// Flat ram which records every bus access as (address, data, is write),
+    /// also used by the single step tests
+    pub(super) struct Recorder {
+	pub(super) ram: Ram,
+	pub(super) accesses: Vec<(u16, u8, bool)>,
+    }
+
+    impl Memory for Recorder {
+	fn read(&mut self, addr: u16) -> u8 {
+	    let data = self.ram.read(addr);
+	    self.accesses.push((addr, data, false));
+	    data
+	}
+
+	fn write(&mut self, addr: u16, data: u8) {
+	    self.ram.write(addr, data);
+	    self.accesses.push((addr, data, true));
+	}
+    }
+
+    /// Resets a cpu on flat ram holding `program` at 0x0400.
+    fn ram_cpu<M: Memory>(memory: &mut M, program: &[u8]) -> Cpu {
+	let mut cpu = Cpu::new();
+	for (i, b) in program.iter().enumerate() {
+	    memory.write(0x0400 + i as u16, *b);
+	}
+	memory.write(Cpu::RESET_VECTOR, 0x00);
+	memory.write(Cpu::RESET_VECTOR + 1, 0x04);
+	cpu.power_on();
+	cpu.set_cycle_stepped(true);
+	cpu.reset(memory);
+	cpu
+    }
+
+    #[test]
+    fn test_flat_ram() {
+	let mut ram = Ram::new();
+	let mut cpu = ram_cpu(&mut ram, &[
+	    0xa2, 0x0a, // LDX #$0A
+	    0xa9, 0x00, // LDA #$00
+	    0x18,       // CLC
+	    0x86, 0x00, // loop: STX $00
+	    0x65, 0x00, // ADC $00
+	    0xca,       // DEX
+	    0xd0, 0xf9, // BNE loop
+	    0x02,       // KIL
+	]);
+
+	let halt = loop {
+	    if let Some(halt) = cpu.step(&mut ram).unwrap() {
+		break halt;
+	    }
+	};
+	assert_eq!(halt.reason, HaltReason::Jam);
+	assert_eq!(halt.pc, 0x040c);
+	assert_eq!(halt.registers.a, 55);
+    }
+
+    #[test]
+    fn test_dummy_accesses() {
+	let mut memory = Recorder { ram: Ram::new(), accesses: Vec::new() };
+	let mut cpu = ram_cpu(&mut memory, &[
+	    0xe6, 0x10,       // INC $10
+	    0xbd, 0xff, 0x04, // LDA $04FF,X
+	]);
+	memory.ram.write(0x0010, 0x05);
+	cpu.reg_x = 0x01;
+
+	memory.accesses.clear();
+	step(&mut cpu, &mut memory);
+	// read-modify-write writes the old value back before the new one
+	assert_eq!(memory.accesses, vec![
+	    (0x0400, 0xe6, false),
+	    (0x0401, 0x10, false),
+	    (0x0010, 0x05, false),
+	    (0x0010, 0x05, true),
+	    (0x0010, 0x06, true),
+	]);
+
+	memory.accesses.clear();
+	step(&mut cpu, &mut memory);
+	// the indexed read crosses a page, so the un-carried address is read first
+	assert_eq!(memory.accesses, vec![
+	    (0x0402, 0xbd, false),
+	    (0x0403, 0xff, false),
+	    (0x0404, 0x04, false),
+	    (0x0400, 0xe6, false),
+	    (0x0500, 0x00, false),
+	]);
+    }
 }
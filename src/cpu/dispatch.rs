@@ -0,0 +1,319 @@
+use std::marker::PhantomData;
+use super::{Cpu, HaltReason, Interrupt};
+use crate::memory::Memory;
+use crate::opcodes::{I, AM, Op, OPCODES, OPCODES_65C02, Variant};
+
+/// Executes one instruction, its opcode has already been fetched. Returns
+/// the reason when the instruction stops the cpu.
+pub type Handler<M> = fn(&mut Cpu, &mut M) -> Option<HaltReason>;
+
+/// Opcode dispatch
+///
+/// Each variant's opcode table is turned into 256 handlers at compile time,
+/// once for every `Memory` the cpu is used with. A handler pairs the
+/// addressing mode resolver with the operation, so executing an instruction
+/// is a single indexed call instead of a match over every opcode and
+/// addressing mode.
+pub struct Dispatch<M>(PhantomData<M>);
+
+impl<M: Memory> Dispatch<M> {
+    // The 2A03 and NMOS 6502 decode identically, decimal mode is checked
+    // by ADC and SBC.
+    const NMOS: [Handler<M>; 256] = handlers(&OPCODES);
+    const CMOS: [Handler<M>; 256] = handlers(&OPCODES_65C02);
+
+    pub fn handler(variant: Variant, opcode: u8) -> Handler<M> {
+	if variant.is_cmos() {
+	    Self::CMOS[opcode as usize]
+	} else {
+	    Self::NMOS[opcode as usize]
+	}
+    }
+}
+
+const fn handlers<M: Memory>(opcodes: &[[I; 16]; 16]) -> [Handler<M>; 256] {
+    let mut table = [unknown::<M> as Handler<M>; 256];
+    let mut opcode = 0;
+    while opcode < 256 {
+	table[opcode] = handler::<M>(opcodes[opcode >> 4][opcode & 0xf]);
+	opcode += 1;
+    }
+    table
+}
+
+fn unknown<M: Memory>(_: &mut Cpu, _: &mut M) -> Option<HaltReason> {
+    Some(HaltReason::Error)
+}
+
+/// Resolves the operand location, then runs the operation on it.
+macro_rules! op {
+    ($resolve:ident, $operation:ident) => {
+	|cpu: &mut Cpu, bus: &mut M| {
+	    let location = cpu.$resolve(bus);
+	    cpu.$operation(location, bus);
+	    None
+	}
+    };
+}
+
+/// Handler for an operation taking its operand from memory, in any of the
+/// addressing modes. The opcode tables decide which are actually used.
+macro_rules! with_mode {
+    ($mode:expr, $operation:ident) => {
+	match $mode {
+	    AM::IMM => op!(immediate, $operation),
+	    AM::ZPG => op!(zero_page, $operation),
+	    AM::ZPX => op!(zero_page_x, $operation),
+	    AM::ZPY => op!(zero_page_y, $operation),
+	    AM::ABS => op!(absolute, $operation),
+	    AM::ABX => op!(absolute_x, $operation),
+	    AM::ABY => op!(absolute_y, $operation),
+	    AM::INX => op!(indexed_indirect, $operation),
+	    AM::INY => op!(indirect_indexed, $operation),
+	    AM::ZPI => op!(indirect, $operation),
+	    AM::IMP | AM::REL | AM::IND | AM::IAX => unknown::<M>,
+	}
+    };
+}
+
+/// Handler for an instruction with no operand
+macro_rules! implied {
+    (|$cpu:ident, $bus:ident| $body:expr) => {
+	|$cpu: &mut Cpu, $bus: &mut M| {
+	    $body;
+	    None
+	}
+    };
+}
+
+const fn handler<M: Memory>(instruction: I) -> Handler<M> {
+    match (instruction.opcode, instruction.addr_mode) {
+	/* logical and arithmetic instructions */
+	(Op::ORA, mode) => with_mode!(mode, ora),
+	(Op::AND, mode) => with_mode!(mode, and),
+	(Op::EOR, mode) => with_mode!(mode, eor),
+	(Op::ADC, mode) => with_mode!(mode, adc),
+	(Op::SBC, mode) => with_mode!(mode, sbc),
+	(Op::CMP, mode) => with_mode!(mode, compare_a),
+	(Op::CPX, mode) => with_mode!(mode, compare_x),
+	(Op::CPY, mode) => with_mode!(mode, compare_y),
+	(Op::BIT, AM::IMM) => op!(immediate, bit_imm),
+	(Op::BIT, mode) => with_mode!(mode, bit),
+
+	(Op::DEC, AM::IMP) => implied!(|cpu, _bus| {
+	    cpu.reg_a = cpu.reg_a.wrapping_sub(1);
+	    cpu.set_zn(cpu.reg_a)
+	}),
+	(Op::DEC, mode) => with_mode!(mode, dec),
+	(Op::DEX, _) => implied!(|cpu, _bus| {
+	    cpu.reg_x = cpu.reg_x.wrapping_sub(1);
+	    cpu.set_zn(cpu.reg_x)
+	}),
+	(Op::DEY, _) => implied!(|cpu, _bus| {
+	    cpu.reg_y = cpu.reg_y.wrapping_sub(1);
+	    cpu.set_zn(cpu.reg_y)
+	}),
+	(Op::INC, AM::IMP) => implied!(|cpu, _bus| {
+	    cpu.reg_a = cpu.reg_a.wrapping_add(1);
+	    cpu.set_zn(cpu.reg_a)
+	}),
+	(Op::INC, mode) => with_mode!(mode, inc),
+	(Op::INX, _) => implied!(|cpu, _bus| {
+	    cpu.reg_x = cpu.reg_x.wrapping_add(1);
+	    cpu.set_zn(cpu.reg_x)
+	}),
+	(Op::INY, _) => implied!(|cpu, _bus| {
+	    cpu.reg_y = cpu.reg_y.wrapping_add(1);
+	    cpu.set_zn(cpu.reg_y)
+	}),
+
+	(Op::ASL, AM::IMP) => implied!(|cpu, _bus| cpu.asl_acc()),
+	(Op::ASL, mode) => with_mode!(mode, asl),
+	(Op::ROL, AM::IMP) => implied!(|cpu, _bus| cpu.rol_acc()),
+	(Op::ROL, mode) => with_mode!(mode, rol),
+	(Op::LSR, AM::IMP) => implied!(|cpu, _bus| cpu.lsr_acc()),
+	(Op::LSR, mode) => with_mode!(mode, lsr),
+	(Op::ROR, AM::IMP) => implied!(|cpu, _bus| cpu.ror_acc()),
+	(Op::ROR, mode) => with_mode!(mode, ror),
+
+	/* move instructions */
+	(Op::LDA, mode) => with_mode!(mode, lda),
+	(Op::STA, mode) => with_mode!(mode, sta),
+	(Op::LDX, mode) => with_mode!(mode, ldx),
+	(Op::STX, mode) => with_mode!(mode, stx),
+	(Op::LDY, mode) => with_mode!(mode, ldy),
+	(Op::STY, mode) => with_mode!(mode, sty),
+
+	(Op::TAX, _) => implied!(|cpu, _bus| {
+	    cpu.reg_x = cpu.reg_a;
+	    cpu.set_zn(cpu.reg_x)
+	}),
+	(Op::TXA, _) => implied!(|cpu, _bus| {
+	    cpu.reg_a = cpu.reg_x;
+	    cpu.set_zn(cpu.reg_a)
+	}),
+	(Op::TAY, _) => implied!(|cpu, _bus| {
+	    cpu.reg_y = cpu.reg_a;
+	    cpu.set_zn(cpu.reg_y)
+	}),
+	(Op::TYA, _) => implied!(|cpu, _bus| {
+	    cpu.reg_a = cpu.reg_y;
+	    cpu.set_zn(cpu.reg_a)
+	}),
+	(Op::TSX, _) => implied!(|cpu, _bus| {
+	    cpu.reg_x = cpu.reg_sp;
+	    cpu.set_zn(cpu.reg_x)
+	}),
+	(Op::TXS, _) => implied!(|cpu, _bus| cpu.reg_sp = cpu.reg_x),
+
+	(Op::PLA, _) => implied!(|cpu, bus| {
+	    cpu.peek_stack(bus);
+	    cpu.reg_a = cpu.pull(bus);
+	    cpu.set_zn(cpu.reg_a)
+	}),
+	(Op::PHA, _) => implied!(|cpu, bus| cpu.push(cpu.reg_a, bus)),
+	// See: https://www.nesdev.org/wiki/Status_flags#The_B_flag
+	// for PLP / PHP details.
+	(Op::PLP, _) => implied!(|cpu, bus| {
+	    cpu.peek_stack(bus);
+	    cpu.reg_p = cpu.pull(bus);
+	    cpu.split_flags()
+	}),
+	(Op::PHP, _) => implied!(|cpu, bus| {
+	    cpu.join_flags();
+	    cpu.push(cpu.reg_p | 0x10, bus)
+	}),
+
+	/* jump/flag instructions */
+	(Op::BPL, _) => implied!(|cpu, bus| cpu.execute_cond_branch(!cpu.flag_n, bus)),
+	(Op::BMI, _) => implied!(|cpu, bus| cpu.execute_cond_branch(cpu.flag_n, bus)),
+	(Op::BVC, _) => implied!(|cpu, bus| cpu.execute_cond_branch(!cpu.flag_v, bus)),
+	(Op::BVS, _) => implied!(|cpu, bus| cpu.execute_cond_branch(cpu.flag_v, bus)),
+	(Op::BCC, _) => implied!(|cpu, bus| cpu.execute_cond_branch(!cpu.flag_c, bus)),
+	(Op::BCS, _) => implied!(|cpu, bus| cpu.execute_cond_branch(cpu.flag_c, bus)),
+	(Op::BNE, _) => implied!(|cpu, bus| cpu.execute_cond_branch(!cpu.flag_z, bus)),
+	(Op::BEQ, _) => implied!(|cpu, bus| cpu.execute_cond_branch(cpu.flag_z, bus)),
+
+	(Op::BRK, _) => implied!(|cpu, bus| cpu.execute_interrupt(Interrupt::Brk, bus)),
+	(Op::RTI, _) => implied!(|cpu, bus| {
+	    cpu.peek_stack(bus);
+	    cpu.reg_p = cpu.pull(bus);
+	    cpu.split_flags();
+	    let pc_lo = cpu.pull(bus) as u16;
+	    let pc_hi = cpu.pull(bus) as u16;
+	    cpu.reg_pc = pc_hi << 8 | pc_lo
+	}),
+	(Op::JSR, _) => implied!(|cpu, bus| {
+	    // The return address pushed is the last byte of JSR, which
+	    // is read after the pushes.
+	    let lo = cpu.fetch(bus) as u16;
+	    cpu.peek_stack(bus);
+	    cpu.push((cpu.reg_pc >> 8) as u8, bus);
+	    cpu.push(cpu.reg_pc as u8, bus);
+	    let hi = cpu.read(bus, cpu.reg_pc) as u16;
+	    cpu.reg_pc = (hi << 8) | lo
+	}),
+	(Op::RTS, _) => implied!(|cpu, bus| {
+	    cpu.peek_stack(bus);
+	    let pc_lo = cpu.pull(bus) as u16;
+	    let pc_hi = cpu.pull(bus) as u16;
+	    cpu.reg_pc = (pc_hi << 8) | pc_lo;
+	    cpu.fetch(bus)
+	}),
+
+	(Op::JMP, AM::ABS) => implied!(|cpu, bus| cpu.reg_pc = cpu.absolute(bus)),
+	(Op::JMP, AM::IND) => implied!(|cpu, bus| cpu.jmp_indirect(bus)),
+	(Op::JMP, AM::IAX) => implied!(|cpu, bus| {
+	    let base = cpu.absolute(bus);
+	    cpu.read(bus, cpu.reg_pc.wrapping_sub(1));
+	    let location = base.wrapping_add(cpu.reg_x as u16);
+	    cpu.reg_pc = cpu.read_vector(location, bus)
+	}),
+
+	(Op::CLC, _) => implied!(|cpu, _bus| cpu.flag_c = false),
+	(Op::SEC, _) => implied!(|cpu, _bus| cpu.flag_c = true),
+	/*
+	NOTE: CLD & SED have no actual affect on the NES. The 2A03 has decimal mode
+	disconnected, but the flag is still changed in P. The other variants honour it
+	in ADC and SBC.
+	 */
+	(Op::CLD, _) => implied!(|cpu, _bus| cpu.flag_d = false),
+	(Op::SED, _) => implied!(|cpu, _bus| cpu.flag_d = true),
+	(Op::CLI, _) => implied!(|cpu, _bus| cpu.flag_i = false),
+	(Op::SEI, _) => implied!(|cpu, _bus| cpu.flag_i = true),
+	(Op::CLV, _) => implied!(|cpu, _bus| cpu.flag_v = false),
+
+	(Op::NOP, AM::IMP) => |_, _| None,
+	// 65C02 0x5C
+	(Op::NOP, AM::ABS) if instruction.cycles == 8 => implied!(|cpu, bus| {
+	    let location = cpu.absolute(bus);
+	    cpu.read(bus, 0xff00 | (location & 0xff));
+	    for _ in 0..4 {
+		cpu.read(bus, 0xffff);
+	    }
+	}),
+	(Op::NOP, mode) => with_mode!(mode, nop),
+
+	/* unofficial opcodes */
+	(Op::LAX, AM::IMM) => op!(immediate, lax_imm),
+	(Op::LAX, mode) => with_mode!(mode, lax),
+	(Op::SAX, mode) => with_mode!(mode, sax),
+	(Op::DCP, mode) => with_mode!(mode, dcp),
+	(Op::ISC, mode) => with_mode!(mode, isc),
+	(Op::SLO, mode) => with_mode!(mode, slo),
+	(Op::RLA, mode) => with_mode!(mode, rla),
+	(Op::SRE, mode) => with_mode!(mode, sre),
+	(Op::RRA, mode) => with_mode!(mode, rra),
+	(Op::ANC, mode) => with_mode!(mode, anc),
+	(Op::ALR, mode) => with_mode!(mode, alr),
+	(Op::ARR, mode) => with_mode!(mode, arr),
+	(Op::AXS, mode) => with_mode!(mode, axs),
+	(Op::XAA, mode) => with_mode!(mode, xaa),
+	(Op::LAS, mode) => with_mode!(mode, las),
+
+	(Op::TAS, _) => implied!(|cpu, bus| {
+	    cpu.reg_sp = cpu.reg_a & cpu.reg_x;
+	    let base = cpu.absolute(bus);
+	    cpu.store_and_high(base, cpu.reg_y, cpu.reg_sp, bus)
+	}),
+	(Op::SHY, _) => implied!(|cpu, bus| {
+	    let base = cpu.absolute(bus);
+	    cpu.store_and_high(base, cpu.reg_x, cpu.reg_y, bus)
+	}),
+	(Op::SHX, _) => implied!(|cpu, bus| {
+	    let base = cpu.absolute(bus);
+	    cpu.store_and_high(base, cpu.reg_y, cpu.reg_x, bus)
+	}),
+	(Op::AHX, AM::ABY) => implied!(|cpu, bus| {
+	    let base = cpu.absolute(bus);
+	    cpu.store_and_high(base, cpu.reg_y, cpu.reg_a & cpu.reg_x, bus)
+	}),
+	(Op::AHX, AM::INY) => implied!(|cpu, bus| {
+	    let base = cpu.indirect(bus);
+	    cpu.store_and_high(base, cpu.reg_y, cpu.reg_a & cpu.reg_x, bus)
+	}),
+
+	(Op::KIL, _) => |_, _| Some(HaltReason::Jam),
+
+	/* 65C02 instructions */
+	(Op::BRA, _) => implied!(|cpu, bus| cpu.execute_cond_branch(true, bus)),
+	(Op::PHX, _) => implied!(|cpu, bus| cpu.push(cpu.reg_x, bus)),
+	(Op::PHY, _) => implied!(|cpu, bus| cpu.push(cpu.reg_y, bus)),
+	(Op::PLX, _) => implied!(|cpu, bus| {
+	    cpu.peek_stack(bus);
+	    cpu.reg_x = cpu.pull(bus);
+	    cpu.set_zn(cpu.reg_x)
+	}),
+	(Op::PLY, _) => implied!(|cpu, bus| {
+	    cpu.peek_stack(bus);
+	    cpu.reg_y = cpu.pull(bus);
+	    cpu.set_zn(cpu.reg_y)
+	}),
+	(Op::STZ, mode) => with_mode!(mode, stz),
+	(Op::TRB, mode) => with_mode!(mode, trb),
+	(Op::TSB, mode) => with_mode!(mode, tsb),
+
+	/* catch all */
+	_ => unknown::<M>,
+    }
+}
@@ -0,0 +1,274 @@
+//! Runs the per-opcode JSON tests from https://github.com/SingleStepTests/65x02
+//! (formerly ProcessorTests), `nes6502` set.
+//!
+//! A handful of cases live in `fixtures/nes6502`. Point `SINGLE_STEP_DIR` at
+//! a checkout of `nes6502/v1` to run the full suite, every one of the 256
+//! opcodes must have a file then.
+//!
+//! Each file `xx.json` holds the cases for opcode $xx:
+//!
+//! ```text
+//! [{"name": "a9 42",
+//!   "initial": {"pc": 4096, "s": 253, "a": 0, "x": 0, "y": 0, "p": 36, "ram": [[4096, 169], [4097, 66]]},
+//!   "final": {...same layout...},
+//!   "cycles": [[4096, 169, "read"], [4097, 66, "read"]]}]
+//! ```
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use super::{Cpu, HaltReason};
+use super::tests::Recorder;
+use crate::memory::{Memory, Ram};
+use crate::opcodes::{Op, Variant};
+
+const FIXTURES: &str = "fixtures/nes6502";
+
+/// Minimal JSON, just enough for the test files
+#[derive(Debug)]
+enum Json {
+    Number(i64),
+    Str(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    fn get(&self, key: &str) -> &Json {
+	match self {
+	    Json::Object(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+		.unwrap_or_else(|| panic!("missing field {}", key)),
+	    _ => panic!("{} looked up on a non-object", key),
+	}
+    }
+
+    fn num(&self) -> i64 {
+	match self {
+	    Json::Number(n) => *n,
+	    _ => panic!("expected a number, got {:?}", self),
+	}
+    }
+
+    fn str(&self) -> &str {
+	match self {
+	    Json::Str(s) => s,
+	    _ => panic!("expected a string, got {:?}", self),
+	}
+    }
+
+    fn array(&self) -> &[Json] {
+	match self {
+	    Json::Array(items) => items,
+	    _ => panic!("expected an array, got {:?}", self),
+	}
+    }
+}
+
+struct Parser<'a> {
+    src: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn parse(src: &str) -> Json {
+	let mut parser = Parser { src: src.as_bytes(), pos: 0 };
+	let value = parser.value();
+	parser.skip_whitespace();
+	assert_eq!(parser.pos, parser.src.len(), "trailing data in json");
+	value
+    }
+
+    fn skip_whitespace(&mut self) {
+	while self.pos < self.src.len() && self.src[self.pos].is_ascii_whitespace() {
+	    self.pos += 1;
+	}
+    }
+
+    fn peek(&mut self) -> u8 {
+	self.skip_whitespace();
+	*self.src.get(self.pos).expect("unexpected end of json")
+    }
+
+    fn expect(&mut self, c: u8) {
+	assert_eq!(self.peek(), c, "expected {:?} at {}", c as char, self.pos);
+	self.pos += 1;
+    }
+
+    /// Parses the items of an array or object up to `close`
+    fn list(&mut self, close: u8, mut item: impl FnMut(&mut Self)) {
+	if self.peek() == close {
+	    self.pos += 1;
+	    return;
+	}
+	loop {
+	    item(self);
+	    match self.peek() {
+		b',' => self.pos += 1,
+		c if c == close => { self.pos += 1; return; },
+		c => panic!("unexpected {:?} at {}", c as char, self.pos),
+	    }
+	}
+    }
+
+    fn value(&mut self) -> Json {
+	match self.peek() {
+	    b'[' => {
+		self.pos += 1;
+		let mut items = Vec::new();
+		self.list(b']', |p| items.push(p.value()));
+		Json::Array(items)
+	    },
+	    b'{' => {
+		self.pos += 1;
+		let mut fields = Vec::new();
+		self.list(b'}', |p| {
+		    let key = p.string();
+		    p.expect(b':');
+		    fields.push((key, p.value()));
+		});
+		Json::Object(fields)
+	    },
+	    b'"' => Json::Str(self.string()),
+	    _ => {
+		let start = self.pos;
+		while self.pos < self.src.len() && (self.src[self.pos] == b'-' || self.src[self.pos].is_ascii_digit()) {
+		    self.pos += 1;
+		}
+		let text = std::str::from_utf8(&self.src[start..self.pos]).unwrap();
+		Json::Number(text.parse().unwrap_or_else(|_| panic!("bad json value at {}", start)))
+	    },
+	}
+    }
+
+    fn string(&mut self) -> String {
+	self.expect(b'"');
+	let start = self.pos;
+	while self.src[self.pos] != b'"' {
+	    self.pos += 1;
+	}
+	self.pos += 1;
+	String::from_utf8(self.src[start..self.pos - 1].to_vec()).unwrap()
+    }
+}
+
+/// Runs one case, describing the first mismatch
+fn run_case(case: &Json) -> Result<(), String> {
+    let initial = case.get("initial");
+    let expected = case.get("final");
+    let mut memory = Recorder { ram: Ram::new(), accesses: Vec::new() };
+    for entry in initial.get("ram").array() {
+	memory.ram.write(entry.array()[0].num() as u16, entry.array()[1].num() as u8);
+    }
+
+    let mut cpu = Cpu::new();
+    cpu.set_variant(Variant::Ricoh2A03);
+    cpu.set_cycle_stepped(true);
+    cpu.set_pc(initial.get("pc").num() as u16);
+    cpu.set_sp(initial.get("s").num() as u8);
+    cpu.set_a(initial.get("a").num() as u8);
+    cpu.set_x(initial.get("x").num() as u8);
+    cpu.set_y(initial.get("y").num() as u8);
+    cpu.set_p(initial.get("p").num() as u8);
+    let opcode = memory.ram.read(cpu.pc());
+
+    let halt = cpu.step(&mut memory).map_err(|e| format!("{:?}", e))?;
+    if matches!(Variant::Ricoh2A03.decode(opcode).opcode, Op::KIL) {
+	// The cpu stops on the jam instead of spinning on the bus
+	return match halt {
+	    Some(halt) if halt.reason == HaltReason::Jam => Ok(()),
+	    _ => Err(format!("expected a jam, got {:?}", halt)),
+	};
+    }
+    if let Some(halt) = halt {
+	return Err(format!("cpu stopped: {}", halt));
+    }
+
+    let registers = cpu.registers();
+    let actual = [
+	("pc", registers.pc as i64),
+	("s", registers.sp as i64),
+	("a", registers.a as i64),
+	("x", registers.x as i64),
+	("y", registers.y as i64),
+	// B and bit 5 only exist on the stack
+	("p", (registers.p & 0xcf) as i64),
+    ];
+    for (name, value) in actual {
+	let mut want = expected.get(name).num();
+	if name == "p" {
+	    want &= 0xcf;
+	}
+	if want != value {
+	    return Err(format!("{} expected {:02X}, actual {:02X}", name, want, value));
+	}
+    }
+
+    for entry in expected.get("ram").array() {
+	let addr = entry.array()[0].num() as u16;
+	let want = entry.array()[1].num() as u8;
+	let value = memory.ram.read(addr);
+	if want != value {
+	    return Err(format!("ram ${:04X} expected {:02X}, actual {:02X}", addr, want, value));
+	}
+    }
+
+    let cycles: Vec<(u16, u8, bool)> = case.get("cycles").array().iter().map(|c| {
+	let c = c.array();
+	(c[0].num() as u16, c[1].num() as u8, c[2].str() == "write")
+    }).collect();
+    if cycles != memory.accesses {
+	return Err(format!("bus expected {:X?}, actual {:X?}", cycles, memory.accesses));
+    }
+    Ok(())
+}
+
+/// Failed cases of one opcode
+struct Failures {
+    cases: usize,
+    failed: usize,
+    first: String,
+}
+
+/// Runs every case in `dir`, returning the failures per opcode. Opcodes
+/// without a file are listed separately.
+fn run_suite(dir: &Path) -> (BTreeMap<u8, Failures>, Vec<u8>) {
+    let mut failures = BTreeMap::new();
+    let mut missing = Vec::new();
+    for opcode in 0..=255u8 {
+	let path = dir.join(format!("{:02x}.json", opcode));
+	let Ok(src) = std::fs::read_to_string(&path) else {
+	    missing.push(opcode);
+	    continue;
+	};
+	let json = Parser::parse(&src);
+	let cases = json.array();
+	let mut failed = 0;
+	let mut first = None;
+	for case in cases {
+	    if let Err(e) = run_case(case) {
+		failed += 1;
+		first.get_or_insert_with(|| format!("{}: {}", case.get("name").str(), e));
+	    }
+	}
+	if let Some(first) = first {
+	    failures.insert(opcode, Failures { cases: cases.len(), failed, first });
+	}
+    }
+    (failures, missing)
+}
+
+#[test]
+fn test_single_step() {
+    let full = std::env::var_os("SINGLE_STEP_DIR").map(PathBuf::from);
+    let dir = full.clone().unwrap_or_else(|| PathBuf::from(FIXTURES));
+    let (failures, missing) = run_suite(&dir);
+
+    let mut report = String::new();
+    for (opcode, f) in &failures {
+	let i = Variant::Ricoh2A03.decode(*opcode);
+	report += &format!("${:02X} {:?} {:?}: {}/{} failed, first {}\n", opcode, i.opcode, i.addr_mode, f.failed, f.cases, f.first);
+    }
+    if full.is_some() && !missing.is_empty() {
+	report += &format!("no tests for {:02X?}\n", missing);
+    }
+    println!("{} of 256 opcodes tested", 256 - missing.len());
+    assert!(report.is_empty(), "\n{}", report);
+}
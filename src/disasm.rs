@@ -0,0 +1,306 @@
+use std::fmt;
+use std::fmt::Write;
+use super::cpu::Registers;
+use super::memory::Memory;
+use super::opcodes::{I,AM,Op,Variant};
+
+/// One disassembled instruction. Displays in the nestest log style, with
+/// unofficial opcodes marked by a `*`:
+///
+/// `C5F5  A2 00     LDX #$00`
+/// `D959  B1 FF     LDA ($FF),Y = 0146 @ 0245 = 12`
+#[derive(Debug, Clone)]
+pub struct Line {
+    pub addr: u16,
+    /// The opcode followed by its operand bytes
+    pub bytes: Vec<u8>,
+    pub instruction: I,
+    pub unofficial: bool,
+    /// Operand as printed, e.g. `$0300,Y @ 0300 = 89`. Effective addresses
+    /// and values are only included when they are known.
+    pub operand: String,
+}
+
+impl Line {
+    pub fn len(&self) -> u16 {
+	self.bytes.len() as u16
+    }
+
+    /// Address of the instruction following this one
+    #[allow(dead_code)]
+    pub fn next_addr(&self) -> u16 {
+	self.addr.wrapping_add(self.len())
+    }
+
+    /// Mnemonic as nestest spells it, e.g. ISB rather than ISC
+    pub fn mnemonic(&self) -> String {
+	match self.instruction.opcode {
+	    Op::ISC => "ISB".to_string(),
+	    Op::_XXX => "???".to_string(),
+	    op => format!("{:?}", op),
+	}
+    }
+}
+
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+	let bytes: Vec<String> = self.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+	let mark = if self.unofficial { '*' } else { ' ' };
+	write!(f, "{:04X}  {:<8} {}{}", self.addr, bytes.join(" "), mark, self.mnemonic())?;
+	if !self.operand.is_empty() {
+	    write!(f, " {}", self.operand)?;
+	}
+	Ok(())
+    }
+}
+
+/// Disassembles the instructions starting in `start..=end`, reading through
+/// `Memory::peek` so devices on the bus aren't disturbed. Stops early at a
+/// byte which can't be peeked.
+#[allow(dead_code)]
+pub fn disassemble<M: Memory + ?Sized>(memory: &M, variant: Variant, start: u16, end: u16) -> Vec<Line> {
+    disassemble_with(|addr| memory.peek(addr), variant, start, end)
+}
+
+/// Disassembles `bytes` as if loaded at `origin`. An instruction cut off by
+/// the end of the slice is left out.
+#[allow(dead_code)]
+pub fn disassemble_bytes(bytes: &[u8], origin: u16, variant: Variant) -> Vec<Line> {
+    if bytes.is_empty() {
+	return Vec::new();
+    }
+    let peek = |addr: u16| bytes.get(addr.wrapping_sub(origin) as usize).copied();
+    let end = origin.wrapping_add((bytes.len() - 1) as u16);
+    disassemble_with(peek, variant, origin, end)
+}
+
+/// Disassembles the instruction at `addr` as the cpu is about to execute it,
+/// resolving indexed and indirect addresses with `registers`.
+#[allow(dead_code)]
+pub fn disassemble_at<M: Memory + ?Sized>(memory: &M, variant: Variant, addr: u16, registers: &Registers) -> Option<Line> {
+    decode(&|a| memory.peek(a), variant, addr, Some(registers))
+}
+
+fn disassemble_with<F>(peek: F, variant: Variant, start: u16, end: u16) -> Vec<Line>
+where F: Fn(u16) -> Option<u8> {
+    let mut lines = Vec::new();
+    let mut addr = start as u32;
+    while addr <= end as u32 {
+	match decode(&peek, variant, addr as u16, None) {
+	    Some(line) => {
+		addr += line.len() as u32;
+		lines.push(line);
+	    },
+	    None => break,
+	}
+    }
+    lines
+}
+
+fn decode<F>(peek: &F, variant: Variant, addr: u16, registers: Option<&Registers>) -> Option<Line>
+where F: Fn(u16) -> Option<u8> {
+    let opcode = peek(addr)?;
+    let instruction = variant.decode(opcode);
+    let mut bytes = vec![opcode];
+    for i in 1..=instruction.addr_mode.operand_len() {
+	bytes.push(peek(addr.wrapping_add(i))?);
+    }
+    let operand = operand(peek, variant, addr, &instruction, &bytes, registers);
+    Some(Line {
+	addr,
+	bytes,
+	instruction,
+	unofficial: variant.is_unofficial(opcode),
+	operand,
+    })
+}
+
+/// Formats the operand the way nestest does. `@` gives the effective address
+/// of indexed modes and `=` what's stored at the address before it.
+fn operand<F>(peek: &F, variant: Variant, addr: u16, instruction: &I, bytes: &[u8], registers: Option<&Registers>) -> String
+where F: Fn(u16) -> Option<u8> {
+    let arg8 = bytes.get(1).copied().unwrap_or(0);
+    let arg16 = (bytes.get(2).copied().unwrap_or(0) as u16) << 8 | arg8 as u16;
+    let x = registers.map(|r| r.x);
+    let y = registers.map(|r| r.y);
+    let peek16 = |lo: u16, hi: u16| Some((peek(hi)? as u16) << 8 | peek(lo)? as u16);
+    let zp_ptr = |zp: u8| peek16(zp as u16, zp.wrapping_add(1) as u16);
+
+    let mut s = String::new();
+    // Appends ` = value` for the byte at `location`, when it can be peeked.
+    let value = |s: &mut String, location: u16| {
+	if let Some(v) = peek(location) {
+	    let _ = write!(s, " = {:02X}", v);
+	}
+    };
+
+    match instruction.addr_mode {
+	AM::IMP => {
+	    if matches!(instruction.opcode, Op::ASL | Op::LSR | Op::ROL | Op::ROR) ||
+		(variant.is_cmos() && matches!(instruction.opcode, Op::INC | Op::DEC)) {
+		s.push('A');
+	    }
+	},
+	AM::IMM => { let _ = write!(s, "#${:02X}", arg8); },
+	AM::REL => {
+	    let target = addr.wrapping_add(2).wrapping_add(arg8 as i8 as u16);
+	    let _ = write!(s, "${:04X}", target);
+	},
+	AM::ZPG => {
+	    let _ = write!(s, "${:02X}", arg8);
+	    value(&mut s, arg8 as u16);
+	},
+	AM::ABS => {
+	    let _ = write!(s, "${:04X}", arg16);
+	    if !matches!(instruction.opcode, Op::JMP | Op::JSR) {
+		value(&mut s, arg16);
+	    }
+	},
+	AM::ZPX | AM::ZPY => {
+	    let (name, index) = if matches!(instruction.addr_mode, AM::ZPX) { ('X', x) } else { ('Y', y) };
+	    let _ = write!(s, "${:02X},{}", arg8, name);
+	    if let Some(index) = index {
+		let location = arg8.wrapping_add(index);
+		let _ = write!(s, " @ {:02X}", location);
+		value(&mut s, location as u16);
+	    }
+	},
+	AM::ABX | AM::ABY => {
+	    let (name, index) = if matches!(instruction.addr_mode, AM::ABX) { ('X', x) } else { ('Y', y) };
+	    let _ = write!(s, "${:04X},{}", arg16, name);
+	    if let Some(index) = index {
+		let location = arg16.wrapping_add(index as u16);
+		let _ = write!(s, " @ {:04X}", location);
+		value(&mut s, location);
+	    }
+	},
+	AM::IND => {
+	    let _ = write!(s, "(${:04X})", arg16);
+	    // The NMOS parts don't carry into the high byte of the pointer
+	    let hi = if variant.is_cmos() {
+		arg16.wrapping_add(1)
+	    } else {
+		(arg16 & 0xff00) | (arg16.wrapping_add(1) & 0xff)
+	    };
+	    if let Some(target) = peek16(arg16, hi) {
+		let _ = write!(s, " = {:04X}", target);
+	    }
+	},
+	AM::IAX => {
+	    let _ = write!(s, "(${:04X},X)", arg16);
+	    if let Some(x) = x {
+		let pointer = arg16.wrapping_add(x as u16);
+		let _ = write!(s, " @ {:04X}", pointer);
+		if let Some(target) = peek16(pointer, pointer.wrapping_add(1)) {
+		    let _ = write!(s, " = {:04X}", target);
+		}
+	    }
+	},
+	AM::INX => {
+	    let _ = write!(s, "(${:02X},X)", arg8);
+	    if let Some(x) = x {
+		let pointer = arg8.wrapping_add(x);
+		let _ = write!(s, " @ {:02X}", pointer);
+		if let Some(target) = zp_ptr(pointer) {
+		    let _ = write!(s, " = {:04X}", target);
+		    value(&mut s, target);
+		}
+	    }
+	},
+	AM::INY => {
+	    let _ = write!(s, "(${:02X}),Y", arg8);
+	    if let Some(base) = zp_ptr(arg8) {
+		let _ = write!(s, " = {:04X}", base);
+		if let Some(y) = y {
+		    let location = base.wrapping_add(y as u16);
+		    let _ = write!(s, " @ {:04X}", location);
+		    value(&mut s, location);
+		}
+	    }
+	},
+	AM::ZPI => {
+	    let _ = write!(s, "(${:02X})", arg8);
+	    if let Some(target) = zp_ptr(arg8) {
+		let _ = write!(s, " = {:04X}", target);
+		value(&mut s, target);
+	    }
+	},
+    }
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memory::Ram;
+    use super::*;
+
+    fn registers(x: u8, y: u8) -> Registers {
+	Registers { pc: 0, sp: 0xfd, a: 0, x, y, p: 0x24 }
+    }
+
+    fn line_at(ram: &Ram, addr: u16, x: u8, y: u8) -> String {
+	disassemble_at(ram, Variant::Ricoh2A03, addr, &registers(x, y)).unwrap().to_string()
+    }
+
+    #[test]
+    fn test_nestest_lines() {
+	let mut ram = Ram::new();
+	ram.load(0xc5f5, &[0xa2, 0x00]);
+	ram.load(0xcefc, &[0x4a]);
+	ram.load(0xc72f, &[0xb0, 0x04]);
+	ram.load(0xc6bd, &[0x04, 0xa9]);
+	ram.load(0xd098, &[0x61, 0x80]);
+	ram.load(0x0080, &[0x00, 0x02]);
+	ram.load(0x0200, &[0x69]);
+	ram.load(0xdf90, &[0xb9, 0x46, 0x01]);
+	ram.load(0x0245, &[0x12]);
+	ram.load(0xd959, &[0xb1, 0xff]);
+	ram.load(0x00ff, &[0x46]);
+	ram.load(0x0000, &[0x01]);
+	ram.load(0xeb9e, &[0xe3, 0x45]);
+	ram.load(0x0047, &[0x47, 0x06]);
+	ram.load(0x0647, &[0xeb]);
+
+	assert_eq!(line_at(&ram, 0xc5f5, 0, 0), "C5F5  A2 00     LDX #$00");
+	assert_eq!(line_at(&ram, 0xcefc, 0, 0), "CEFC  4A        LSR A");
+	assert_eq!(line_at(&ram, 0xc72f, 0, 0), "C72F  B0 04     BCS $C735");
+	assert_eq!(line_at(&ram, 0xc6bd, 0, 0), "C6BD  04 A9    *NOP $A9 = 00");
+	assert_eq!(line_at(&ram, 0xd098, 0, 0), "D098  61 80     ADC ($80,X) @ 80 = 0200 = 69");
+	assert_eq!(line_at(&ram, 0xdf90, 0, 0xff), "DF90  B9 46 01  LDA $0146,Y @ 0245 = 12");
+	assert_eq!(line_at(&ram, 0xd959, 0, 0xff), "D959  B1 FF     LDA ($FF),Y = 0146 @ 0245 = 12");
+	assert_eq!(line_at(&ram, 0xeb9e, 2, 0), "EB9E  E3 45    *ISB ($45,X) @ 47 = 0647 = EB");
+
+	// The pointer's high byte comes from $0200, not $0300
+	ram.load(0xdbb5, &[0x6c, 0xff, 0x02]);
+	ram.load(0x0200, &[0x03]);
+	ram.load(0x02ff, &[0x00]);
+	ram.load(0x0300, &[0x89]);
+	assert_eq!(line_at(&ram, 0xdbb5, 0, 0), "DBB5  6C FF 02  JMP ($02FF) = 0300");
+    }
+
+    #[test]
+    fn test_disassemble_bytes() {
+	// LDA #$01; STA $0200,X; JMP $8000; truncated JSR
+	let program = [0xa9, 0x01, 0x9d, 0x00, 0x02, 0x4c, 0x00, 0x80, 0x20, 0x00];
+	let lines: Vec<String> = disassemble_bytes(&program, 0x8000, Variant::Ricoh2A03)
+	    .iter().map(|l| l.to_string()).collect();
+	assert_eq!(lines, [
+	    "8000  A9 01     LDA #$01",
+	    "8002  9D 00 02  STA $0200,X",
+	    "8005  4C 00 80  JMP $8000",
+	]);
+    }
+
+    #[test]
+    fn test_disassemble_65c02() {
+	let program = [0x1a, 0x12, 0x10, 0x7c, 0x00, 0x90, 0x0b];
+	let lines: Vec<String> = disassemble_bytes(&program, 0x0400, Variant::Cmos65C02)
+	    .iter().map(|l| l.to_string()).collect();
+	assert_eq!(lines, [
+	    "0400  1A        INC A",
+	    "0401  12 10     ORA ($10)",
+	    "0403  7C 00 90  JMP ($9000,X)",
+	    "0406  0B       *NOP",
+	]);
+    }
+}
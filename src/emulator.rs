@@ -1,39 +1,297 @@
-use std::cell::RefCell;
 use std::convert::AsRef;
+use std::io::Write;
+use std::ops::RangeInclusive;
 use std::path::Path;
-use std::rc::Rc;
-use super::bus::Bus;
+use super::bus::{Bus, Diagnostic};
+use super::clock::Region;
 use super::controller::Controller;
-use super::cpu::Cpu;
+use super::cpu::{Cpu, Halt, HaltReason};
 use super::err::EmuErr;
+use super::frame::{Frame, Palette};
+use super::memory::RamInit;
 use super::ppu::Ppu;
+use super::trace::Tracer;
 
 pub struct Emulator {
     cpu: Cpu,
     bus: Bus,
+    tracer: Option<Tracer>,
+    trace_filter: Option<RangeInclusive<u16>>,
 }
 
 impl Emulator {
     pub fn new<F>(_update_game: Box<F>) -> Self
     where F: FnMut (&Ppu, &mut Controller) {
-	let nmi_signal: Rc<RefCell<bool>> = Rc::new(RefCell::new(false));
 	Self {
-	    cpu: Cpu::new(nmi_signal.clone()),
-	    bus: Bus::new(nmi_signal),
+	    cpu: Cpu::new(),
+	    bus: Bus::new(),
+	    tracer: None,
+	    trace_filter: None,
 	}
     }
 
     pub fn init<P: AsRef<Path>>(&mut self, rom_path: P) -> Result<(), EmuErr> {
 	self.bus.load_rom(rom_path)?;
+	self.power_cycle();
+
+	Ok(())
+    }
+
+    /// Turns the console off and on again. Ram is filled as set by
+    /// `set_ram_init`, the cartridge stays in.
+    pub fn power_cycle(&mut self) {
+	self.bus.power_on();
 	self.cpu.power_on();
 	self.cpu.reset(&mut self.bus);
+    }
 
-	Ok(())
+    /// Presses the reset button. Ram and mapper state are kept, the cpu runs
+    /// its reset sequence and the ppu clears its control registers.
+    #[allow(dead_code)]
+    pub fn soft_reset(&mut self) {
+	self.bus.reset();
+	self.cpu.reset(&mut self.bus);
+    }
+
+    /// How ram is filled at the next power cycle
+    #[allow(dead_code)]
+    pub fn set_ram_init(&mut self, ram_init: RamInit) {
+	self.bus.set_ram_init(ram_init);
+    }
+
+    /// Switches between NTSC and PAL timing
+    #[allow(dead_code)]
+    pub fn set_region(&mut self, region: Region) {
+	self.bus.set_region(region);
+    }
+
+    /// Runs the cpu cycle by cycle, clocking the rest of the system on every
+    /// cpu bus access instead of after each cpu step. See `Cpu::set_cycle_stepped`.
+    #[allow(dead_code)]
+    pub fn set_cycle_stepped(&mut self, cycle_stepped: bool) {
+	self.cpu.set_cycle_stepped(cycle_stepped);
+    }
+
+    /// The cpu, for inspecting its state. See `Cpu::state`.
+    #[allow(dead_code)]
+    pub fn cpu(&self) -> &Cpu {
+	&self.cpu
+    }
+
+    #[allow(dead_code)]
+    pub fn cpu_mut(&mut self) -> &mut Cpu {
+	&mut self.cpu
+    }
+
+    /// Starts writing a nestest style trace line to `sink` before every
+    /// instruction. Replaces the sink of a trace already running.
+    #[allow(dead_code)]
+    pub fn start_trace(&mut self, sink: Box<dyn Write + Send>) {
+	let mut tracer = Tracer::new(sink);
+	tracer.set_filter(self.trace_filter.clone());
+	self.tracer = Some(tracer);
+    }
+
+    /// Stops tracing, handing back the flushed sink
+    #[allow(dead_code)]
+    pub fn stop_trace(&mut self) -> Result<Option<Box<dyn Write + Send>>, EmuErr> {
+	self.tracer.take().map(Tracer::finish).transpose()
+    }
+
+    /// Only traces instructions in `range`, or all of them with `None`. The
+    /// filter also applies to traces started later.
+    #[allow(dead_code)]
+    pub fn set_trace_filter(&mut self, range: Option<RangeInclusive<u16>>) {
+	if let Some(tracer) = &mut self.tracer {
+	    tracer.set_filter(range.clone());
+	}
+	self.trace_filter = range;
+    }
+
+    /// Reads `addr` the way the cpu would without disturbing the system,
+    /// for debuggers and memory viewers. See `Bus::peek`.
+    #[allow(dead_code)]
+    pub fn peek(&self, addr: u16) -> Option<u8> {
+	self.bus.peek(addr)
+    }
+
+    /// What the program did wrong since the last call, see `Bus::take_diagnostics`
+    pub fn take_diagnostics(&mut self) -> Vec<Diagnostic> {
+	self.bus.take_diagnostics()
+    }
+
+    /// Whether a frame was completed since the last call. Frames complete
+    /// as vblank starts, the frontend then has until the ppu starts drawing
+    /// the next one to present `frame`.
+    #[allow(dead_code)]
+    pub fn take_frame_complete(&mut self) -> bool {
+	self.bus.take_frame_complete()
+    }
+
+    /// The ppu's picture, see `Frame`
+    #[allow(dead_code)]
+    pub fn frame(&self) -> &Frame {
+	self.bus.ppu().frame()
+    }
+
+    /// Writes the ppu's picture to `buf` as RGB24 through `palette`
+    #[allow(dead_code)]
+    pub fn draw(&self, palette: &Palette, buf: &mut [u8]) {
+	self.bus.draw(palette, buf);
     }
 
-    pub fn step(&mut self) -> Result<bool, EmuErr> {
-	let exit = self.cpu.step(&mut self.bus)?;
-	self.bus.step()?;
-	Ok(exit)
+    /// Steps the cpu and the rest of the system. Returns why the cpu
+    /// stopped, if it did.
+    pub fn step(&mut self) -> Result<Option<Halt>, EmuErr> {
+	if let Some(tracer) = &mut self.tracer {
+	    if self.cpu.at_instruction_boundary() {
+		self.bus.catch_up()?;
+		let ppu = self.bus.ppu();
+		tracer.trace(&self.cpu, &self.bus, ppu.scanline(), ppu.cycle())?;
+	    }
+	}
+	let halt = self.cpu.step(&mut self.bus)?;
+	// A breakpoint stops the cpu before the instruction, it spent no cycle
+	let at_breakpoint = matches!(halt, Some(Halt { reason: HaltReason::Breakpoint, .. }));
+	if !self.cpu.cycle_stepped() && !at_breakpoint {
+	    self.bus.step()?;
+	}
+	Ok(halt)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+    use super::*;
+
+    /// Sink the test can still read after handing it to the emulator
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+	fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+	    self.0.lock().unwrap().extend_from_slice(buf);
+	    Ok(buf.len())
+	}
+
+	fn flush(&mut self) -> std::io::Result<()> { Ok(()) }
+    }
+
+    fn nestest_emulator() -> Emulator {
+	let mut emu = Emulator::new(Box::new(|_: &Ppu, _: &mut Controller| {}));
+	emu.init("testrom.nes").unwrap();
+	// The automated suite starts at 0xc000
+	emu.cpu_mut().set_pc(0xc000);
+	emu
+    }
+
+    /// Drops the `= value` nestest shows for registers, which depend on the
+    /// open bus and the apu
+    fn without_io_values(line: &str) -> String {
+	let (instruction, registers) = line.split_at(47);
+	let io = [" $2", " $3", " $4"].iter().any(|r| instruction.contains(r));
+	match instruction.find(" = ") {
+	    Some(i) if io => format!("{:<47}{}", &instruction[..i], registers),
+	    _ => line.to_string(),
+	}
+    }
+
+    #[test]
+    fn test_emulator_is_send() {
+	fn assert_send<T: Send>() {}
+	assert_send::<Emulator>();
+    }
+
+    #[test]
+    fn test_trace_matches_nestest() {
+	let expected = std::fs::read_to_string("nestest.log").unwrap();
+	let expected: Vec<&str> = expected.lines().collect();
+	let buf = SharedBuf::default();
+	let mut emu = nestest_emulator();
+	emu.start_trace(Box::new(buf.clone()));
+	let last_cycle: usize = expected.last().unwrap().split("CYC:").nth(1).unwrap().parse().unwrap();
+	while emu.cpu().total_cycles() <= last_cycle {
+	    assert!(emu.step().unwrap().is_none());
+	}
+	emu.stop_trace().unwrap();
+
+	let actual = String::from_utf8(buf.0.lock().unwrap().clone()).unwrap();
+	assert_eq!(actual.lines().count(), expected.len());
+	for (actual, expected) in actual.lines().zip(expected) {
+	    assert_eq!(without_io_values(actual), without_io_values(expected));
+	}
+    }
+
+    #[test]
+    fn test_soft_reset_and_power_cycle() {
+	let mut emu = nestest_emulator();
+	emu.set_ram_init(RamInit::Pattern(vec![0x00, 0xff]));
+	emu.power_cycle();
+	assert_eq!((emu.bus.read(0x0000), emu.bus.read(0x0001)), (0x00, 0xff));
+
+	emu.bus.write(0x0010, 0x42);
+	emu.cpu_mut().set_a(0x12);
+	emu.soft_reset();
+	assert_eq!(emu.bus.read(0x0010), 0x42);
+	assert_eq!(emu.cpu().a(), 0x12);
+	assert_eq!(emu.cpu().sp(), 0xfa);
+	assert_eq!(emu.cpu().pc(), 0xc004);
+
+	emu.set_ram_init(RamInit::Random(7));
+	emu.power_cycle();
+	let first: Vec<u8> = (0..0x800).map(|a| emu.bus.read(a)).collect();
+	emu.power_cycle();
+	let second: Vec<u8> = (0..0x800).map(|a| emu.bus.read(a)).collect();
+	assert_eq!(first, second);
+	assert!(first.iter().any(|&b| b != first[0]));
+	assert_eq!((emu.cpu().a(), emu.cpu().sp()), (0x00, 0xfd));
+    }
+
+    #[test]
+    fn test_trace_filter() {
+	// the filter can be set before or after starting the trace
+	for filter_first in [false, true] {
+	    let buf = SharedBuf::default();
+	    let mut emu = nestest_emulator();
+	    if filter_first {
+		emu.set_trace_filter(Some(0xc5f5..=0xc5ff));
+	    }
+	    emu.start_trace(Box::new(buf.clone()));
+	    if !filter_first {
+		emu.set_trace_filter(Some(0xc5f5..=0xc5ff));
+	    }
+	    for _ in 0..100 {
+		emu.step().unwrap();
+	    }
+	    emu.stop_trace().unwrap();
+	    let trace = String::from_utf8(buf.0.lock().unwrap().clone()).unwrap();
+	    let pcs: Vec<&str> = trace.lines().map(|l| &l[..4]).collect();
+	    assert_eq!(pcs, ["C5F5", "C5F7", "C5F9", "C5FB", "C5FD"], "filter first {}", filter_first);
+	}
+    }
+
+    #[test]
+    fn test_breakpoint_keeps_timing() {
+	let mut plain = nestest_emulator();
+	let mut stopped = nestest_emulator();
+	stopped.cpu_mut().add_breakpoint(0xc5f5);
+	let mut hits = 0;
+	while stopped.cpu().total_cycles() < 1000 {
+	    if let Some(halt) = stopped.step().unwrap() {
+		assert_eq!(halt.reason, HaltReason::Breakpoint);
+		hits += 1;
+	    }
+	}
+	while plain.cpu().total_cycles() < 1000 {
+	    plain.step().unwrap();
+	}
+	assert_eq!(hits, 1);
+
+	plain.bus.catch_up().unwrap();
+	stopped.bus.catch_up().unwrap();
+	let position = |emu: &Emulator| (emu.bus.ppu().scanline(), emu.bus.ppu().cycle());
+	assert_eq!(position(&stopped), position(&plain));
     }
 }
@@ -1,3 +1,4 @@
+use std::fmt;
 use std::io::Error as IOError;
 
 #[derive(Debug)]
@@ -5,5 +6,24 @@ pub enum EmuErr {
     ReadRom(IOError),
     InvalidRom,
     UnsupportedMapperType,
-    UnrecognizedOpCode(u16),
+    InvalidCpuState,
+    InvalidPalette,
+    WriteTrace,
+    /// Line number and what's wrong with it, line 0 for the whole program
+    Assemble(usize, String),
+}
+
+impl fmt::Display for EmuErr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+	match self {
+	    EmuErr::ReadRom(err) => write!(f, "can't read rom: {}", err),
+	    EmuErr::InvalidRom => write!(f, "invalid rom"),
+	    EmuErr::UnsupportedMapperType => write!(f, "unsupported mapper type"),
+	    EmuErr::InvalidCpuState => write!(f, "invalid cpu state"),
+	    EmuErr::InvalidPalette => write!(f, "invalid palette"),
+	    EmuErr::WriteTrace => write!(f, "can't write trace"),
+	    EmuErr::Assemble(0, msg) => write!(f, "{}", msg),
+	    EmuErr::Assemble(line, msg) => write!(f, "line {}: {}", line, msg),
+	}
+    }
 }
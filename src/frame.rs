@@ -0,0 +1,155 @@
+use super::err::EmuErr;
+
+pub const WIDTH: usize = 256;
+pub const HEIGHT: usize = 240;
+
+/// One picture as the ppu outputs it, row by row from the top left.
+///
+/// Each pixel is a 9 bit color: the 6 bit palette entry in bits [0,5] and
+/// the PPUMASK emphasis bits, red green blue, in bits [6,8]. `Palette` turns
+/// them into RGB.
+pub struct Frame {
+    pixels: Box<[u16]>,
+}
+
+impl Frame {
+    pub fn new() -> Self {
+	Self {
+	    pixels: vec![0; WIDTH * HEIGHT].into_boxed_slice(),
+	}
+    }
+
+    #[allow(dead_code)]
+    pub fn pixels(&self) -> &[u16] {
+	&self.pixels
+    }
+
+    #[allow(dead_code)]
+    pub fn pixel(&self, x: usize, y: usize) -> u16 {
+	self.pixels[y * WIDTH + x]
+    }
+
+    pub fn set_pixel(&mut self, x: usize, y: usize, color: u16) {
+	self.pixels[y * WIDTH + x] = color;
+    }
+
+    /// Writes the frame to `buf` as 3 bytes per pixel, red green blue.
+    /// `buf` must hold `WIDTH * HEIGHT * 3` bytes.
+    pub fn to_rgb24(&self, palette: &Palette, buf: &mut [u8]) {
+	assert_eq!(buf.len(), WIDTH * HEIGHT * 3, "rgb24 buffer size");
+	for (out, &pixel) in buf.chunks_exact_mut(3).zip(self.pixels.iter()) {
+	    out.copy_from_slice(&palette.rgb(pixel));
+	}
+    }
+
+    /// Writes the frame to `buf` as 4 bytes per pixel, red green blue and
+    /// an opaque alpha. `buf` must hold `WIDTH * HEIGHT * 4` bytes.
+    #[allow(dead_code)]
+    pub fn to_rgba8888(&self, palette: &Palette, buf: &mut [u8]) {
+	assert_eq!(buf.len(), WIDTH * HEIGHT * 4, "rgba8888 buffer size");
+	for (out, &pixel) in buf.chunks_exact_mut(4).zip(self.pixels.iter()) {
+	    let [r, g, b] = palette.rgb(pixel);
+	    out.copy_from_slice(&[r, g, b, 0xff]);
+	}
+    }
+}
+
+/// The RGB value of every 9 bit color, see `Frame`.
+/// https://www.nesdev.org/wiki/PPU_palettes
+pub struct Palette {
+    colors: Box<[[u8;3]]>,
+}
+
+impl Palette {
+    const COLORS: usize = 64;
+    const EMPHASIZED: usize = 8 * Self::COLORS;
+    // What an emphasis bit leaves of the other two channels
+    const ATTENUATION: f32 = 0.816328;
+
+    /// Reads a palette in the .pal format most emulators share: 64 colors,
+    /// or 512 with every combination of emphasis bits, 3 bytes each.
+    /// Emphasis for a 64 color palette is approximated by dimming the
+    /// channels which aren't emphasized.
+    #[allow(dead_code)]
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, EmuErr> {
+	let colors: Vec<[u8;3]> = bytes.chunks_exact(3).map(|c| [c[0], c[1], c[2]]).collect();
+	match (bytes.len() % 3, colors.len()) {
+	    (0, Self::EMPHASIZED) => Ok(Self { colors: colors.into_boxed_slice() }),
+	    (0, Self::COLORS) => Ok(Self::emphasize(&colors)),
+	    _ => Err(EmuErr::InvalidPalette),
+	}
+    }
+
+    fn emphasize(colors: &[[u8;3]]) -> Self {
+	let colors = (0..Self::EMPHASIZED).map(|i| {
+	    let (emphasis, color) = (i >> 6, colors[i & 0x3f]);
+	    // the blacks of columns $xE and $xF aren't affected
+	    if emphasis == 0 || i & 0x0e == 0x0e {
+		return color;
+	    }
+	    let mut rgb = color;
+	    for (channel, value) in rgb.iter_mut().enumerate() {
+		if emphasis & (1 << channel) == 0 {
+		    *value = (*value as f32 * Self::ATTENUATION) as u8;
+		}
+	    }
+	    rgb
+	}).collect();
+	Self { colors }
+    }
+
+    /// The RGB value of a 9 bit color
+    pub fn rgb(&self, color: u16) -> [u8;3] {
+	self.colors[color as usize & (Self::EMPHASIZED - 1)]
+    }
+}
+
+impl Default for Palette {
+    /// The 2C02 colors as measured from a composite capture
+    fn default() -> Self {
+	Self::emphasize(&NTSC)
+    }
+}
+
+const NTSC: [[u8;3]; 64] = [
+    [84, 84, 84], [0, 30, 116], [8, 16, 144], [48, 0, 136], [68, 0, 100], [92, 0, 48], [84, 4, 0], [60, 24, 0],
+    [32, 42, 0], [8, 58, 0], [0, 64, 0], [0, 60, 0], [0, 50, 60], [0, 0, 0], [0, 0, 0], [0, 0, 0],
+    [152, 150, 152], [8, 76, 196], [48, 50, 236], [92, 30, 228], [136, 20, 176], [160, 20, 100], [152, 34, 32], [120, 60, 0],
+    [84, 90, 0], [40, 114, 0], [8, 124, 0], [0, 118, 40], [0, 102, 120], [0, 0, 0], [0, 0, 0], [0, 0, 0],
+    [236, 238, 236], [76, 154, 236], [120, 124, 236], [176, 98, 236], [228, 84, 236], [236, 88, 180], [236, 106, 100], [212, 136, 32],
+    [160, 170, 0], [116, 196, 0], [76, 208, 32], [56, 204, 108], [56, 180, 204], [60, 60, 60], [0, 0, 0], [0, 0, 0],
+    [236, 238, 236], [168, 204, 236], [188, 188, 236], [212, 178, 236], [236, 174, 236], [236, 174, 212], [236, 180, 176], [228, 196, 144],
+    [204, 210, 120], [180, 222, 120], [168, 226, 144], [152, 226, 180], [160, 214, 228], [160, 162, 160], [0, 0, 0], [0, 0, 0],
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_palette() {
+	let mut pal: Vec<u8> = (0..64u8).flat_map(|i| [i, 100, 200]).collect();
+	let palette = Palette::from_bytes(&pal).unwrap();
+	assert_eq!(palette.rgb(0x21), [0x21, 100, 200]);
+	// red emphasized dims green and blue
+	assert_eq!(palette.rgb(1 << 6 | 0x21), [0x21, 81, 163]);
+	assert_eq!(palette.rgb(7 << 6 | 0x21), [0x21, 100, 200]);
+	assert_eq!(palette.rgb(1 << 6 | 0x0e), [0x0e, 100, 200]);
+
+	pal.push(0);
+	assert!(Palette::from_bytes(&pal).is_err());
+    }
+
+    #[test]
+    fn test_frame_conversion() {
+	let mut frame = Frame::new();
+	frame.set_pixel(1, 0, 0x30);
+	let palette = Palette::default();
+	let mut rgb = vec![0; WIDTH * HEIGHT * 3];
+	frame.to_rgb24(&palette, &mut rgb);
+	assert_eq!(rgb[..6], [84, 84, 84, 236, 238, 236]);
+	let mut rgba = vec![0; WIDTH * HEIGHT * 4];
+	frame.to_rgba8888(&palette, &mut rgba);
+	assert_eq!(rgba[..8], [84, 84, 84, 0xff, 236, 238, 236, 0xff]);
+    }
+}
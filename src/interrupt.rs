@@ -0,0 +1,101 @@
+/// Devices which can pull the shared IRQ line low. The line is level
+/// triggered: it stays asserted until every source has released it.
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy)]
+pub enum IrqSource {
+    Mapper = 1,
+    FrameCounter = 1 << 1,
+    Dmc = 1 << 2,
+    /// Famicom Disk System timer and disk transfers
+    Fds = 1 << 3,
+}
+
+/// The cpu's two interrupt inputs, owned by the bus.
+///
+/// NMI is edge triggered: a rising edge on the line is latched until the cpu
+/// acknowledges it by starting the NMI sequence, so holding the line high
+/// only fires once. IRQ is a wired OR of the sources holding it, serviced
+/// for as long as any of them does.
+#[derive(Debug, Default, Clone)]
+pub struct InterruptController {
+    nmi_line: bool,
+    nmi_pending: bool,
+    irq_sources: u8,
+}
+
+impl InterruptController {
+    pub fn new() -> Self {
+	Self::default()
+    }
+
+    /// Drives the NMI line, latching an NMI on a rising edge
+    pub fn set_nmi_line(&mut self, level: bool) {
+	if level && !self.nmi_line {
+	    self.nmi_pending = true;
+	}
+	self.nmi_line = level;
+    }
+
+    /// An NMI edge has been seen and not acknowledged yet
+    pub fn nmi_pending(&self) -> bool {
+	self.nmi_pending
+    }
+
+    /// The cpu has started servicing the NMI
+    pub fn acknowledge_nmi(&mut self) {
+	self.nmi_pending = false;
+    }
+
+    /// Pulls the IRQ line low on behalf of `source`
+    pub fn assert_irq(&mut self, source: IrqSource) {
+	self.irq_sources |= source as u8;
+    }
+
+    /// Releases `source`'s hold on the IRQ line. Devices do this when the
+    /// program acknowledges the interrupt through their registers.
+    pub fn release_irq(&mut self, source: IrqSource) {
+	self.irq_sources &= !(source as u8);
+    }
+
+    /// Whether any source is holding the IRQ line
+    pub fn irq(&self) -> bool {
+	self.irq_sources != 0
+    }
+
+    /// Whether `source` is holding the IRQ line
+    #[allow(dead_code)]
+    pub fn irq_asserted(&self, source: IrqSource) -> bool {
+	self.irq_sources & source as u8 != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nmi_edge() {
+	let mut interrupts = InterruptController::new();
+	interrupts.set_nmi_line(true);
+	assert!(interrupts.nmi_pending());
+	interrupts.acknowledge_nmi();
+	// still high, no new edge
+	interrupts.set_nmi_line(true);
+	assert!(!interrupts.nmi_pending());
+	interrupts.set_nmi_line(false);
+	interrupts.set_nmi_line(true);
+	assert!(interrupts.nmi_pending());
+    }
+
+    #[test]
+    fn test_irq_sources() {
+	let mut interrupts = InterruptController::new();
+	interrupts.assert_irq(IrqSource::Mapper);
+	interrupts.assert_irq(IrqSource::Fds);
+	interrupts.release_irq(IrqSource::Mapper);
+	assert!(interrupts.irq());
+	assert!(interrupts.irq_asserted(IrqSource::Fds));
+	interrupts.release_irq(IrqSource::Fds);
+	assert!(!interrupts.irq());
+    }
+}
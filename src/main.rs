@@ -1,12 +1,19 @@
+mod asm;
 mod bus;
 mod cartridge;
+mod clock;
 mod controller;
 mod cpu;
+mod disasm;
 mod emulator;
 mod err;
+mod frame;
+mod interrupt;
 mod mapper;
+mod memory;
 mod opcodes;
 mod ppu;
+mod trace;
 
 use controller::{Button, Controller};
 use emulator::Emulator;
@@ -64,9 +71,18 @@ fn main() {
     });
 
     let mut emu = Emulator::new(update_fn);
-    emu.init("./testrom.nes").unwrap();
+    if let Err(err) = emu.init("./testrom.nes") {
+	eprintln!("{}", err);
+	return;
+    }
 
     loop {
-	emu.step().unwrap();
+	if let Some(halt) = emu.step().unwrap() {
+	    println!("cpu stopped: {}", halt);
+	    break;
+	}
+	for diagnostic in emu.take_diagnostics() {
+	    eprintln!("{}", diagnostic);
+	}
     }
 }
@@ -1,6 +1,6 @@
 mod nrom;
 
-use super::cartridge::Cartridge;
+use super::cartridge::{Cartridge, Mirroring};
 use super::err::EmuErr;
 use nrom::MapperNROM;
 
@@ -21,11 +21,44 @@ impl std::convert::TryFrom<u8> for MapperType {
     }
 }
 
-pub trait Mapper {
-    fn read_prg_rom(&self, addr: u16) -> u8;
-    fn write_prg_rom(&self, addr: u16, data: u8);
+pub trait Mapper: Send {
+    /// Reads cartridge space [0x4020,0xffff] without side effects. `None`
+    /// when nothing on the cartridge drives the bus.
+    fn peek_prg(&self, addr: u16) -> Option<u8>;
+    /// Reads cartridge space as the cpu does. Mappers which latch or
+    /// acknowledge on reads override this, see `peek_prg`.
+    fn read_prg(&mut self, addr: u16) -> Option<u8> {
+	self.peek_prg(addr)
+    }
+    /// Writes cartridge space [0x4020,0xffff]: prg ram and mapper
+    /// registers. Returns false when the cartridge doesn't decode `addr`.
+    fn write_prg(&mut self, addr: u16, data: u8) -> bool;
+    /// Reads the pattern tables, ppu addresses [0x0000,0x1fff]
     fn read_chr(&self, addr: u16) -> u8;
-    fn write_chr(&self, addr: u16, data: u8);
+    /// Writes the pattern tables. Only does something for chr ram.
+    fn write_chr(&mut self, addr: u16, data: u8);
+    /// The current nametable layout. Fixed by the board for most mappers,
+    /// others switch it through their registers.
+    fn mirroring(&self) -> Mirroring;
+    /// Reads a nametable the console's VRAM doesn't cover, ppu addresses
+    /// [0x2000,0x2fff] mapped outside of it by `mirroring`
+    fn read_nametable(&self, _addr: u16) -> u8 { 0 }
+    /// Writes a nametable on the cartridge, see `read_nametable`
+    fn write_nametable(&mut self, _addr: u16, _data: u8) {}
+
+    /// Puts the mapper's registers back in their power up state
+    fn power_on(&mut self) {}
+}
+
+/// The empty cartridge slot, nothing answers
+pub struct Unplugged;
+
+impl Mapper for Unplugged {
+    fn peek_prg(&self, _addr: u16) -> Option<u8> { None }
+    fn write_prg(&mut self, _addr: u16, _data: u8) -> bool { false }
+    fn read_chr(&self, _addr: u16) -> u8 { 0 }
+    fn write_chr(&mut self, _addr: u16, _data: u8) {}
+    fn mirroring(&self) -> Mirroring { Mirroring::Horizontal }
 }
 
 pub fn build_mapper(cartridge: Cartridge) -> Box<dyn Mapper> {
@@ -1,47 +1,92 @@
 use crate::mapper::Mapper;
-use crate::cartridge::Cartridge;
+use crate::cartridge::{Cartridge, Mirroring};
 
 pub struct MapperNROM {
     cartridge: Cartridge,
     nrom_128: bool,
+    prg_ram: Vec<u8>,
+    // Empty when the cartridge has chr rom
+    chr_ram: Vec<u8>,
+    mirroring: Mirroring,
+    // The second 2KiB of nametables on four screen boards, empty otherwise
+    vram: Vec<u8>,
 }
 
 impl Mapper for MapperNROM {
-    fn read_prg_rom(&self, addr: u16) -> u8 {
-	let mut addr = addr - 0x8000;
-	if self.nrom_128 {
-	    addr &= 0x3fff;
+    fn peek_prg(&self, addr: u16) -> Option<u8> {
+	match addr {
+	    Self::PRG_RAM_START..=Self::PRG_RAM_END => Some(self.prg_ram[(addr - Self::PRG_RAM_START) as usize]),
+	    Self::PRG_ROM_START..=0xffff => {
+		let mut addr = addr - Self::PRG_ROM_START;
+		if self.nrom_128 {
+		    addr &= 0x3fff;
+		}
+		Some(self.cartridge.read_prg_rom(addr))
+	    },
+	    _ => None,
 	}
-	self.cartridge.read_prg_rom(addr)
     }
 
-    fn write_prg_rom(&self, addr: u16, data: u8) {
-	println!("PRG ROM memory write: addr {:x} data {:x}", addr, data);
+    fn write_prg(&mut self, addr: u16, data: u8) -> bool {
+	match addr {
+	    Self::PRG_RAM_START..=Self::PRG_RAM_END => {
+		self.prg_ram[(addr - Self::PRG_RAM_START) as usize] = data;
+		true
+	    },
+	    // No registers, rom writes go nowhere
+	    _ => false,
+	}
     }
 
     fn read_chr(&self, addr: u16) -> u8 {
-	if self.cartridge.uses_chr_ram() {
-	    std::todo!("NROM chr ram unimplemented");
-	} else {
+	if self.chr_ram.is_empty() {
 	    self.cartridge.read_chr_rom(addr)
+	} else {
+	    self.chr_ram[addr as usize]
 	}
     }
 
-    fn write_chr(&self, addr: u16, data: u8) {
-	if self.cartridge.uses_chr_ram() {
-	    std::todo!("NROM chr ram unimplemented");
-	} else {
-	    println!("CHR ROM memory write: addr {:x} data {:x}", addr, data);
+    fn write_chr(&mut self, addr: u16, data: u8) {
+	// chr rom ignores writes
+	if let Some(b) = self.chr_ram.get_mut(addr as usize) {
+	    *b = data;
+	}
+    }
+
+    fn mirroring(&self) -> Mirroring {
+	self.mirroring
+    }
+
+    fn read_nametable(&self, addr: u16) -> u8 {
+	self.vram.get((addr & 0x07ff) as usize).copied().unwrap_or(0)
+    }
+
+    fn write_nametable(&mut self, addr: u16, data: u8) {
+	if let Some(b) = self.vram.get_mut((addr & 0x07ff) as usize) {
+	    *b = data;
 	}
     }
 }
 
 impl MapperNROM {
+    const PRG_RAM_START: u16 = 0x6000;
+    const PRG_RAM_END: u16 = 0x7fff;
+    const PRG_ROM_START: u16 = 0x8000;
+
     pub fn new(cartridge: Cartridge) -> Self {
 	let nrom_128 = cartridge.prg_rom_sz() == 0x4000;
+	let chr_ram = if cartridge.uses_chr_ram() { vec![0; 0x2000] } else { Vec::new() };
+	let mirroring = cartridge.mirroring();
+	let vram = if mirroring == Mirroring::FourScreen { vec![0; 0x800] } else { Vec::new() };
 	Self {
 	    cartridge,
 	    nrom_128,
+	    // Only Family Basic boards have it, but headers rarely say so and
+	    // homebrew expects it
+	    prg_ram: vec![0; 0x2000],
+	    chr_ram,
+	    mirroring,
+	    vram,
 	}
     }
 }
@@ -0,0 +1,146 @@
+use super::err::EmuErr;
+
+/// The cpu's view of the address space it's attached to.
+///
+/// The 6502 core only talks to memory through this trait, so the same core
+/// runs on the NES `Bus` or on plain `Ram` for standalone 6502 test suites.
+pub trait Memory {
+    fn read(&mut self, addr: u16) -> u8;
+    fn write(&mut self, addr: u16, data: u8);
+
+    /// Reads `addr` without side effects, for tooling. `None` when the
+    /// value can't be known without disturbing the device behind it.
+    fn peek(&self, _addr: u16) -> Option<u8> { None }
+
+    /// Clocks whatever else is attached to the bus by one cpu cycle. Only
+    /// called by a cycle stepped cpu, right before each bus access.
+    fn tick(&mut self) -> Result<(), EmuErr> { Ok(()) }
+
+    /// Takes the next DMA waiting to steal cycles from the cpu. Polled at
+    /// the end of every instruction.
+    fn take_dma(&mut self) -> Option<Dma> { None }
+
+    /// Whether an NMI edge is waiting to be serviced. Polled before every
+    /// instruction.
+    fn nmi_pending(&self) -> bool { false }
+
+    /// The cpu has started the NMI sequence
+    fn acknowledge_nmi(&mut self) {}
+
+    /// Whether the IRQ line is asserted
+    fn irq(&self) -> bool { false }
+}
+
+/// A DMA transfer holding the cpu off the bus. The device copies the data,
+/// the cpu sits out the cycles the transfer takes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dma {
+    /// 256 bytes copied into ppu OAM through $4014
+    Oam,
+    /// A sample byte fetched for the APU's delta modulation channel
+    Dmc,
+}
+
+impl Dma {
+    /// Cycles stolen when the transfer starts on cycle `cycle`, with
+    /// `oam_left` cycles of an OAM DMA still to go. OAM DMA spends one cycle
+    /// halting the cpu, one more to line up with a read cycle when starting
+    /// on an odd cycle, then alternates 256 reads and writes.
+    ///
+    /// A DMC fetch halts the cpu, spends a dummy cycle and lines up with a
+    /// read cycle the same way, so it takes 3 or 4 cycles. During an OAM DMA
+    /// the cpu is already halted: the fetch takes a read cycle from the OAM
+    /// DMA, which spends one more lining up again, unless only its last
+    /// write was left.
+    pub fn cycles(self, cycle: usize, oam_left: usize) -> usize {
+	match self {
+	    Dma::Oam => 513 + cycle % 2,
+	    Dma::Dmc if oam_left > 1 => 2,
+	    Dma::Dmc if oam_left == 1 => 1,
+	    Dma::Dmc => 4 - cycle % 2,
+	}
+    }
+}
+
+/// Contents of ram at power on. Real consoles come up with mostly, but not
+/// reliably, random ram, and some games seed their RNG from it.
+#[allow(dead_code)]
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum RamInit {
+    #[default]
+    Zeros,
+    /// Every byte 0xFF
+    Ones,
+    /// The bytes repeated over the whole ram
+    Pattern(Vec<u8>),
+    /// Pseudo random bytes, the same ones for the same seed
+    Random(u64),
+}
+
+impl RamInit {
+    pub fn fill(&self, ram: &mut [u8]) {
+	match self {
+	    RamInit::Zeros => ram.fill(0),
+	    RamInit::Ones => ram.fill(0xff),
+	    RamInit::Pattern(pattern) if pattern.is_empty() => ram.fill(0),
+	    RamInit::Pattern(pattern) => {
+		for (b, p) in ram.iter_mut().zip(pattern.iter().cycle()) {
+		    *b = *p;
+		}
+	    },
+	    RamInit::Random(seed) => {
+		// xorshift64*, the state must not be zero
+		let mut state = seed ^ 0x9e37_79b9_7f4a_7c15;
+		if state == 0 {
+		    state = 1;
+		}
+		for b in ram.iter_mut() {
+		    state ^= state >> 12;
+		    state ^= state << 25;
+		    state ^= state >> 27;
+		    *b = (state.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 56) as u8;
+		}
+	    },
+	}
+    }
+}
+
+/// Flat 64KiB of RAM with nothing else attached
+pub struct Ram {
+    mem: Vec<u8>,
+}
+
+#[allow(dead_code)]
+impl Ram {
+    pub fn new() -> Self {
+	Self {
+	    mem: vec![0;0x10000],
+	}
+    }
+
+    /// Copies `data` into memory starting at `addr`
+    pub fn load(&mut self, addr: u16, data: &[u8]) {
+	let start = addr as usize;
+	self.mem[start..start + data.len()].copy_from_slice(data);
+    }
+}
+
+impl Default for Ram {
+    fn default() -> Self {
+	Self::new()
+    }
+}
+
+impl Memory for Ram {
+    fn read(&mut self, addr: u16) -> u8 {
+	self.mem[addr as usize]
+    }
+
+    fn write(&mut self, addr: u16, data: u8) {
+	self.mem[addr as usize] = data;
+    }
+
+    fn peek(&self, addr: u16) -> Option<u8> {
+	Some(self.mem[addr as usize])
+    }
+}
@@ -1,6 +1,6 @@
 
 /// 6502 Opcodes
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 #[allow(clippy::upper_case_acronyms)] // I happen to think uppercase enum variants look better :)
 pub enum Op {
     ADC,
@@ -81,6 +81,16 @@ pub enum Op {
     SRE,
     TAS,
     XAA,
+
+    // 65C02 Opcodes
+    BRA,
+    PHX,
+    PHY,
+    PLX,
+    PLY,
+    STZ,
+    TRB,
+    TSB,
 }
 
 /// Addressing Modes
@@ -91,13 +101,15 @@ pub enum Op {
 /// - IMM: immedaite
 /// - IMP: implicit (e.g. RTS or CLC which have no address operand)
 /// - IND: indirect (JMP has special addressing mode that can jump to address stored in a 16 bit ptr anywhere in memory)
+/// - IAX: absolute indexed indirect, 65C02 only. JMP to the address stored at (arg + X)
 /// - INX: indexed indirect. val = PEEK(PEEK((arg + X) % 256) + PEEK((arg + X + 1) % 256) * 256) 6 cycles
 /// - INY: indirect indexed. val = PEEK(PEEK(arg) + PEEK((arg + 1) % 256) * 256 + Y) 5+ cycles
 /// - REL: relative (BEQ, BCS)
 /// - ZPG: zero page. Fetches value from an 8 bit addr on zero page
 /// - ZPX: zero page indexed by X. val = PEEK((arg + X) % 256)
 /// - ZPY: zero page indexed by Y. val = PEEK((arg + Y) % 256)
-#[derive(Debug, Clone, Copy)]
+/// - ZPI: zero page indirect, 65C02 only. val = PEEK(PEEK(arg) + PEEK((arg + 1) % 256) * 256) 5 cycles
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[allow(clippy::upper_case_acronyms)]
 pub enum AM {
     ABS,
@@ -106,14 +118,27 @@ pub enum AM {
     IMM,
     IMP,
     IND,
+    IAX,
     INX,
     INY,
     REL,
     ZPG,
     ZPX,
     ZPY,
+    ZPI,
 }
 
+impl AM {
+    /// Operand bytes following the opcode
+    pub fn operand_len(self) -> u16 {
+	match self {
+	    AM::IMP => 0,
+	    AM::IMM | AM::INX | AM::INY | AM::REL |
+	    AM::ZPG | AM::ZPX | AM::ZPY | AM::ZPI => 1,
+	    AM::ABS | AM::ABX | AM::ABY | AM::IND | AM::IAX => 2,
+	}
+    }
+}
 
 /// Instruction
 #[derive(Debug, Clone, Copy)]
@@ -131,6 +156,79 @@ impl I {
 	    addr_mode,
 	}
     }
+
+    /// How the instruction accesses its operand. This decides which dummy
+    /// cycles are spent resolving indexed addresses.
+    pub fn access(&self) -> Access {
+	match self.opcode {
+	    Op::STA | Op::STX | Op::STY | Op::SAX |
+	    Op::AHX | Op::SHX | Op::SHY | Op::TAS | Op::STZ => Access::Write,
+	    Op::ASL | Op::LSR | Op::ROL | Op::ROR | Op::INC | Op::DEC |
+	    Op::SLO | Op::SRE | Op::RLA | Op::RRA | Op::DCP | Op::ISC |
+	    Op::TRB | Op::TSB => Access::ReadModifyWrite,
+	    _ => Access::Read,
+	}
+    }
+}
+
+/// Memory access performed by an instruction on its operand
+#[derive(Debug, Clone, Copy)]
+pub enum Access {
+    Read,
+    Write,
+    ReadModifyWrite,
+}
+
+/// Member of the 6502 family being emulated
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Variant {
+    /// The NES cpu: an NMOS 6502 with decimal mode disconnected.
+    #[default]
+    Ricoh2A03,
+    /// NMOS 6502 with working decimal mode.
+    Nmos6502,
+    /// CMOS 65C02. Adds opcodes, turns the illegal ones into NOPs and fixes
+    /// the JMP indirect page wrap bug.
+    Cmos65C02,
+}
+
+impl Variant {
+    /// Opcode table decoding instructions for this variant
+    pub fn opcodes(self) -> &'static [[I; 16]; 16] {
+	match self {
+	    Variant::Ricoh2A03 | Variant::Nmos6502 => &OPCODES,
+	    Variant::Cmos65C02 => &OPCODES_65C02,
+	}
+    }
+
+    /// Decodes `opcode` for this variant
+    pub fn decode(self, opcode: u8) -> I {
+	self.opcodes()[(opcode >> 4) as usize][(opcode & 0xf) as usize]
+    }
+
+    /// Whether `opcode` is outside the documented instruction set: the
+    /// illegal opcodes, every NOP but $EA and the $EB copy of SBC.
+    pub fn is_unofficial(self, opcode: u8) -> bool {
+	match self.decode(opcode).opcode {
+	    Op::NOP => opcode != 0xEA,
+	    Op::SBC => opcode == 0xEB,
+	    Op::AHX | Op::ALR | Op::ANC | Op::ARR | Op::AXS | Op::DCP |
+	    Op::ISC | Op::KIL | Op::LAS | Op::LAX | Op::RLA | Op::RRA |
+	    Op::SAX | Op::SHX | Op::SHY | Op::SLO | Op::SRE | Op::TAS |
+	    Op::XAA | Op::_XXX => true,
+	    _ => false,
+	}
+    }
+
+    /// Whether ADC and SBC honour the D flag
+    pub fn has_decimal(self) -> bool {
+	!matches!(self, Variant::Ricoh2A03)
+    }
+
+    pub fn is_cmos(self) -> bool {
+	matches!(self, Variant::Cmos65C02)
+    }
 }
 
 /// Table reference: http://www.oxyron.de/html/opcodes02.html
@@ -152,3 +250,24 @@ pub const OPCODES: [[I; 16]; 16] = [
 [I::new(Op::CPX,2,AM::IMM),I::new(Op::SBC,6,AM::INX),I::new(Op::NOP,2,AM::IMM),I::new(Op::ISC,8,AM::INX),I::new(Op::CPX,3,AM::ZPG),I::new(Op::SBC,3,AM::ZPG),I::new(Op::INC,5,AM::ZPG),I::new(Op::ISC,5,AM::ZPG),I::new(Op::INX,2,AM::IMP),I::new(Op::SBC,2,AM::IMM),I::new(Op::NOP,2,AM::IMP),I::new(Op::SBC,2,AM::IMM),I::new(Op::CPX,4,AM::ABS),I::new(Op::SBC,4,AM::ABS),I::new(Op::INC,6,AM::ABS),I::new(Op::ISC,6,AM::ABS),],
 [I::new(Op::BEQ,2,AM::REL),I::new(Op::SBC,5,AM::INY),I::new(Op::KIL,0,AM::IMP),I::new(Op::ISC,8,AM::INY),I::new(Op::NOP,4,AM::ZPX),I::new(Op::SBC,4,AM::ZPX),I::new(Op::INC,6,AM::ZPX),I::new(Op::ISC,6,AM::ZPX),I::new(Op::SED,2,AM::IMP),I::new(Op::SBC,4,AM::ABY),I::new(Op::NOP,2,AM::IMP),I::new(Op::ISC,7,AM::ABY),I::new(Op::NOP,4,AM::ABX),I::new(Op::SBC,4,AM::ABX),I::new(Op::INC,7,AM::ABX),I::new(Op::ISC,7,AM::ABX),],
 ];
+
+/// 65C02 table. Unused opcodes are NOPs, one byte and one cycle unless noted.
+/// Table reference: http://www.6502.org/tutorials/65c02opcodes.html
+pub const OPCODES_65C02: [[I; 16]; 16] = [
+[I::new(Op::BRK,7,AM::IMP),I::new(Op::ORA,6,AM::INX),I::new(Op::NOP,2,AM::IMM),I::new(Op::NOP,1,AM::IMP),I::new(Op::TSB,5,AM::ZPG),I::new(Op::ORA,3,AM::ZPG),I::new(Op::ASL,5,AM::ZPG),I::new(Op::NOP,1,AM::IMP),I::new(Op::PHP,3,AM::IMP),I::new(Op::ORA,2,AM::IMM),I::new(Op::ASL,2,AM::IMP),I::new(Op::NOP,1,AM::IMP),I::new(Op::TSB,6,AM::ABS),I::new(Op::ORA,4,AM::ABS),I::new(Op::ASL,6,AM::ABS),I::new(Op::NOP,1,AM::IMP),],
+[I::new(Op::BPL,2,AM::REL),I::new(Op::ORA,5,AM::INY),I::new(Op::ORA,5,AM::ZPI),I::new(Op::NOP,1,AM::IMP),I::new(Op::TRB,5,AM::ZPG),I::new(Op::ORA,4,AM::ZPX),I::new(Op::ASL,6,AM::ZPX),I::new(Op::NOP,1,AM::IMP),I::new(Op::CLC,2,AM::IMP),I::new(Op::ORA,4,AM::ABY),I::new(Op::INC,2,AM::IMP),I::new(Op::NOP,1,AM::IMP),I::new(Op::TRB,6,AM::ABS),I::new(Op::ORA,4,AM::ABX),I::new(Op::ASL,6,AM::ABX),I::new(Op::NOP,1,AM::IMP),],
+[I::new(Op::JSR,6,AM::ABS),I::new(Op::AND,6,AM::INX),I::new(Op::NOP,2,AM::IMM),I::new(Op::NOP,1,AM::IMP),I::new(Op::BIT,3,AM::ZPG),I::new(Op::AND,3,AM::ZPG),I::new(Op::ROL,5,AM::ZPG),I::new(Op::NOP,1,AM::IMP),I::new(Op::PLP,4,AM::IMP),I::new(Op::AND,2,AM::IMM),I::new(Op::ROL,2,AM::IMP),I::new(Op::NOP,1,AM::IMP),I::new(Op::BIT,4,AM::ABS),I::new(Op::AND,4,AM::ABS),I::new(Op::ROL,6,AM::ABS),I::new(Op::NOP,1,AM::IMP),],
+[I::new(Op::BMI,2,AM::REL),I::new(Op::AND,5,AM::INY),I::new(Op::AND,5,AM::ZPI),I::new(Op::NOP,1,AM::IMP),I::new(Op::BIT,4,AM::ZPX),I::new(Op::AND,4,AM::ZPX),I::new(Op::ROL,6,AM::ZPX),I::new(Op::NOP,1,AM::IMP),I::new(Op::SEC,2,AM::IMP),I::new(Op::AND,4,AM::ABY),I::new(Op::DEC,2,AM::IMP),I::new(Op::NOP,1,AM::IMP),I::new(Op::BIT,4,AM::ABX),I::new(Op::AND,4,AM::ABX),I::new(Op::ROL,6,AM::ABX),I::new(Op::NOP,1,AM::IMP),],
+[I::new(Op::RTI,6,AM::IMP),I::new(Op::EOR,6,AM::INX),I::new(Op::NOP,2,AM::IMM),I::new(Op::NOP,1,AM::IMP),I::new(Op::NOP,3,AM::ZPG),I::new(Op::EOR,3,AM::ZPG),I::new(Op::LSR,5,AM::ZPG),I::new(Op::NOP,1,AM::IMP),I::new(Op::PHA,3,AM::IMP),I::new(Op::EOR,2,AM::IMM),I::new(Op::LSR,2,AM::IMP),I::new(Op::NOP,1,AM::IMP),I::new(Op::JMP,3,AM::ABS),I::new(Op::EOR,4,AM::ABS),I::new(Op::LSR,6,AM::ABS),I::new(Op::NOP,1,AM::IMP),],
+[I::new(Op::BVC,2,AM::REL),I::new(Op::EOR,5,AM::INY),I::new(Op::EOR,5,AM::ZPI),I::new(Op::NOP,1,AM::IMP),I::new(Op::NOP,4,AM::ZPX),I::new(Op::EOR,4,AM::ZPX),I::new(Op::LSR,6,AM::ZPX),I::new(Op::NOP,1,AM::IMP),I::new(Op::CLI,2,AM::IMP),I::new(Op::EOR,4,AM::ABY),I::new(Op::PHY,3,AM::IMP),I::new(Op::NOP,1,AM::IMP),I::new(Op::NOP,8,AM::ABS),I::new(Op::EOR,4,AM::ABX),I::new(Op::LSR,6,AM::ABX),I::new(Op::NOP,1,AM::IMP),],
+[I::new(Op::RTS,6,AM::IMP),I::new(Op::ADC,6,AM::INX),I::new(Op::NOP,2,AM::IMM),I::new(Op::NOP,1,AM::IMP),I::new(Op::STZ,3,AM::ZPG),I::new(Op::ADC,3,AM::ZPG),I::new(Op::ROR,5,AM::ZPG),I::new(Op::NOP,1,AM::IMP),I::new(Op::PLA,4,AM::IMP),I::new(Op::ADC,2,AM::IMM),I::new(Op::ROR,2,AM::IMP),I::new(Op::NOP,1,AM::IMP),I::new(Op::JMP,6,AM::IND),I::new(Op::ADC,4,AM::ABS),I::new(Op::ROR,6,AM::ABS),I::new(Op::NOP,1,AM::IMP),],
+[I::new(Op::BVS,2,AM::REL),I::new(Op::ADC,5,AM::INY),I::new(Op::ADC,5,AM::ZPI),I::new(Op::NOP,1,AM::IMP),I::new(Op::STZ,4,AM::ZPX),I::new(Op::ADC,4,AM::ZPX),I::new(Op::ROR,6,AM::ZPX),I::new(Op::NOP,1,AM::IMP),I::new(Op::SEI,2,AM::IMP),I::new(Op::ADC,4,AM::ABY),I::new(Op::PLY,4,AM::IMP),I::new(Op::NOP,1,AM::IMP),I::new(Op::JMP,6,AM::IAX),I::new(Op::ADC,4,AM::ABX),I::new(Op::ROR,6,AM::ABX),I::new(Op::NOP,1,AM::IMP),],
+[I::new(Op::BRA,3,AM::REL),I::new(Op::STA,6,AM::INX),I::new(Op::NOP,2,AM::IMM),I::new(Op::NOP,1,AM::IMP),I::new(Op::STY,3,AM::ZPG),I::new(Op::STA,3,AM::ZPG),I::new(Op::STX,3,AM::ZPG),I::new(Op::NOP,1,AM::IMP),I::new(Op::DEY,2,AM::IMP),I::new(Op::BIT,2,AM::IMM),I::new(Op::TXA,2,AM::IMP),I::new(Op::NOP,1,AM::IMP),I::new(Op::STY,4,AM::ABS),I::new(Op::STA,4,AM::ABS),I::new(Op::STX,4,AM::ABS),I::new(Op::NOP,1,AM::IMP),],
+[I::new(Op::BCC,2,AM::REL),I::new(Op::STA,6,AM::INY),I::new(Op::STA,5,AM::ZPI),I::new(Op::NOP,1,AM::IMP),I::new(Op::STY,4,AM::ZPX),I::new(Op::STA,4,AM::ZPX),I::new(Op::STX,4,AM::ZPY),I::new(Op::NOP,1,AM::IMP),I::new(Op::TYA,2,AM::IMP),I::new(Op::STA,5,AM::ABY),I::new(Op::TXS,2,AM::IMP),I::new(Op::NOP,1,AM::IMP),I::new(Op::STZ,4,AM::ABS),I::new(Op::STA,5,AM::ABX),I::new(Op::STZ,5,AM::ABX),I::new(Op::NOP,1,AM::IMP),],
+[I::new(Op::LDY,2,AM::IMM),I::new(Op::LDA,6,AM::INX),I::new(Op::LDX,2,AM::IMM),I::new(Op::NOP,1,AM::IMP),I::new(Op::LDY,3,AM::ZPG),I::new(Op::LDA,3,AM::ZPG),I::new(Op::LDX,3,AM::ZPG),I::new(Op::NOP,1,AM::IMP),I::new(Op::TAY,2,AM::IMP),I::new(Op::LDA,2,AM::IMM),I::new(Op::TAX,2,AM::IMP),I::new(Op::NOP,1,AM::IMP),I::new(Op::LDY,4,AM::ABS),I::new(Op::LDA,4,AM::ABS),I::new(Op::LDX,4,AM::ABS),I::new(Op::NOP,1,AM::IMP),],
+[I::new(Op::BCS,2,AM::REL),I::new(Op::LDA,5,AM::INY),I::new(Op::LDA,5,AM::ZPI),I::new(Op::NOP,1,AM::IMP),I::new(Op::LDY,4,AM::ZPX),I::new(Op::LDA,4,AM::ZPX),I::new(Op::LDX,4,AM::ZPY),I::new(Op::NOP,1,AM::IMP),I::new(Op::CLV,2,AM::IMP),I::new(Op::LDA,4,AM::ABY),I::new(Op::TSX,2,AM::IMP),I::new(Op::NOP,1,AM::IMP),I::new(Op::LDY,4,AM::ABX),I::new(Op::LDA,4,AM::ABX),I::new(Op::LDX,4,AM::ABY),I::new(Op::NOP,1,AM::IMP),],
+[I::new(Op::CPY,2,AM::IMM),I::new(Op::CMP,6,AM::INX),I::new(Op::NOP,2,AM::IMM),I::new(Op::NOP,1,AM::IMP),I::new(Op::CPY,3,AM::ZPG),I::new(Op::CMP,3,AM::ZPG),I::new(Op::DEC,5,AM::ZPG),I::new(Op::NOP,1,AM::IMP),I::new(Op::INY,2,AM::IMP),I::new(Op::CMP,2,AM::IMM),I::new(Op::DEX,2,AM::IMP),I::new(Op::NOP,1,AM::IMP),I::new(Op::CPY,4,AM::ABS),I::new(Op::CMP,4,AM::ABS),I::new(Op::DEC,6,AM::ABS),I::new(Op::NOP,1,AM::IMP),],
+[I::new(Op::BNE,2,AM::REL),I::new(Op::CMP,5,AM::INY),I::new(Op::CMP,5,AM::ZPI),I::new(Op::NOP,1,AM::IMP),I::new(Op::NOP,4,AM::ZPX),I::new(Op::CMP,4,AM::ZPX),I::new(Op::DEC,6,AM::ZPX),I::new(Op::NOP,1,AM::IMP),I::new(Op::CLD,2,AM::IMP),I::new(Op::CMP,4,AM::ABY),I::new(Op::PHX,3,AM::IMP),I::new(Op::NOP,1,AM::IMP),I::new(Op::NOP,4,AM::ABS),I::new(Op::CMP,4,AM::ABX),I::new(Op::DEC,7,AM::ABX),I::new(Op::NOP,1,AM::IMP),],
+[I::new(Op::CPX,2,AM::IMM),I::new(Op::SBC,6,AM::INX),I::new(Op::NOP,2,AM::IMM),I::new(Op::NOP,1,AM::IMP),I::new(Op::CPX,3,AM::ZPG),I::new(Op::SBC,3,AM::ZPG),I::new(Op::INC,5,AM::ZPG),I::new(Op::NOP,1,AM::IMP),I::new(Op::INX,2,AM::IMP),I::new(Op::SBC,2,AM::IMM),I::new(Op::NOP,2,AM::IMP),I::new(Op::NOP,1,AM::IMP),I::new(Op::CPX,4,AM::ABS),I::new(Op::SBC,4,AM::ABS),I::new(Op::INC,6,AM::ABS),I::new(Op::NOP,1,AM::IMP),],
+[I::new(Op::BEQ,2,AM::REL),I::new(Op::SBC,5,AM::INY),I::new(Op::SBC,5,AM::ZPI),I::new(Op::NOP,1,AM::IMP),I::new(Op::NOP,4,AM::ZPX),I::new(Op::SBC,4,AM::ZPX),I::new(Op::INC,6,AM::ZPX),I::new(Op::NOP,1,AM::IMP),I::new(Op::SED,2,AM::IMP),I::new(Op::SBC,4,AM::ABY),I::new(Op::PLX,4,AM::IMP),I::new(Op::NOP,1,AM::IMP),I::new(Op::NOP,4,AM::ABS),I::new(Op::SBC,4,AM::ABX),I::new(Op::INC,7,AM::ABX),I::new(Op::NOP,1,AM::IMP),],
+];
@@ -1,7 +1,7 @@
-use std::cell::RefCell;
-use std::rc::Rc;
 use super::cartridge::Mirroring;
+use super::clock::Region;
 use super::err::EmuErr;
+use super::frame::Frame;
 use super::mapper::Mapper;
 
 /// Picture Processing Unit (PPU)
@@ -14,33 +14,59 @@ use super::mapper::Mapper;
 /// of Object Attribute Memory (OAM), and 32 bytes for pallete tables. The chr rom
 /// mapped onto the cartridge chr rom.
 pub struct Ppu {
-    pub nmi_signal: Rc<RefCell<bool>>,
-
     ctrl: CtrlReg,
     status: StatusReg,
     mask: MaskReg,
-    mirror: Mirroring,
+    // The ppu's own data bus, what reads of write only registers return
     buffer: u8,
+    // PPUDATA reads lag one behind, except for the palette
+    read_buffer: u8,
+    // w, the first/second write toggle shared by PPUSCROLL & PPUADDR
     address_latch: bool,
 
-    // background state
+    // Scroll and VRAM address, laid out as yyy NN YYYYY XXXXX: fine y,
+    // nametable, coarse y and coarse x.
+    // https://www.nesdev.org/wiki/PPU_scrolling
+    v: u16,
+    // v for the top left of the screen, copied into v during rendering
+    t: u16,
+
     name_tables: [u8;2*1024],
+    palette: [u8;32],
+
+    // background state
     bg_shift_h: u16,
     bg_shift_l: u16,
     at_shift_h: u8,
-    at_shit_l: u8,
+    at_shift_l: u8,
     at_latch_h: u8,
     at_latch_l: u8,
     fine_x: u8,
+    // Fetched for the next tile, loaded into the shifters every 8 dots
+    next_tile: u8,
+    next_attribute: u8,
+    next_pattern_l: u8,
+    next_pattern_h: u8,
 
     // sprite state
-    primary_oam: [u8;1],
-    secondary_oam: [u8;1],
+    oam: [u8;256],
+    oam_addr: u8,
+    // Up to 8 sprites found on the scanline for the next one
+    secondary_oam: [u8;32],
+    sprite_count: usize,
+    // Whether sprite 0 is the first of them
+    sprite_zero: bool,
+    // The fetched sprites: pattern planes, low then high, attributes and x
     shift_registers: [u8;16],
     sprite_latches: [u8;8],
     counters: [u8;8],
 
+    // output
+    picture: Frame,
+    frame_complete: bool,
+
     // rendering state
+    region: Region,
     cycle: usize,
     scanline: usize,
     frame: usize,
@@ -48,63 +74,625 @@ pub struct Ppu {
 
 impl Ppu {
 
-    pub fn new(nmi_signal: Rc<RefCell<bool>>) -> Self {
+    pub fn new() -> Self {
 	Self {
-	    nmi_signal,
-
 	    ctrl: CtrlReg::new(),
 	    status: StatusReg::new(),
 	    mask: MaskReg::new(),
-	    mirror: Mirroring::Horizontal,
 	    buffer: 0,
+	    read_buffer: 0,
 	    address_latch: false,
 
+	    v: 0,
+	    t: 0,
+
 	    name_tables: [0;2*1024],
+	    palette: [0;32],
 	    bg_shift_h: 0,
 	    bg_shift_l: 0,
 	    at_shift_h: 0,
-	    at_shit_l: 0,
+	    at_shift_l: 0,
 	    at_latch_h: 0,
 	    at_latch_l: 0,
 	    fine_x: 0,
+	    next_tile: 0,
+	    next_attribute: 0,
+	    next_pattern_l: 0,
+	    next_pattern_h: 0,
 
-	    primary_oam: [0;1],
-	    secondary_oam: [0;1],
+	    oam: [0;256],
+	    oam_addr: 0,
+	    secondary_oam: [0xff;32],
+	    sprite_count: 0,
+	    sprite_zero: false,
 	    shift_registers: [0;16],
 	    sprite_latches: [0;8],
 	    counters: [0;8],
 
+	    picture: Frame::new(),
+	    frame_complete: false,
+
+	    region: Region::default(),
 	    cycle: 0,
 	    scanline: 0,
 	    frame: 0,
 	}
     }
 
-    pub fn step(&mut self, _mapper: &dyn Mapper) -> Result<(), EmuErr> { Ok(()) }
+    /// Power up state. Only the region survives.
+    pub fn power_on(&mut self) {
+	let region = self.region;
+	*self = Self::new();
+	self.region = region;
+    }
+
+    pub fn set_region(&mut self, region: Region) {
+	self.region = region;
+    }
+
+    /// The reset line clears PPUCTRL, PPUMASK, PPUSCROLL, the write latch
+    /// and the read buffer. OAM, VRAM, v and the rendering position are
+    /// left alone.
+    pub fn reset(&mut self) {
+	self.ctrl = CtrlReg::new();
+	self.mask = MaskReg::new();
+	self.address_latch = false;
+	self.buffer = 0;
+	self.read_buffer = 0;
+	self.t = 0;
+	self.fine_x = 0;
+    }
+
+    const DOTS_PER_SCANLINE: usize = 341;
+    const VBLANK_SCANLINE: usize = 241;
+
+    /// The last scanline of the frame, 261 on NTSC and 311 on PAL
+    fn pre_render_scanline(&self) -> usize {
+	self.region.scanlines() - 1
+    }
+
+    /// Whether the odd frame dot is skipped at the end of this frame
+    fn skips_dot(&self) -> bool {
+	self.region == Region::Ntsc && self.rendering() && self.frame % 2 == 1
+    }
+
+    fn rendering(&self) -> bool {
+	self.mask.show_bg || self.mask.show_sp
+    }
+
+    /// Advances the PPU by one dot.
+    ///
+    /// A frame is 262 scanlines of 341 dots, 312 on PAL. On NTSC odd frames
+    /// with rendering enabled the last dot of the pre-render scanline is
+    /// skipped.
+    pub fn step(&mut self, mapper: &dyn Mapper) -> Result<(), EmuErr> {
+	if self.skips_dot() && self.scanline == self.pre_render_scanline() && self.cycle == Self::DOTS_PER_SCANLINE - 2 {
+	    self.cycle += 1;
+	}
+
+	self.cycle += 1;
+	if self.cycle == Self::DOTS_PER_SCANLINE {
+	    self.cycle = 0;
+	    self.scanline += 1;
+	    if self.scanline == self.region.scanlines() {
+		self.scanline = 0;
+		self.frame += 1;
+	    }
+	}
+
+	if self.cycle == 1 {
+	    if self.scanline == Self::VBLANK_SCANLINE {
+		self.status.vblank = true;
+		self.frame_complete = true;
+	    } else if self.scanline == self.pre_render_scanline() {
+		self.status.vblank = false;
+		self.status.sprite_zero_hit = false;
+		self.status.overflow = false;
+	    }
+	}
+
+	if self.rendering() && (self.scanline < Self::VISIBLE_SCANLINES || self.scanline == self.pre_render_scanline()) {
+	    self.background(mapper);
+	    self.sprites(mapper);
+	}
+	if self.scanline < Self::VISIBLE_SCANLINES && (1..=256).contains(&self.cycle) {
+	    let pixel = self.render_pixel();
+	    self.output(pixel);
+	}
+	Ok(())
+    }
+
+    const VISIBLE_SCANLINES: usize = 240;
+
+    /// The background fetch pipeline for the current dot of a visible or
+    /// the pre-render scanline.
+    /// https://www.nesdev.org/wiki/PPU_rendering
+    ///
+    /// Each tile takes 8 dots: nametable byte, attribute byte, then the low
+    /// and high pattern planes, two dots each, after which coarse x moves to
+    /// the next tile. Dots [1,256] fetch tiles 2 to 33 of the scanline while
+    /// shifting out pixels, dots [321,336] prefetch the first two tiles of the
+    /// next scanline.
+    fn background(&mut self, mapper: &dyn Mapper) {
+	let cycle = self.cycle;
+	if (2..=257).contains(&cycle) || (322..=337).contains(&cycle) {
+	    self.shift_background();
+	}
+	if (1..=256).contains(&cycle) || (321..=336).contains(&cycle) {
+	    match cycle % 8 {
+		1 => {
+		    self.load_background();
+		    self.next_tile = self.read_vram(0x2000 | (self.v & 0x0fff), mapper);
+		},
+		3 => {
+		    let v = self.v;
+		    let at = self.read_vram(0x23c0 | (v & 0x0c00) | ((v >> 4) & 0x38) | ((v >> 2) & 0x07), mapper);
+		    // two bits for each 16x16 quadrant of the 32x32 attribute area
+		    let shift = ((v >> 4) & 4) | (v & 2);
+		    self.next_attribute = (at >> shift) & 0b11;
+		},
+		5 => self.next_pattern_l = self.read_vram(self.pattern_addr(), mapper),
+		7 => self.next_pattern_h = self.read_vram(self.pattern_addr() + 8, mapper),
+		0 => self.increment_x(),
+		_ => (),
+	    }
+	}
+	match cycle {
+	    256 => self.increment_y(),
+	    257 => {
+		self.load_background();
+		// horizontal position from t
+		self.v = (self.v & !0x041f) | (self.t & 0x041f);
+	    },
+	    // vertical position from t, for the top of the next frame
+	    280..=304 if self.scanline == self.pre_render_scanline() => {
+		self.v = (self.v & !0x7be0) | (self.t & 0x7be0);
+	    },
+	    // unused nametable fetches, some mappers count them
+	    338 | 340 => {
+		self.next_tile = self.read_vram(0x2000 | (self.v & 0x0fff), mapper);
+	    },
+	    _ => (),
+	}
+    }
+
+    /// Pattern table address of the next tile's low plane, at the fine y
+    /// scroll
+    fn pattern_addr(&self) -> u16 {
+	let table = if self.ctrl.bg_pattern_table_addr { 0x1000 } else { 0 };
+	table | (self.next_tile as u16) << 4 | (self.v >> 12)
+    }
+
+    /// Moves the fetched tile into the low byte of the pattern shifters and
+    /// its palette into the attribute latches
+    fn load_background(&mut self) {
+	self.bg_shift_l = (self.bg_shift_l & 0xff00) | self.next_pattern_l as u16;
+	self.bg_shift_h = (self.bg_shift_h & 0xff00) | self.next_pattern_h as u16;
+	self.at_latch_l = self.next_attribute & 1;
+	self.at_latch_h = self.next_attribute >> 1;
+    }
 
-    pub fn write(&mut self, addr: u16, data: u8) {
-	match addr {
-	    0x2000 => self.ctrl.write(data),
+    fn shift_background(&mut self) {
+	self.bg_shift_l <<= 1;
+	self.bg_shift_h <<= 1;
+	self.at_shift_l = (self.at_shift_l << 1) | self.at_latch_l;
+	self.at_shift_h = (self.at_shift_h << 1) | self.at_latch_h;
+    }
+
+    /// Next tile to the right, wrapping into the horizontally adjacent
+    /// nametable
+    fn increment_x(&mut self) {
+	if self.v & 0x001f == 31 {
+	    self.v &= !0x001f;
+	    self.v ^= 0x0400;
+	} else {
+	    self.v += 1;
+	}
+    }
+
+    /// Next pixel row down. Coarse y wraps into the vertically adjacent
+    /// nametable after row 29, rows 30 and 31 hold attributes and wrap in
+    /// place.
+    fn increment_y(&mut self) {
+	if self.v & 0x7000 != 0x7000 {
+	    self.v += 0x1000;
+	    return;
+	}
+	self.v &= !0x7000;
+	let mut y = (self.v & 0x03e0) >> 5;
+	if y == 29 {
+	    y = 0;
+	    self.v ^= 0x0800;
+	} else if y == 31 {
+	    y = 0;
+	} else {
+	    y += 1;
+	}
+	self.v = (self.v & !0x03e0) | (y << 5);
+    }
+
+    fn sprite_height(&self) -> usize {
+	if self.ctrl.sprite_sz { 16 } else { 8 }
+    }
+
+    /// The sprite half of the pipeline for the current dot of a visible or
+    /// the pre-render scanline.
+    /// https://www.nesdev.org/wiki/PPU_sprite_evaluation
+    ///
+    /// Once the scanline's pixels are out, the sprites on it are evaluated
+    /// into secondary OAM and dots [257,320] fetch their patterns, 8 dots per
+    /// sprite, ready for the next scanline. Sprites are never drawn on
+    /// scanline 0 as the pre-render scanline does not evaluate.
+    fn sprites(&mut self, mapper: &dyn Mapper) {
+	let cycle = self.cycle;
+	if cycle == 257 {
+	    if self.scanline == self.pre_render_scanline() {
+		self.sprite_count = 0;
+		self.sprite_zero = false;
+	    } else {
+		self.evaluate_sprites();
+	    }
+	}
+	if (257..=320).contains(&cycle) {
+	    self.oam_addr = 0;
+	    let slot = (cycle - 257) / 8;
+	    match (cycle - 257) % 8 {
+		4 => {
+		    self.sprite_latches[slot] = self.secondary_oam[slot * 4 + 2];
+		    self.counters[slot] = self.secondary_oam[slot * 4 + 3];
+		    self.shift_registers[slot * 2] = self.sprite_pattern(slot, 0, mapper);
+		},
+		6 => self.shift_registers[slot * 2 + 1] = self.sprite_pattern(slot, 8, mapper),
+		_ => (),
+	    }
+	}
+    }
+
+    /// Copies the first 8 sprites on the current scanline into secondary
+    /// OAM.
+    ///
+    /// Past the eighth the hardware keeps looking for a ninth to set the
+    /// overflow flag, but it steps the byte offset along with the sprite
+    /// index. It checks the tile, attribute and x bytes of later sprites as
+    /// if they were y, so both misses real overflows and reports false ones.
+    fn evaluate_sprites(&mut self) {
+	let height = self.sprite_height();
+	let line = self.scanline;
+	let in_range = |y: u8| line.wrapping_sub(y as usize) < height;
+
+	self.secondary_oam = [0xff;32];
+	self.sprite_count = 0;
+	self.sprite_zero = false;
+	let mut n = 0;
+	while n < 64 && self.sprite_count < 8 {
+	    if in_range(self.oam[n * 4]) {
+		let slot = self.sprite_count * 4;
+		self.secondary_oam[slot..slot + 4].copy_from_slice(&self.oam[n * 4..n * 4 + 4]);
+		self.sprite_zero |= n == 0;
+		self.sprite_count += 1;
+	    }
+	    n += 1;
+	}
+
+	let mut m = 0;
+	while n < 64 {
+	    if in_range(self.oam[n * 4 + m]) {
+		self.status.overflow = true;
+		break;
+	    }
+	    n += 1;
+	    m = (m + 1) & 3;
+	}
+    }
+
+    /// Fetches one pattern plane, `plane` 0 or 8, of the sprite in `slot`
+    /// for the next scanline, flipped so the leftmost pixel is the high bit.
+    /// Empty slots are transparent.
+    fn sprite_pattern(&self, slot: usize, plane: u16, mapper: &dyn Mapper) -> u8 {
+	let sprite = &self.secondary_oam[slot * 4..slot * 4 + 4];
+	let (y, mut tile, attribute) = (sprite[0], sprite[1] as u16, sprite[2]);
+	let height = self.sprite_height();
+	let mut row = self.scanline.wrapping_sub(y as usize) & (height - 1);
+	if attribute & 0x80 != 0 {
+	    row = height - 1 - row;
+	}
+	let table = if height == 16 {
+	    // 8x16 sprites pick their table with bit 0 of the tile, the
+	    // bottom half is the next tile
+	    let table = (tile & 1) << 12;
+	    tile &= 0xfe;
+	    if row >= 8 {
+		tile += 1;
+		row -= 8;
+	    }
+	    table
+	} else if self.ctrl.sprite_pattern_table_addr {
+	    0x1000
+	} else {
+	    0
+	};
+	let data = self.read_vram(table | tile << 4 | plane | row as u16, mapper);
+	if slot >= self.sprite_count {
+	    0
+	} else if attribute & 0x40 != 0 {
+	    data.reverse_bits()
+	} else {
+	    data
+	}
+    }
+
+    /// The first opaque sprite pixel at the current dot, with the slot it
+    /// came from: palette in the high two bits and color in the low two.
+    fn sprite_pixel(&self) -> Option<(usize, u8)> {
+	if !self.mask.show_sp || (self.cycle <= 8 && !self.mask.show_sp_left) {
+	    return None;
+	}
+	let x = self.cycle - 1;
+	(0..self.sprite_count).find_map(|slot| {
+	    let offset = x.wrapping_sub(self.counters[slot] as usize);
+	    if offset >= 8 {
+		return None;
+	    }
+	    let bit = 7 - offset;
+	    let color = ((self.shift_registers[slot * 2 + 1] >> bit) & 1) << 1 | ((self.shift_registers[slot * 2] >> bit) & 1);
+	    (color != 0).then(|| (slot, (self.sprite_latches[slot] & 0b11) << 2 | color))
+	})
+    }
+
+    /// Picks the background or the sprite pixel at the current dot and
+    /// flags a sprite 0 hit when both are opaque. Returns the palette ram
+    /// index to draw.
+    ///
+    /// Priority comes from the first opaque sprite only: a sprite behind the
+    /// background still hides the sprites after it.
+    fn render_pixel(&mut self) -> u8 {
+	let background = self.background_pixel();
+	let Some((slot, sprite)) = self.sprite_pixel() else {
+	    return background;
+	};
+	if background == 0 {
+	    return 0x10 | sprite;
+	}
+	// no hit at x=255
+	if slot == 0 && self.sprite_zero && self.cycle != 256 {
+	    self.status.sprite_zero_hit = true;
+	}
+	if self.sprite_latches[slot] & 0x20 != 0 {
+	    background
+	} else {
+	    0x10 | sprite
+	}
+    }
+
+    /// Draws the current dot with the color at palette ram index `pixel`,
+    /// greyscaled and emphasized as set in PPUMASK
+    fn output(&mut self, pixel: u8) {
+	let mut color = self.palette[pixel as usize] as u16;
+	if self.mask.grayscale {
+	    color &= 0x30;
+	}
+	let emphasis = (self.mask.em_red as u16) | (self.mask.em_green as u16) << 1 | (self.mask.em_blue as u16) << 2;
+	self.picture.set_pixel(self.cycle - 1, self.scanline, emphasis << 6 | color);
+    }
+
+    /// The background pixel at the current dot: palette in the high two
+    /// bits and color in the low two, 0 when transparent.
+    pub fn background_pixel(&self) -> u8 {
+	if !self.mask.show_bg || (self.cycle <= 8 && !self.mask.show_bg_left) {
+	    return 0;
+	}
+	let bit = 15 - self.fine_x as u16;
+	let color = ((self.bg_shift_h >> bit) & 1) << 1 | ((self.bg_shift_l >> bit) & 1);
+	if color == 0 {
+	    return 0;
+	}
+	let bit = 7 - self.fine_x;
+	let palette = ((self.at_shift_h >> bit) & 1) << 1 | ((self.at_shift_l >> bit) & 1);
+	palette << 2 | color as u8
+    }
+
+    /// Reads the ppu address space: pattern tables on the cartridge, the
+    /// nametables and the palette.
+    /// https://www.nesdev.org/wiki/PPU_memory_map
+    fn read_vram(&self, addr: u16, mapper: &dyn Mapper) -> u8 {
+	match addr & 0x3fff {
+	    0x0000..=0x1fff => mapper.read_chr(addr),
+	    0x3f00..=0x3fff => self.palette[Self::palette_index(addr)],
+	    addr => match Self::ciram_index(addr, mapper.mirroring()) {
+		Some(i) => self.name_tables[i],
+		None => mapper.read_nametable(addr),
+	    },
+	}
+    }
+
+    fn write_vram(&mut self, addr: u16, data: u8, mapper: &mut dyn Mapper) {
+	match addr & 0x3fff {
+	    0x0000..=0x1fff => mapper.write_chr(addr, data),
+	    0x3f00..=0x3fff => self.palette[Self::palette_index(addr)] = data & 0x3f,
+	    addr => match Self::ciram_index(addr, mapper.mirroring()) {
+		Some(i) => self.name_tables[i] = data,
+		None => mapper.write_nametable(addr, data),
+	    },
+	}
+    }
+
+    /// Where nametable address `addr` lands in the 2KiB of VRAM, [0x3000,0x3eff]
+    /// mirroring [0x2000,0x2eff]. `None` when the cartridge provides it.
+    fn ciram_index(addr: u16, mirroring: Mirroring) -> Option<usize> {
+	let table = (addr >> 10) & 0b11;
+	let page = match mirroring {
+	    Mirroring::Horizontal => table >> 1,
+	    Mirroring::Vertical => table & 1,
+	    Mirroring::SingleScreenA => 0,
+	    Mirroring::SingleScreenB => 1,
+	    Mirroring::FourScreen if table < 2 => table,
+	    Mirroring::FourScreen => return None,
+	};
+	Some((page << 10 | (addr & 0x03ff)) as usize)
+    }
+
+    /// Palette ram is 32 bytes mirrored through [0x3f00,0x3fff]. The
+    /// backdrop entries of the sprite palettes, 0x3f10/0x3f14/0x3f18/0x3f1c,
+    /// are the ones of the background palettes.
+    fn palette_index(addr: u16) -> usize {
+	let i = addr & 0x1f;
+	if i & 0x13 == 0x10 {
+	    (i & 0x0f) as usize
+	} else {
+	    i as usize
+	}
+    }
+
+    /// Moves v past a PPUDATA access, by 1 or 32 as set in PPUCTRL. While
+    /// rendering the access instead bumps both coarse x and y.
+    fn increment_vram_addr(&mut self) {
+	let rendering_line = self.scanline < Self::VISIBLE_SCANLINES || self.scanline == self.pre_render_scanline();
+	if self.rendering() && rendering_line {
+	    self.increment_x();
+	    self.increment_y();
+	} else {
+	    let inc = if self.ctrl.vram_address_inc { 32 } else { 1 };
+	    self.v = (self.v + inc) & 0x7fff;
+	}
+    }
+
+    /// Runs `dots` dots
+    pub fn run(&mut self, dots: u64, mapper: &dyn Mapper) -> Result<(), EmuErr> {
+	for _ in 0..dots {
+	    self.step(mapper)?;
+	}
+	Ok(())
+    }
+
+    /// Dots until the ppu next does something the cpu can notice without
+    /// touching a register: vblank starting or ending, which moves the NMI
+    /// line.
+    pub fn dots_until_event(&self) -> u64 {
+	let dot = |scanline: usize, cycle: usize| scanline * Self::DOTS_PER_SCANLINE + cycle;
+	let now = dot(self.scanline, self.cycle);
+	let frame = dot(self.region.scanlines(), 0);
+	let vblank = dot(Self::VBLANK_SCANLINE, 1);
+	let pre_render = dot(self.pre_render_scanline(), 1);
+	let next = if now < vblank {
+	    vblank - now
+	} else if now < pre_render {
+	    pre_render - now
+	} else {
+	    let skipped = self.skips_dot() && now < frame - 1;
+	    frame - now + vblank - skipped as usize
+	};
+	next as u64
+    }
+
+    /// The NMI output, held while vblank is flagged with NMI enabled
+    pub fn nmi_line(&self) -> bool {
+	self.status.vblank && self.ctrl.nmi
+    }
+
+    /// Current dot within the scanline, [0,340]
+    pub fn cycle(&self) -> usize { self.cycle }
+
+    /// Current scanline, [0,261]. 261 is the pre-render scanline.
+    pub fn scanline(&self) -> usize { self.scanline }
+
+    /// Registers are mirrored every 8 bytes through [0x2000,0x3fff]
+    fn register(addr: u16) -> u16 {
+	0x2000 | (addr & 7)
+    }
+
+    pub fn write(&mut self, addr: u16, data: u8, mapper: &mut dyn Mapper) {
+	self.buffer = data;
+	match Self::register(addr) {
+	    0x2000 => {
+		self.ctrl.write(data);
+		self.t = (self.t & !0x0c00) | ((data as u16 & 0b11) << 10);
+	    },
 	    0x2001 => self.mask.write(data),
+	    0x2003 => self.oam_addr = data,
+	    0x2004 => {
+		self.oam[self.oam_addr as usize] = data;
+		self.oam_addr = self.oam_addr.wrapping_add(1);
+	    },
+	    // PPUSCROLL: x then y
+	    0x2005 => {
+		if !self.address_latch {
+		    self.t = (self.t & !0x001f) | (data >> 3) as u16;
+		    self.fine_x = data & 0b111;
+		} else {
+		    self.t = (self.t & !0x73e0) | ((data & 0b111) as u16) << 12 | ((data >> 3) as u16) << 5;
+		}
+		self.address_latch = !self.address_latch;
+	    },
+	    // PPUADDR: high then low byte, the second write sets v
+	    0x2006 => {
+		if !self.address_latch {
+		    self.t = (self.t & 0x00ff) | ((data & 0x3f) as u16) << 8;
+		} else {
+		    self.t = (self.t & 0xff00) | data as u16;
+		    self.v = self.t;
+		}
+		self.address_latch = !self.address_latch;
+	    },
+	    // PPUDATA
+	    0x2007 => {
+		self.write_vram(self.v, data, mapper);
+		self.increment_vram_addr();
+	    },
 	    _ => (),
 	}
     }
 
-    pub fn read(&mut self, addr: u16) -> u8 {
-	match addr {
+    pub fn read(&mut self, addr: u16, mapper: &dyn Mapper) -> u8 {
+	let res = self.peek(addr);
+	match Self::register(addr) {
 	    0x2002 => {
-		let res = self.buffer;
-		self.buffer = self.status.read() | (self.buffer & 0b11_111);
-		// reading status needs to clear the address latch used by
+		// reading status clears vblank and the address latch used by
 		// PPUSCROLL & PPUADDR.
+		self.status.vblank = false;
 		self.address_latch = false;
-		res
 	    },
+	    0x2007 => {
+		// A palette read still fills the buffer, with the nametable
+		// byte underneath it
+		let addr = self.v & 0x3fff;
+		let buffered = if addr >= 0x3f00 { addr - 0x1000 } else { addr };
+		self.read_buffer = self.read_vram(buffered, mapper);
+		self.increment_vram_addr();
+	    },
+	    _ => (),
+	}
+	self.buffer = res;
+	res
+    }
+
+    /// Returns what `read` would without clearing any flags, moving the
+    /// VRAM address or refilling the read buffer
+    pub fn peek(&self, addr: u16) -> u8 {
+	match Self::register(addr) {
+	    0x2002 => self.status.bits() | (self.buffer & 0b11_111),
+	    0x2004 => self.oam[self.oam_addr as usize],
+	    0x2007 if self.v & 0x3fff >= 0x3f00 => {
+		// palette entries are 6 bits, the rest is the ppu data bus
+		self.palette[Self::palette_index(self.v)] | (self.buffer & 0xc0)
+	    },
+	    0x2007 => self.read_buffer,
 	    _ => self.buffer,
 	}
     }
 
-    pub fn set_mirror(&mut self, m: Mirroring) { self.mirror = m; }
+    /// The picture being drawn. It is whole from the start of vblank, when
+    /// `take_frame_complete` turns true, until rendering starts over on
+    /// scanline 0.
+    pub fn frame(&self) -> &Frame { &self.picture }
+
+    /// Whether a frame was completed since the last call
+    pub fn take_frame_complete(&mut self) -> bool {
+	std::mem::take(&mut self.frame_complete)
+    }
+
+    /// Object attribute memory, four bytes per sprite
+    #[allow(dead_code)]
+    pub fn oam(&self) -> &[u8;256] { &self.oam }
 
 }
 
@@ -169,15 +757,10 @@ impl StatusReg {
 	}
     }
 
-    fn read(&mut self)  -> u8 {
-	let res = (self.vblank as u8) << 7 |
+    fn bits(&self) -> u8 {
+	(self.vblank as u8) << 7 |
 	(self.sprite_zero_hit as u8) << 6 |
-	(self.overflow as u8) << 5;
-
-	// reading status reg clears vblank
-	self.vblank = false;
-
-	res
+	(self.overflow as u8) << 5
     }
 }
 
@@ -217,3 +800,251 @@ impl MaskReg {
 	self.em_blue = (data >> 7) & 1 > 0;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Just pattern tables, as chr ram
+    struct Chr([u8;0x2000]);
+
+    impl Mapper for Chr {
+	fn peek_prg(&self, _addr: u16) -> Option<u8> { None }
+	fn write_prg(&mut self, _addr: u16, _data: u8) -> bool { false }
+	fn read_chr(&self, addr: u16) -> u8 { self.0[addr as usize] }
+	fn write_chr(&mut self, addr: u16, data: u8) { self.0[addr as usize] = data; }
+	fn mirroring(&self) -> Mirroring { Mirroring::Horizontal }
+    }
+
+    #[test]
+    fn test_scroll_registers() {
+	// https://www.nesdev.org/wiki/PPU_scrolling#Summary
+	let mut ppu = Ppu::new();
+	let mut chr = Chr([0;0x2000]);
+	ppu.write(0x2000, 0b10, &mut chr);
+	assert_eq!(ppu.t, 0x0800);
+	ppu.write(0x2005, 0x7d, &mut chr);
+	assert_eq!((ppu.t, ppu.fine_x, ppu.address_latch), (0x080f, 5, true));
+	ppu.write(0x2005, 0x5e, &mut chr);
+	assert_eq!((ppu.t, ppu.address_latch), (0x696f, false));
+	ppu.write(0x200d, 0x3d, &mut chr);
+	ppu.read(0x2002, &chr);
+	assert!(!ppu.address_latch);
+	ppu.write(0x2006, 0x3d, &mut chr);
+	ppu.write(0x2006, 0xf0, &mut chr);
+	assert_eq!((ppu.t, ppu.v), (0x3df0, 0x3df0));
+    }
+
+    #[test]
+    fn test_scroll_increments() {
+	let mut ppu = Ppu::new();
+	ppu.v = 0x001f;
+	ppu.increment_x();
+	assert_eq!(ppu.v, 0x0400);
+	// fine y 7 of coarse row 29 wraps into the nametable below
+	ppu.v = 0x7000 | 29 << 5;
+	ppu.increment_y();
+	assert_eq!(ppu.v, 0x0800);
+	ppu.v = 0x7000 | 31 << 5;
+	ppu.increment_y();
+	assert_eq!(ppu.v, 0x0000);
+    }
+
+    #[test]
+    fn test_background_pixels() {
+	let mut chr = Chr([0;0x2000]);
+	// tile 1, top row: colors 3 2 1 0 3 2 1 0
+	chr.0[0x10] = 0b1010_1010;
+	chr.0[0x18] = 0b1100_1100;
+	for fine_x in [0, 1] {
+	    let mut ppu = Ppu::new();
+	    ppu.name_tables[0] = 1;
+	    // palette 1 for the top left quadrant
+	    ppu.name_tables[0x3c0] = 0b01;
+	    ppu.write(0x2001, 0b1010, &mut chr);
+	    ppu.write(0x2005, fine_x, &mut chr);
+	    ppu.write(0x2005, 0, &mut chr);
+
+	    // the first tiles are fetched at the end of the pre-render scanline
+	    ppu.run(262 * 341, &chr).unwrap();
+	    assert_eq!((ppu.scanline(), ppu.cycle()), (0, 0));
+	    let pixels: Vec<u8> = (0..8).map(|_| {
+		ppu.step(&chr).unwrap();
+		ppu.background_pixel()
+	    }).collect();
+	    let expected = [7, 6, 5, 0, 7, 6, 5, 0, 0];
+	    assert_eq!(pixels, expected[fine_x as usize..][..8], "fine x {}", fine_x);
+	}
+    }
+
+    #[test]
+    fn test_ppudata() {
+	let mut ppu = Ppu::new();
+	let mut chr = Chr([0;0x2000]);
+	let seek = |ppu: &mut Ppu, chr: &mut Chr, addr: u16| {
+	    ppu.write(0x2006, (addr >> 8) as u8, chr);
+	    ppu.write(0x2006, addr as u8, chr);
+	};
+
+	seek(&mut ppu, &mut chr, 0x2108);
+	ppu.write(0x2007, 0xaa, &mut chr);
+	assert_eq!((ppu.name_tables[0x108], ppu.v), (0xaa, 0x2109));
+	// reads lag behind by one
+	seek(&mut ppu, &mut chr, 0x2108);
+	ppu.read(0x2007, &chr);
+	assert_eq!(ppu.read(0x2007, &chr), 0xaa);
+
+	ppu.write(0x2000, 0b100, &mut chr);
+	seek(&mut ppu, &mut chr, 0x2000);
+	ppu.write(0x2007, 1, &mut chr);
+	ppu.write(0x2007, 2, &mut chr);
+	assert_eq!((ppu.name_tables[0], ppu.name_tables[0x20], ppu.v), (1, 2, 0x2040));
+	ppu.write(0x2000, 0, &mut chr);
+
+	seek(&mut ppu, &mut chr, 0x0010);
+	ppu.write(0x2007, 0x77, &mut chr);
+	assert_eq!(chr.0[0x10], 0x77);
+
+	// the sprite backdrops mirror the background ones
+	seek(&mut ppu, &mut chr, 0x3f10);
+	ppu.write(0x2007, 0x2a, &mut chr);
+	ppu.name_tables[0x700] = 0x55;
+	seek(&mut ppu, &mut chr, 0x3f00);
+	assert_eq!(ppu.read(0x2007, &chr) & 0x3f, 0x2a);
+	assert_eq!(ppu.read_buffer, 0x55);
+	seek(&mut ppu, &mut chr, 0x3f14);
+	ppu.write(0x2007, 0x11, &mut chr);
+	assert_eq!(ppu.palette[0x04], 0x11);
+    }
+
+    #[test]
+    fn test_sprite_overflow() {
+	let mut ppu = Ppu::new();
+	ppu.scanline = 12;
+	for n in 0..8 {
+	    ppu.oam[n * 4] = 10;
+	}
+	ppu.evaluate_sprites();
+	assert_eq!((ppu.sprite_count, ppu.status.overflow), (8, false));
+
+	// the search for a ninth checks sprite 9's tile byte, not its y
+	ppu.oam[9 * 4] = 10;
+	ppu.evaluate_sprites();
+	assert!(!ppu.status.overflow);
+	ppu.oam[9 * 4 + 1] = 10;
+	ppu.evaluate_sprites();
+	assert!(ppu.status.overflow);
+
+	ppu.status.overflow = false;
+	ppu.oam[9 * 4 + 1] = 0;
+	ppu.oam[8 * 4] = 5;
+	ppu.evaluate_sprites();
+	assert!(ppu.status.overflow);
+    }
+
+    #[test]
+    fn test_sprite_zero_hit() {
+	let mut chr = Chr([0;0x2000]);
+	// tile 1 is solid color 1
+	chr.0[0x10..0x18].fill(0xff);
+	for (mask, x, cycle) in [(0x1e, 20, 21), (0x18, 4, 9)] {
+	    let mut ppu = Ppu::new();
+	    ppu.name_tables[..0x3c0].fill(1);
+	    ppu.oam[..4].copy_from_slice(&[30, 1, 0, x]);
+	    ppu.write(0x2001, mask, &mut chr);
+	    while !ppu.status.sprite_zero_hit {
+		ppu.step(&chr).unwrap();
+		assert!(ppu.scanline() < 240, "no hit");
+	    }
+	    // the sprite's top row is the scanline after its y
+	    assert_eq!((ppu.scanline(), ppu.cycle()), (31, cycle), "mask {:02x}", mask);
+	}
+    }
+
+    /// The pixels of `line` with the background off
+    fn sprite_line(ppu: &mut Ppu, chr: &Chr, line: usize) -> Vec<u8> {
+	ppu.run((line * 341) as u64, chr).unwrap();
+	(0..256).map(|_| {
+	    ppu.step(chr).unwrap();
+	    ppu.render_pixel()
+	}).collect()
+    }
+
+    #[test]
+    fn test_sprite_pixels() {
+	let mut chr = Chr([0;0x2000]);
+	chr.0[0x20] = 0b1100_0000;
+	// the bottom row of tile 3 in the right table, color 3
+	chr.0[0x1037] = 0b1000_0000;
+	chr.0[0x103f] = 0b1000_0000;
+
+	// flipped horizontally
+	let mut ppu = Ppu::new();
+	ppu.oam[..4].copy_from_slice(&[9, 2, 0x40, 16]);
+	ppu.write(0x2001, 0x14, &mut chr);
+	let pixels = sprite_line(&mut ppu, &chr, 10);
+	let drawn: Vec<(usize, u8)> = pixels.iter().copied().enumerate().filter(|&(_, p)| p != 0).collect();
+	assert_eq!(drawn, [(22, 0x11), (23, 0x11)]);
+
+	// 8x16 flipped vertically, the first of two overlapping sprites wins
+	let mut ppu = Ppu::new();
+	ppu.oam[..8].copy_from_slice(&[9, 3, 0x82, 40, 9, 3, 0x83, 40]);
+	ppu.write(0x2000, 0x20, &mut chr);
+	ppu.write(0x2001, 0x14, &mut chr);
+	let pixels = sprite_line(&mut ppu, &chr, 10);
+	let drawn: Vec<(usize, u8)> = pixels.iter().copied().enumerate().filter(|&(_, p)| p != 0).collect();
+	assert_eq!(drawn, [(40, 0x1b)]);
+    }
+
+    #[test]
+    fn test_nametable_mirroring() {
+	let layouts = [
+	    (Mirroring::Horizontal, [Some(0), Some(0), Some(1), Some(1)]),
+	    (Mirroring::Vertical, [Some(0), Some(1), Some(0), Some(1)]),
+	    (Mirroring::SingleScreenA, [Some(0); 4]),
+	    (Mirroring::SingleScreenB, [Some(1); 4]),
+	    (Mirroring::FourScreen, [Some(0), Some(1), None, None]),
+	];
+	for (mirroring, pages) in layouts {
+	    for (table, page) in pages.into_iter().enumerate() {
+		let addr = 0x2000 + table as u16 * 0x400 + 0x123;
+		let expected = page.map(|p| p << 10 | 0x123);
+		assert_eq!(Ppu::ciram_index(addr, mirroring), expected, "{:?} ${:04X}", mirroring, addr);
+		// [0x3000,0x3eff] mirrors the nametables
+		assert_eq!(Ppu::ciram_index(addr + 0x1000, mirroring), expected);
+	    }
+	}
+    }
+
+    #[test]
+    fn test_frame_output() {
+	let mut chr = Chr([0;0x2000]);
+	chr.0[0x10..0x18].fill(0xff);
+	let mut ppu = Ppu::new();
+	ppu.name_tables[0] = 1;
+	ppu.palette[0] = 0x0f;
+	ppu.palette[1] = 0x2a;
+	// background with red emphasis
+	ppu.write(0x2001, 0x2a, &mut chr);
+
+	// the first frame starts without the pre-render prefetch
+	for _ in 0..2 {
+	    while !ppu.take_frame_complete() {
+		ppu.step(&chr).unwrap();
+	    }
+	}
+	assert_eq!((ppu.scanline(), ppu.cycle()), (Ppu::VBLANK_SCANLINE, 1));
+	assert!(!ppu.take_frame_complete());
+	let frame = ppu.frame();
+	assert_eq!(frame.pixel(0, 0), 1 << 6 | 0x2a);
+	assert_eq!(frame.pixel(7, 7), 1 << 6 | 0x2a);
+	assert_eq!(frame.pixel(8, 0), 1 << 6 | 0x0f);
+	assert_eq!(frame.pixel(0, 8), 1 << 6 | 0x0f);
+
+	ppu.write(0x2001, 0x0b, &mut chr);
+	while !ppu.take_frame_complete() {
+	    ppu.step(&chr).unwrap();
+	}
+	assert_eq!(ppu.frame().pixel(0, 0), 0x20);
+    }
+}
@@ -0,0 +1,59 @@
+use std::io::Write;
+use std::ops::RangeInclusive;
+use super::cpu::Cpu;
+use super::disasm;
+use super::err::EmuErr;
+use super::memory::Memory;
+
+/// Logs one line per instruction in the nestest.log layout, so traces can be
+/// diffed against nestest.log and the FCEUX/Mesen nestest style traces:
+///
+/// `C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7`
+pub struct Tracer {
+    sink: Box<dyn Write + Send>,
+    filter: Option<RangeInclusive<u16>>,
+}
+
+impl Tracer {
+    pub fn new(sink: Box<dyn Write + Send>) -> Self {
+	Self {
+	    sink,
+	    filter: None,
+	}
+    }
+
+    /// Only logs instructions whose address is in `filter`. `None` logs
+    /// everything.
+    pub fn set_filter(&mut self, filter: Option<RangeInclusive<u16>>) {
+	self.filter = filter;
+    }
+
+    /// Flushes and hands back the sink
+    pub fn finish(mut self) -> Result<Box<dyn Write + Send>, EmuErr> {
+	self.sink.flush().map_err(|_| EmuErr::WriteTrace)?;
+	Ok(self.sink)
+    }
+
+    /// Logs the instruction the cpu is about to execute. `scanline` and
+    /// `dot` are the ppu's position.
+    pub fn trace<M: Memory>(&mut self, cpu: &Cpu, memory: &M, scanline: usize, dot: usize) -> Result<(), EmuErr> {
+	if let Some(filter) = &self.filter {
+	    if !filter.contains(&cpu.pc()) {
+		return Ok(());
+	    }
+	}
+	writeln!(self.sink, "{}", trace_line(cpu, memory, scanline, dot)).map_err(|_| EmuErr::WriteTrace)
+    }
+}
+
+/// Formats the cpu state as a nestest.log line
+pub fn trace_line<M: Memory>(cpu: &Cpu, memory: &M, scanline: usize, dot: usize) -> String {
+    let registers = cpu.registers();
+    let instruction = match disasm::disassemble_at(memory, cpu.variant(), registers.pc, &registers) {
+	Some(line) => line.to_string(),
+	None => format!("{:04X}", registers.pc),
+    };
+    format!("{:<47} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} PPU:{:>3},{:>3} CYC:{}",
+	    instruction, registers.a, registers.x, registers.y, registers.p, registers.sp,
+	    scanline, dot, cpu.total_cycles())
+}